@@ -0,0 +1,245 @@
+use crate::CmdOptions;
+use parking_lot::Mutex;
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::time::{Duration, Instant, SystemTime};
+
+const MONTHS: [&str; 12] =
+    ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// How access log records are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Common Log Format: `host ident authuser [date] "request" status bytes`
+    Common,
+    /// Common Log Format followed by the quoted referer and user agent
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Format, String> {
+        match format {
+            "common" | "clf" => Ok(Format::Common),
+            "combined" => Ok(Format::Combined),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown access log format {:?} (expected common, combined or json)", format)),
+        }
+    }
+}
+
+/// When a request was received, by the wall clock (for the record) and by a monotonic clock (for
+/// measuring how long it took).
+#[derive(Debug, Clone, Copy)]
+pub struct RequestTimer {
+    received: SystemTime,
+    started: Instant,
+}
+
+impl RequestTimer {
+    pub fn start() -> RequestTimer {
+        RequestTimer {
+            received: SystemTime::now(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Everything recorded about a request once its response has been sent.
+pub struct Entry<'a> {
+    pub client_ip: &'a str,
+    pub request: &'a http::Request<Vec<u8>>,
+    pub timer: RequestTimer,
+    pub status: http::StatusCode,
+    /// Response body bytes sent to the client
+    pub bytes: u64,
+    /// Address of the upstream that answered, unless balancebeam answered itself
+    pub upstream: Option<&'a str>,
+    /// How long the upstream took to send the response head
+    pub upstream_latency: Option<Duration>,
+}
+
+/// Where records go: a file, which is rotated once it grows too big, or stdout.
+enum Destination {
+    Stdout,
+    File {
+        path: String,
+        file: File,
+        size: u64,
+        max_size: u64,
+        max_files: usize,
+    },
+}
+
+/// A log with one record per request, separate from balancebeam's own log.
+pub struct AccessLog {
+    format: Format,
+    destination: Mutex<Destination>,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let destination = match &*self.destination.lock() {
+            Destination::Stdout => "-".to_string(),
+            Destination::File { path, .. } => path.clone(),
+        };
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .field("destination", &destination)
+            .finish()
+    }
+}
+
+impl AccessLog {
+    /// Opens the access log the --access-log options describe, if any. A path of `-` means
+    /// stdout. Files are appended to, so reopening a log on reload doesn't lose anything.
+    pub fn from_options(options: &CmdOptions) -> Result<Option<AccessLog>, String> {
+        let path = match &options.access_log {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let format = Format::parse(&options.access_log_format)?;
+        let destination = if path == "-" {
+            Destination::Stdout
+        } else {
+            let file = open(path).map_err(|err| format!("Could not open access log {}: {}", path, err))?;
+            Destination::File {
+                path: path.clone(),
+                size: file.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+                file,
+                max_size: options.access_log_max_size,
+                max_files: options.access_log_max_files,
+            }
+        };
+        Ok(Some(AccessLog {
+            format,
+            destination: Mutex::new(destination),
+        }))
+    }
+
+    /// Writes a record for a request. Failing to write is logged, but doesn't affect the request.
+    pub fn record(&self, entry: &Entry) {
+        let mut line = self.format(entry);
+        line.push('\n');
+        if let Err(err) = self.destination.lock().write(line.as_bytes()) {
+            log::error!("Failed to write to the access log: {}", err);
+        }
+    }
+
+    fn format(&self, entry: &Entry) -> String {
+        let request = entry.request;
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        let total_latency = entry.timer.started.elapsed();
+        match self.format {
+            Format::Common | Format::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {:?}\" {} {}",
+                    entry.client_ip,
+                    clf_time(entry.timer.received),
+                    request.method(),
+                    escape(&request.uri().to_string()),
+                    request.version(),
+                    entry.status.as_u16(),
+                    if entry.bytes == 0 { "-".to_string() } else { entry.bytes.to_string() },
+                );
+                if self.format == Format::Combined {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        header("referer").map_or("-".to_string(), |referer| escape(&referer)),
+                        header("user-agent").map_or("-".to_string(), |agent| escape(&agent)),
+                    ));
+                }
+                line
+            }
+            Format::Json => json!({
+                "time": humantime::format_rfc3339_millis(entry.timer.received).to_string(),
+                "client_ip": entry.client_ip,
+                "upstream": entry.upstream,
+                "method": request.method().as_str(),
+                "uri": request.uri().to_string(),
+                "protocol": format!("{:?}", request.version()),
+                "status": entry.status.as_u16(),
+                "bytes": entry.bytes,
+                "upstream_latency_ms": entry.upstream_latency.map(|latency| latency.as_secs_f64() * 1000.0),
+                "total_latency_ms": total_latency.as_secs_f64() * 1000.0,
+                "request_id": header("x-request-id"),
+                "referer": header("referer"),
+                "user_agent": header("user-agent"),
+            })
+            .to_string(),
+        }
+    }
+}
+
+impl Destination {
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        match self {
+            Destination::Stdout => io::stdout().write_all(line),
+            Destination::File { path, file, size, max_size, max_files } => {
+                if *max_size > 0 && *size > 0 && *size + line.len() as u64 > *max_size {
+                    rotate(path, *max_files)?;
+                    *file = open(path)?;
+                    *size = 0;
+                }
+                file.write_all(line)?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn open(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Moves a full log out of the way: `path` becomes `path.1`, `path.1` becomes `path.2`, and so on,
+/// with the oldest dropped so that at most `max_files` old logs are kept.
+fn rotate(path: &str, max_files: usize) -> io::Result<()> {
+    if max_files == 0 {
+        return std::fs::remove_file(path);
+    }
+    for n in (1..max_files).rev() {
+        let from = format!("{}.{}", path, n);
+        if std::path::Path::new(&from).exists() {
+            std::fs::rename(&from, format!("{}.{}", path, n + 1))?;
+        }
+    }
+    std::fs::rename(path, format!("{}.1", path))
+}
+
+/// Formats a time as Common Log Format does, e.g. `10/Oct/2000:13:55:36 +0000`. Times are always
+/// in UTC.
+fn clf_time(time: SystemTime) -> String {
+    // Rearranged from 2000-10-10T13:55:36Z
+    let rfc3339 = humantime::format_rfc3339_seconds(time).to_string();
+    let month: usize = rfc3339[5..7].parse().unwrap_or(1);
+    format!(
+        "{}/{}/{}:{} +0000",
+        &rfc3339[8..10],
+        MONTHS[month.clamp(1, 12) - 1],
+        &rfc3339[0..4],
+        &rfc3339[11..19]
+    )
+}
+
+/// Escapes quotes, backslashes and control characters so that a value can't break up a record.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
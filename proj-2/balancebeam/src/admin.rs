@@ -0,0 +1,325 @@
+use crate::circuit_breaker::State;
+use crate::config::Upstream;
+use crate::metrics::{CacheGauges, UpstreamGauges};
+use crate::upstream::Endpoint;
+use crate::{current_state, request, response, RateLimit, ReportState, SharedState};
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+use tokio::sync::RwLock;
+
+/// Everything the admin API can inspect or change.
+#[derive(Clone)]
+pub struct Admin {
+    pub shared_state: SharedState,
+    pub report_state: Arc<RwLock<ReportState>>,
+    pub rate_limit_count: Arc<RwLock<RateLimit>>,
+}
+
+/// Serves the admin API. Every endpoint except /metrics replies with JSON:
+///
+/// * `GET /metrics`: counters and histograms in the Prometheus text format
+/// * `GET /status`: the strategy, each upstream (pool, weight, health, circuit breaker state,
+///   whether it is draining, active and idle connections), each upstream pool, the rate limit
+///   rules and how many requests each key has counted against them
+/// * `POST /upstreams`: adds the upstream in the body, given as `"host:port"` or as
+///   `{"address": "host:port", "weight": 2}`, to the default pool
+/// * `DELETE /upstreams/<host:port>`: removes an upstream
+/// * `POST /upstreams/<host:port>/drain`: stops sending new requests to an upstream, letting the
+///   ones in flight finish
+/// * `POST /upstreams/<host:port>/enable`: puts a drained upstream back into rotation
+///
+/// Upstreams added or removed here stay that way until the config is next reloaded.
+pub async fn serve(mut listener: TcpListener, admin: Admin) {
+    while let Some(stream) = listener.next().await {
+        match stream {
+            Ok(stream) => {
+                let admin = admin.clone();
+                tokio::spawn(async move {
+                    handle_connection(stream, &admin).await;
+                });
+            }
+            Err(err) => log::error!("Admin connection failed. {:?}", err),
+        }
+    }
+}
+
+async fn handle_connection(mut conn: TcpStream, admin: &Admin) {
+    loop {
+        let request = match request::read_from_stream(&mut conn).await {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) | Err(request::Error::ConnectionError(_)) => {
+                return
+            }
+            Err(error) => {
+                log::debug!("Error parsing admin request: {:?}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                let _ = response::write_to_stream(&response, &mut conn).await;
+                return;
+            }
+        };
+        log::info!("Admin request: {}", request::format_request_line(&request));
+        let response = handle_request(admin, &request).await;
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+        if request::wants_close(&request) {
+            return;
+        }
+    }
+}
+
+async fn handle_request(
+    admin: &Admin,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let segments: Vec<&str> = request.uri().path().trim_matches('/').split('/').collect();
+    let method = request.method();
+    match segments.as_slice() {
+        ["metrics"] if method == http::Method::GET => metrics(admin).await,
+        ["status"] if method == http::Method::GET => {
+            json_response(http::StatusCode::OK, &status(admin).await)
+        }
+        ["upstreams"] if method == http::Method::POST => add_upstream(admin, request.body()).await,
+        ["upstreams", address] if method == http::Method::DELETE => {
+            remove_upstream(admin, address).await
+        }
+        ["upstreams", address, "drain"] if method == http::Method::POST => {
+            set_drained(admin, address, true).await
+        }
+        ["upstreams", address, "enable"] if method == http::Method::POST => {
+            set_drained(admin, address, false).await
+        }
+        ["metrics"] | ["status"] | ["upstreams"] | ["upstreams", _] | ["upstreams", _, "drain"]
+        | ["upstreams", _, "enable"] => error_response(
+            http::StatusCode::METHOD_NOT_ALLOWED,
+            &format!("{} is not supported here", method),
+        ),
+        _ => error_response(http::StatusCode::NOT_FOUND, "No such endpoint"),
+    }
+}
+
+async fn status(admin: &Admin) -> serde_json::Value {
+    let state = current_state(&admin.shared_state);
+    let report = admin.report_state.read().await;
+    let now = Instant::now();
+    let upstreams: Vec<serde_json::Value> = state
+        .upstream_addresses
+        .iter()
+        .enumerate()
+        .map(|(idx, address)| {
+            let circuit = report.circuits.state(address, now);
+            let pool = state.pools.iter().find(|pool| pool.members.contains(&idx));
+            json!({
+                "address": address,
+                "pool": pool.map(|pool| &pool.name),
+                "weight": state.upstream_weights[idx],
+                "tls": state.upstream_endpoints[idx].is_tls(),
+                "healthy": !report.content.contains(address) && circuit != State::Open,
+                "circuit": circuit.name(),
+                "draining": report.drained.contains(address),
+                "active_connections": state.active_connections[idx].load(Ordering::Relaxed),
+                "idle_connections": state.pool.idle_count(idx),
+            })
+        })
+        .collect();
+    let pools: Vec<serde_json::Value> = state
+        .pools
+        .iter()
+        .map(|pool| {
+            json!({
+                "name": pool.name,
+                "strategy": pool.strategy_name,
+                "health_check_interval": pool.health_check_interval,
+                "upstreams": pool
+                    .members
+                    .iter()
+                    .map(|idx| &state.upstream_addresses[*idx])
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    let rules: Vec<serde_json::Value> = state
+        .rate_limit_rules
+        .iter()
+        .map(|rule| {
+            json!({
+                "path_prefix": rule.path_prefix,
+                "key": rule.key.name(),
+                "algorithm": rule.policy.algorithm.name(),
+                "limit": rule.policy.limit,
+                "window_seconds": rule.policy.window.as_secs(),
+            })
+        })
+        .collect();
+    let usage = admin.rate_limit_count.write().await.usage(Instant::now());
+    json!({
+        "mode": state.mode.name(),
+        "strategy": state.pools[0].strategy_name,
+        "upstreams": upstreams,
+        "pools": pools,
+        "rate_limit": {
+            "rules": rules,
+            "usage": usage,
+        },
+    })
+}
+
+async fn metrics(admin: &Admin) -> http::Response<Vec<u8>> {
+    let state = current_state(&admin.shared_state);
+    let report = admin.report_state.read().await;
+    let now = Instant::now();
+    let gauges: Vec<UpstreamGauges> = state
+        .upstream_addresses
+        .iter()
+        .enumerate()
+        .map(|(idx, address)| UpstreamGauges {
+            address,
+            healthy: !report.content.contains(address)
+                && report.circuits.state(address, now) != State::Open,
+            active_connections: state.active_connections[idx].load(Ordering::Relaxed),
+            idle_connections: state.pool.idle_count(idx),
+        })
+        .collect();
+    let cache = state.cache.as_ref().map(|cache| {
+        let (entries, bytes) = cache.usage();
+        CacheGauges { entries, bytes }
+    });
+    let body = state.metrics.render(&gauges, cache).into_bytes();
+    response_with_body(http::StatusCode::OK, "text/plain; version=0.0.4", body)
+}
+
+async fn add_upstream(admin: &Admin, body: &[u8]) -> http::Response<Vec<u8>> {
+    let upstream: Upstream = match serde_json::from_slice(body) {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            return error_response(
+                http::StatusCode::BAD_REQUEST,
+                &format!("Invalid upstream: {}", err),
+            )
+        }
+    };
+    let (address, weight, server_name) = match upstream.into_parts() {
+        Ok(parts) => parts,
+        Err(err) => return error_response(http::StatusCode::BAD_REQUEST, &err),
+    };
+    let address = {
+        let mut shared_state = admin.shared_state.write();
+        let endpoint =
+            match Endpoint::parse(&address, server_name.as_deref(), &shared_state.upstream_tls) {
+                Ok(endpoint) => endpoint,
+                Err(err) => return error_response(http::StatusCode::BAD_REQUEST, &err),
+            };
+        if shared_state.upstream_addresses.contains(&endpoint.address) {
+            return error_response(
+                http::StatusCode::CONFLICT,
+                &format!("{} is already an upstream", endpoint.address),
+            );
+        }
+        let address = endpoint.address.clone();
+        let mut upstreams = upstreams_of(&shared_state);
+        upstreams.push((endpoint, weight));
+        match shared_state.with_upstreams(upstreams) {
+            Ok(state) => *shared_state = Arc::new(state),
+            Err(err) => {
+                return error_response(http::StatusCode::BAD_REQUEST, &err.to_string())
+            }
+        }
+        address
+    };
+    log::info!("Added upstream {} with weight {}", address, weight);
+    json_response(http::StatusCode::CREATED, &status(admin).await)
+}
+
+async fn remove_upstream(admin: &Admin, address: &str) -> http::Response<Vec<u8>> {
+    {
+        let mut shared_state = admin.shared_state.write();
+        if !shared_state.upstream_addresses.iter().any(|a| a == address) {
+            return unknown_upstream(address);
+        }
+        if shared_state.upstream_addresses.len() == 1 {
+            return error_response(
+                http::StatusCode::CONFLICT,
+                "Can't remove the last upstream",
+            );
+        }
+        let mut upstreams = upstreams_of(&shared_state);
+        upstreams.retain(|(endpoint, _)| endpoint.address != address);
+        match shared_state.with_upstreams(upstreams) {
+            Ok(state) => *shared_state = Arc::new(state),
+            Err(err) => {
+                return error_response(http::StatusCode::BAD_REQUEST, &err.to_string())
+            }
+        }
+    }
+    {
+        let mut report = admin.report_state.write().await;
+        report.drained.retain(|a| a != address);
+        report.circuits.remove(address);
+    }
+    log::info!("Removed upstream {}", address);
+    json_response(http::StatusCode::OK, &status(admin).await)
+}
+
+async fn set_drained(admin: &Admin, address: &str, drained: bool) -> http::Response<Vec<u8>> {
+    let state = current_state(&admin.shared_state);
+    if !state.upstream_addresses.iter().any(|a| a == address) {
+        return unknown_upstream(address);
+    }
+    {
+        let mut report = admin.report_state.write().await;
+        report.drained.retain(|a| a != address);
+        if drained {
+            report.drained.push(address.to_string());
+        }
+    }
+    if drained {
+        log::info!("Draining upstream {}", address);
+    } else {
+        log::info!("Re-enabled upstream {}", address);
+    }
+    json_response(http::StatusCode::OK, &status(admin).await)
+}
+
+/// Returns the (endpoint, weight) pairs a state proxies to.
+fn upstreams_of(state: &crate::ProxyState) -> Vec<(Endpoint, usize)> {
+    state
+        .upstream_endpoints
+        .iter()
+        .cloned()
+        .zip(state.upstream_weights.iter().copied())
+        .collect()
+}
+
+fn unknown_upstream(address: &str) -> http::Response<Vec<u8>> {
+    error_response(
+        http::StatusCode::NOT_FOUND,
+        &format!("{} is not an upstream", address),
+    )
+}
+
+fn error_response(status: http::StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    json_response(status, &json!({ "error": message }))
+}
+
+fn json_response(status: http::StatusCode, body: &serde_json::Value) -> http::Response<Vec<u8>> {
+    response_with_body(status, "application/json", serde_json::to_vec_pretty(body).unwrap())
+}
+
+fn response_with_body(
+    status: http::StatusCode,
+    content_type: &str,
+    body: Vec<u8>,
+) -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
@@ -0,0 +1,153 @@
+use crate::chunked;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+/// Size of the buffer used to shuttle body bytes from one stream to another. This bounds how much
+/// of a streamed body is held in memory at any time.
+const COPY_BUFFER_SIZE: usize = 8192;
+
+/// How the end of a message body is marked on the wire.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// The body is exactly this many bytes long (Content-Length)
+    Length(usize),
+    /// The body uses chunked transfer encoding and ends with a zero-length chunk
+    Chunked,
+    /// The body ends when the sender closes the connection (responses only)
+    UntilClose,
+}
+
+/// Wraps the stream a body is copied from, remembering whether a read from it failed or found it
+/// closed. When a copy fails, this tells a sender that gave up apart from a receiver that did.
+pub struct Source<'a, R> {
+    inner: &'a mut R,
+    failed: bool,
+}
+
+impl<'a, R> Source<'a, R> {
+    pub fn new(inner: &'a mut R) -> Source<'a, R> {
+        Source { inner, failed: false }
+    }
+
+    /// Returns true if the copy failed because of the sender: it hung up or its stream failed
+    /// before the body was over, or it sent a body that isn't framed the way it said.
+    pub fn is_to_blame(&self, error: &io::Error) -> bool {
+        self.failed || error.kind() == io::ErrorKind::InvalidData
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Source<'_, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Err(_)) | Poll::Ready(Ok(0)) = result {
+            this.failed |= !buf.is_empty();
+        }
+        result
+    }
+}
+
+/// Copies a message body from one stream to another as it arrives, without holding more than a
+/// small buffer of it in memory. `buffered` holds the start of the body, which was read along with
+/// the headers. Writing to `to` is awaited before reading more from `from`, so a slow receiver
+/// slows down the sender rather than making us buffer. Whatever has arrived is flushed to `to`
+/// before waiting on `from` again, so a body that trickles in reaches the receiver as it comes.
+/// Chunked bodies are passed through with their framing (and trailers) intact. Returns the number
+/// of body bytes copied, and any bytes that were read from `from` past the end of the body (which
+/// only a chunked body, read a block at a time, can run on into).
+pub async fn copy<R, W>(
+    framing: Framing,
+    buffered: &[u8],
+    from: &mut R,
+    to: &mut W,
+) -> std::io::Result<(u64, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut to = BufWriter::with_capacity(COPY_BUFFER_SIZE, to);
+    let copied = match framing {
+        Framing::Length(length) => (copy_length(length, buffered, from, &mut to).await?, Vec::new()),
+        Framing::Chunked => chunked::copy_body(buffered, from, &mut to).await?,
+        Framing::UntilClose => (copy_until_close(buffered, from, &mut to).await?, Vec::new()),
+    };
+    to.flush().await?;
+    Ok(copied)
+}
+
+/// Copies a message body like copy, but with any chunked framing taken off, for a receiver that
+/// marks the end of the body some other way (such as an HTTP/2 stream). Returns the number of
+/// body bytes copied, and the trailers that followed a chunked body.
+pub async fn copy_decoded<R, W>(
+    framing: Framing,
+    buffered: &[u8],
+    from: &mut R,
+    to: &mut W,
+) -> std::io::Result<(u64, Option<chunked::Trailers>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if framing != Framing::Chunked {
+        return Ok((copy(framing, buffered, from, to).await?.0, None));
+    }
+    let mut to = BufWriter::with_capacity(COPY_BUFFER_SIZE, to);
+    let (copied, trailers) = chunked::copy_decoded_body(buffered, from, &mut to).await?;
+    to.flush().await?;
+    Ok((copied, Some(trailers)))
+}
+
+async fn copy_length<R, W>(
+    length: usize,
+    buffered: &[u8],
+    from: &mut R,
+    to: &mut W,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if buffered.len() > length {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "sender sent more bytes than its Content-Length",
+        ));
+    }
+    to.write_all(buffered).await?;
+    to.flush().await?;
+    let mut remaining = length - buffered.len();
+    let mut buffer = [0_u8; COPY_BUFFER_SIZE];
+    while remaining > 0 {
+        let max = remaining.min(COPY_BUFFER_SIZE);
+        let bytes_read = from.read(&mut buffer[..max]).await?;
+        if bytes_read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        to.write_all(&buffer[..bytes_read]).await?;
+        to.flush().await?;
+        remaining -= bytes_read;
+    }
+    Ok(length as u64)
+}
+
+async fn copy_until_close<R, W>(buffered: &[u8], from: &mut R, to: &mut W) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    to.write_all(buffered).await?;
+    to.flush().await?;
+    let mut copied = buffered.len() as u64;
+    let mut buffer = [0_u8; COPY_BUFFER_SIZE];
+    loop {
+        let bytes_read = from.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            return Ok(copied);
+        }
+        to.write_all(&buffer[..bytes_read]).await?;
+        to.flush().await?;
+        copied += bytes_read as u64;
+    }
+}
@@ -0,0 +1,524 @@
+use crate::headers::strip_hop_by_hop;
+use crate::metrics::Metrics;
+use crate::CmdOptions;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Response header telling the client how the cache handled its request.
+pub const CACHE_STATUS: &str = "x-cache";
+
+/// Statuses whose responses may be stored. These are the statuses RFC 9110 (section 15.1) calls
+/// cacheable by default, less 206 (which needs ranges stitched together), 405, 414 and 501.
+const CACHEABLE_STATUSES: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+/// How the cache handled a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// Answered with a fresh stored response
+    Hit,
+    /// Answered with a stale stored response, after the upstream confirmed it hasn't changed
+    Revalidated,
+    /// Forwarded, and the response stored if it may be
+    Miss,
+    /// Forwarded without looking in the cache, since the request can't be answered from it
+    Bypass,
+}
+
+impl Status {
+    /// The value of the X-Cache header
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Status::Hit => "HIT",
+            Status::Revalidated => "REVALIDATED",
+            Status::Miss => "MISS",
+            Status::Bypass => "BYPASS",
+        }
+    }
+
+    /// The label of the cache metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Hit => "hit",
+            Status::Revalidated => "revalidated",
+            Status::Miss => "miss",
+            Status::Bypass => "bypass",
+        }
+    }
+}
+
+/// What the cache has for a request.
+pub enum Lookup {
+    /// A fresh response, ready to send
+    Fresh(http::Response<Vec<u8>>),
+    /// A response that is stale but has validators. The request should be forwarded with these
+    /// conditional headers, and a 304 in reply handed to `revalidate`.
+    Stale(HeaderMap),
+    /// Nothing usable is stored for the request
+    Miss,
+    /// The request can't be answered from the cache
+    Bypass,
+}
+
+/// A stored response.
+struct Entry {
+    status: http::StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    /// The request headers named by the response's Vary header, with the values they had on the
+    /// request that the response answered
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    /// When the response, or the 304 that last revalidated it, was received
+    received: Instant,
+    /// How old the response already was when it was received, from its Age header
+    initial_age: Duration,
+    /// How long after it was generated the response stays fresh
+    lifetime: Duration,
+    /// Position in the least-recently-used order; a bigger number was used more recently
+    last_used: u64,
+    /// Bytes counted against the cache size: the body plus the headers
+    size: usize,
+}
+
+impl Entry {
+    fn age(&self, now: Instant) -> Duration {
+        self.initial_age + now.saturating_duration_since(self.received)
+    }
+
+    /// Whether the response can answer a request that has the same URL: every header it varies
+    /// on has the same value now as on the request it answered.
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        self.vary.iter().all(|(name, value)| joined(request.headers(), name).as_ref() == value.as_ref())
+    }
+
+    /// Builds the response to send for a request: the stored response with its current Age, a
+    /// 304 if the request's own conditions show the client already has it, and no body for HEAD.
+    fn respond(&self, request: &http::Request<Vec<u8>>, now: Instant) -> http::Response<Vec<u8>> {
+        let not_modified = is_not_modified(request.headers(), &self.headers);
+        let body = if not_modified || request.method() == http::Method::HEAD {
+            Vec::new()
+        } else {
+            self.body.clone()
+        };
+        let mut response = http::Response::builder()
+            .status(if not_modified { http::StatusCode::NOT_MODIFIED } else { self.status })
+            .version(http::Version::HTTP_11)
+            .body(body)
+            .unwrap();
+        *response.headers_mut() = self.headers.clone();
+        response.headers_mut().insert(header::AGE, self.age(now).as_secs().into());
+        response
+    }
+
+    /// Returns the conditional headers that ask the upstream whether the response has changed,
+    /// if it has any validators.
+    fn conditions(&self) -> Option<HeaderMap> {
+        let mut conditions = HeaderMap::new();
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            conditions.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(header::LAST_MODIFIED) {
+            conditions.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        if conditions.is_empty() {
+            None
+        } else {
+            Some(conditions)
+        }
+    }
+
+    /// Works out the entry's freshness from its headers, for a response that was received just
+    /// now, `initial_age` old.
+    fn refresh(&mut self, initial_age: Duration, now: Instant) {
+        self.received = now;
+        self.initial_age = initial_age;
+        self.lifetime = freshness_lifetime(&self.headers, &cache_control(&self.headers));
+        self.size = self.body.len() + headers_size(&self.headers);
+    }
+}
+
+/// Stored responses by URL, along with the least-recently-used order over them.
+#[derive(Default)]
+struct Store {
+    /// The responses stored for each URL, one for each combination of the request headers they
+    /// vary on
+    entries: HashMap<String, Vec<Entry>>,
+    /// The URL of every entry, keyed by its last_used, so that the first is the one to evict
+    recency: BTreeMap<u64, String>,
+    /// The last last_used handed out
+    clock: u64,
+    /// Total size of the entries
+    bytes: usize,
+}
+
+impl Store {
+    /// Returns the entry for a URL that can answer a request, marking it as just used.
+    fn find(&mut self, key: &str, request: &http::Request<Vec<u8>>) -> Option<&mut Entry> {
+        let entry = self.entries.get_mut(key)?.iter_mut().find(|entry| entry.matches(request))?;
+        self.recency.remove(&entry.last_used);
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.recency.insert(self.clock, key.to_string());
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: String, mut entry: Entry) {
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.bytes += entry.size;
+        self.recency.insert(self.clock, key.clone());
+        self.entries.entry(key).or_default().push(entry);
+    }
+
+    /// Removes the entries for a URL that `keep` returns false for.
+    fn retain(&mut self, key: &str, mut keep: impl FnMut(&Entry) -> bool) {
+        let entries = match self.entries.get_mut(key) {
+            Some(entries) => entries,
+            None => return,
+        };
+        let (recency, bytes) = (&mut self.recency, &mut self.bytes);
+        entries.retain(|entry| {
+            if keep(entry) {
+                return true;
+            }
+            recency.remove(&entry.last_used);
+            *bytes -= entry.size;
+            false
+        });
+        if entries.is_empty() {
+            self.entries.remove(key);
+        }
+    }
+
+    /// Evicts the least recently used entries until the cache fits in `max_size`. Returns how
+    /// many were evicted.
+    fn evict_to_fit(&mut self, max_size: usize) -> usize {
+        let mut evicted = 0;
+        while self.bytes > max_size {
+            let (last_used, key) = match self.recency.iter().next() {
+                Some((last_used, key)) => (*last_used, key.clone()),
+                None => break,
+            };
+            self.retain(&key, |entry| entry.last_used != last_used);
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+/// An in-memory cache of upstream responses to GET requests, which answers GET and HEAD requests
+/// for as long as the responses stay fresh (RFC 9111). Once a response is stale, it is only used
+/// again after the upstream confirms that it hasn't changed. It is a shared cache: responses
+/// marked private are never stored. When it grows past its size, the least recently used
+/// responses are evicted.
+pub struct Cache {
+    max_size: usize,
+    max_entry_size: usize,
+    store: Mutex<Store>,
+    metrics: Arc<Metrics>,
+}
+
+impl std::fmt::Debug for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (entries, bytes) = self.usage();
+        f.debug_struct("Cache")
+            .field("max_size", &self.max_size)
+            .field("max_entry_size", &self.max_entry_size)
+            .field("entries", &entries)
+            .field("bytes", &bytes)
+            .finish()
+    }
+}
+
+impl Cache {
+    /// Builds the cache described by the options, if caching is on.
+    pub fn from_options(options: &CmdOptions, metrics: Arc<Metrics>) -> Option<Cache> {
+        if options.cache_size == 0 {
+            return None;
+        }
+        Some(Cache {
+            max_size: options.cache_size,
+            max_entry_size: options.cache_max_entry_size.min(options.cache_size),
+            store: Mutex::new(Store::default()),
+            metrics,
+        })
+    }
+
+    /// Returns the number of stored responses and their total size.
+    pub fn usage(&self) -> (usize, usize) {
+        let store = self.store.lock();
+        (store.recency.len(), store.bytes)
+    }
+
+    /// Looks for a stored response that can answer a request.
+    pub fn lookup(&self, request: &http::Request<Vec<u8>>, now: Instant) -> Lookup {
+        if !matches!(*request.method(), http::Method::GET | http::Method::HEAD)
+            || request.headers().contains_key(header::UPGRADE)
+        {
+            return Lookup::Bypass;
+        }
+        let directives = cache_control(request.headers());
+        if directives.contains_key("no-store") {
+            return Lookup::Bypass;
+        }
+        let mut store = self.store.lock();
+        let entry = match store.find(&key(request), request) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+        // A client can ask for a response younger than the cache would otherwise send
+        let max_age = if directives.contains_key("no-cache") {
+            None
+        } else {
+            Some(seconds(&directives, "max-age").map_or(entry.lifetime, |max_age| max_age.min(entry.lifetime)))
+        };
+        match max_age {
+            Some(max_age) if entry.age(now) < max_age => Lookup::Fresh(entry.respond(request, now)),
+            _ => entry.conditions().map_or(Lookup::Miss, Lookup::Stale),
+        }
+    }
+
+    /// Stores the response to a request, if it may be stored.
+    pub fn store(&self, request: &http::Request<Vec<u8>>, response: &http::Response<Vec<u8>>, now: Instant) {
+        if request.method() != http::Method::GET || !CACHEABLE_STATUSES.contains(&response.status().as_u16()) {
+            return;
+        }
+        let request_directives = cache_control(request.headers());
+        let directives = cache_control(response.headers());
+        if request_directives.contains_key("no-store")
+            || directives.contains_key("no-store")
+            || directives.contains_key("private")
+        {
+            return;
+        }
+        // Responses to authorized requests are meant for that client alone, unless the upstream
+        // says otherwise (RFC 9111 section 3.5)
+        if request.headers().contains_key(header::AUTHORIZATION)
+            && !["public", "s-maxage", "must-revalidate"].iter().any(|name| directives.contains_key(*name))
+        {
+            return;
+        }
+        // A cookie is meant for one client, and would be handed out to everyone else
+        if response.headers().contains_key(header::SET_COOKIE) {
+            return;
+        }
+        let vary: Vec<HeaderName> = match vary(response.headers()) {
+            Some(vary) => vary,
+            None => return,
+        };
+        let mut headers = response.headers().clone();
+        strip_hop_by_hop(&mut headers);
+        headers.remove(header::AGE);
+        let mut entry = Entry {
+            status: response.status(),
+            headers,
+            body: response.body().clone(),
+            vary: vary.into_iter().map(|name| (name.clone(), joined(request.headers(), &name))).collect(),
+            received: now,
+            initial_age: Duration::from_secs(0),
+            lifetime: Duration::from_secs(0),
+            last_used: 0,
+            size: 0,
+        };
+        entry.refresh(age(response.headers()), now);
+        // A response that is stale straight away and can't be revalidated is no use
+        if (entry.lifetime <= entry.initial_age && entry.conditions().is_none()) || entry.size > self.max_entry_size {
+            return;
+        }
+        let key = key(request);
+        let mut store = self.store.lock();
+        store.retain(&key, |stored| !stored.matches(request));
+        store.insert(key, entry);
+        let evicted = store.evict_to_fit(self.max_size);
+        drop(store);
+        self.metrics.record_cache_evictions(evicted);
+    }
+
+    /// Freshens the stored response to a request with the 304 the upstream sent back when asked
+    /// whether it had changed, and returns the response to send. Returns None if the response has
+    /// been evicted in the meantime.
+    pub fn revalidate(&self, request: &http::Request<Vec<u8>>, not_modified: &http::Response<Vec<u8>>,
+            now: Instant) -> Option<http::Response<Vec<u8>>> {
+        let mut updates = not_modified.headers().clone();
+        strip_hop_by_hop(&mut updates);
+        let mut store = self.store.lock();
+        let entry = store.find(&key(request), request)?;
+        let old_size = entry.size;
+        // The 304 carries the headers a full response would have, with new freshness information
+        for name in updates.keys() {
+            if name == header::CONTENT_LENGTH || name == header::AGE {
+                continue;
+            }
+            entry.headers.remove(name);
+            for value in updates.get_all(name) {
+                entry.headers.append(name, value.clone());
+            }
+        }
+        entry.refresh(age(not_modified.headers()), now);
+        let response = entry.respond(request, now);
+        let new_size = entry.size;
+        store.bytes = store.bytes - old_size + new_size;
+        let evicted = store.evict_to_fit(self.max_size);
+        drop(store);
+        self.metrics.record_cache_evictions(evicted);
+        Some(response)
+    }
+
+    /// Forgets the responses stored for a request's URL, after a request that may have changed
+    /// what is there (RFC 9111 section 4.4).
+    pub fn invalidate(&self, request: &http::Request<Vec<u8>>) {
+        self.store.lock().retain(&key(request), |_| false);
+    }
+}
+
+/// Returns a copy of a request that asks the upstream to respond only if what it has differs from
+/// the stale response that `conditions` were taken from. The client's own conditions are
+/// replaced, since they are about what the client has rather than what the cache has.
+pub fn conditional_request(request: &http::Request<Vec<u8>>, conditions: HeaderMap) -> http::Request<Vec<u8>> {
+    let mut conditional = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version())
+        .body(Vec::new())
+        .unwrap();
+    *conditional.headers_mut() = request.headers().clone();
+    for name in [header::IF_MATCH, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE,
+            header::IF_UNMODIFIED_SINCE, header::IF_RANGE].iter() {
+        conditional.headers_mut().remove(name);
+    }
+    conditional.headers_mut().extend(conditions);
+    conditional
+}
+
+/// The URL a request is for, which its stored responses are filed under.
+fn key(request: &http::Request<Vec<u8>>) -> String {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().authority().map(|authority| authority.as_str()))
+        .unwrap_or("");
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+    format!("{}{}", host.to_lowercase(), path)
+}
+
+/// Returns the Cache-Control directives in a set of headers, by lowercase name, with their values
+/// unquoted.
+fn cache_control(headers: &HeaderMap) -> HashMap<String, Option<String>> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap().trim().to_ascii_lowercase();
+            let value = parts.next().map(|value| value.trim().trim_matches('"').to_string());
+            if name.is_empty() {
+                None
+            } else {
+                Some((name, value))
+            }
+        })
+        .collect()
+}
+
+/// The value of a directive that takes a number of seconds, such as max-age.
+fn seconds(directives: &HashMap<String, Option<String>>, name: &str) -> Option<Duration> {
+    directives
+        .get(name)?
+        .as_ref()?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// How long a response stays fresh, from its s-maxage or max-age directive, or else from its
+/// Expires header. A response with none of them (or with no-cache) has to be revalidated every
+/// time; responses aren't given a heuristic lifetime.
+fn freshness_lifetime(headers: &HeaderMap, directives: &HashMap<String, Option<String>>) -> Duration {
+    if directives.contains_key("no-cache") {
+        return Duration::from_secs(0);
+    }
+    if let Some(lifetime) = seconds(directives, "s-maxage").or_else(|| seconds(directives, "max-age")) {
+        return lifetime;
+    }
+    // An Expires header that can't be parsed means the response has already expired
+    match date(headers, header::EXPIRES) {
+        Some(expires) => {
+            let generated = date(headers, header::DATE).unwrap_or_else(SystemTime::now);
+            expires.duration_since(generated).unwrap_or_default()
+        }
+        None => Duration::from_secs(0),
+    }
+}
+
+/// The age the upstream says a response already had when it was sent.
+fn age(headers: &HeaderMap) -> Duration {
+    headers
+        .get(header::AGE)
+        .and_then(|age| age.to_str().ok())
+        .and_then(|age| age.trim().parse().ok())
+        .map_or(Duration::from_secs(0), Duration::from_secs)
+}
+
+fn date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+/// Returns the request headers a response varies on, or None if it varies on something other
+/// than headers (`Vary: *`) and can't be stored.
+fn vary(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for name in headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            names.push(name);
+        }
+    }
+    Some(names)
+}
+
+/// All the values of a header joined into one, or None if it is missing.
+fn joined(headers: &HeaderMap, name: &HeaderName) -> Option<HeaderValue> {
+    let values: Vec<&[u8]> = headers.get_all(name).iter().map(|value| value.as_bytes()).collect();
+    if values.is_empty() {
+        return None;
+    }
+    HeaderValue::from_bytes(&values.join(&b", "[..])).ok()
+}
+
+/// Whether a request's If-None-Match or If-Modified-Since shows that the client already has the
+/// response with these headers. If-None-Match is compared weakly, as RFC 9110 section 13.1.2
+/// says to.
+fn is_not_modified(request_headers: &HeaderMap, headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()) {
+        let etag = match headers.get(header::ETAG).and_then(|etag| etag.to_str().ok()) {
+            Some(etag) => etag.trim_start_matches("W/"),
+            None => return false,
+        };
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag);
+    }
+    match (date(request_headers, header::IF_MODIFIED_SINCE), date(headers, header::LAST_MODIFIED)) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+fn headers_size(headers: &HeaderMap) -> usize {
+    headers.iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum()
+}
@@ -0,0 +1,285 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest chunk-size line or trailer line we are willing to buffer while looking for its CRLF.
+const MAX_LINE_SIZE: usize = 8000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The peer hung up before sending the terminating zero-length chunk
+    Incomplete,
+    /// A chunk-size line, chunk terminator or trailer was not valid
+    Malformed,
+    /// The decoded body is bigger than the limit we were given
+    TooLarge,
+    /// Encountered an I/O error when reading from the stream
+    Io(std::io::Error),
+}
+
+/// Trailer fields sent after the last chunk of a chunked message. These are stored in the
+/// message's extensions so that they can be re-encoded when the message is forwarded.
+#[derive(Debug, Clone, Default)]
+pub struct Trailers(pub http::HeaderMap);
+
+/// Returns true if chunked is the final transfer coding applied to a message with these headers,
+/// meaning the body has to be read with read_body.
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+}
+
+/// Reads data off of the stream on behalf of the decoder, keeping whatever has been received but
+/// not yet consumed.
+struct Reader<'a, S> {
+    stream: &'a mut S,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, S: AsyncRead + Unpin> Reader<'a, S> {
+    /// Reads more bytes from the stream into the buffer.
+    async fn fill(&mut self) -> Result<(), Error> {
+        let mut chunk = [0_u8; 512];
+        let bytes_read = self.stream.read(&mut chunk).await.map_err(Error::Io)?;
+        if bytes_read == 0 {
+            return Err(Error::Incomplete);
+        }
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(())
+    }
+
+    /// Returns the next CRLF-terminated line, without the CRLF.
+    async fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(end) = self.buffer[self.pos..].windows(2).position(|w| w == b"\r\n") {
+                let line = self.buffer[self.pos..self.pos + end].to_vec();
+                self.pos += end + 2;
+                return Ok(line);
+            }
+            if self.buffer.len() - self.pos > MAX_LINE_SIZE {
+                return Err(Error::Malformed);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Returns the next `len` bytes.
+    async fn read_exact(&mut self, len: usize) -> Result<&[u8], Error> {
+        while self.buffer.len() - self.pos < len {
+            self.fill().await?;
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&self.buffer[start..self.pos])
+    }
+
+    /// Returns between 1 and `max` bytes, reading from the stream only if nothing is buffered.
+    async fn read_some(&mut self, max: usize) -> Result<&[u8], Error> {
+        if self.pos == self.buffer.len() {
+            self.compact();
+            self.fill().await?;
+        }
+        let start = self.pos;
+        self.pos += max.min(self.buffer.len() - start);
+        Ok(&self.buffer[start..self.pos])
+    }
+
+    /// Drops the bytes that have already been consumed so the buffer doesn't grow with the body.
+    fn compact(&mut self) {
+        self.buffer.drain(..self.pos);
+        self.pos = 0;
+    }
+}
+
+/// Parses a chunk-size line (a hex size, optionally followed by ;extensions, which we ignore).
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let line = std::str::from_utf8(line).map_err(|_| Error::Malformed)?;
+    let size = line.split(';').next().unwrap_or("").trim();
+    usize::from_str_radix(size, 16).map_err(|_| Error::Malformed)
+}
+
+/// Parses a single `name: value` trailer line into `trailers`.
+fn parse_trailer(line: &[u8], trailers: &mut http::HeaderMap) -> Result<(), Error> {
+    let colon = line.iter().position(|b| *b == b':').ok_or(Error::Malformed)?;
+    let name = http::header::HeaderName::from_bytes(&line[..colon]).map_err(|_| Error::Malformed)?;
+    let value = http::HeaderValue::from_bytes(line[colon + 1..].trim_ascii())
+        .map_err(|_| Error::Malformed)?;
+    trailers.append(name, value);
+    Ok(())
+}
+
+/// Decodes a chunked body from the stream. `buffered` holds any bytes of the body that were read
+/// along with the headers. Returns the decoded body and any trailers that followed it.
+pub async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffered: Vec<u8>,
+    max_size: usize,
+) -> Result<(Vec<u8>, Trailers), Error> {
+    let mut reader = Reader {
+        stream,
+        buffer: buffered,
+        pos: 0,
+    };
+    let mut body = Vec::new();
+    loop {
+        let size = parse_chunk_size(&reader.read_line().await?)?;
+        if size == 0 {
+            break;
+        }
+        if size > max_size - body.len() {
+            return Err(Error::TooLarge);
+        }
+        body.extend_from_slice(reader.read_exact(size).await?);
+        if !reader.read_line().await?.is_empty() {
+            // Chunk data must be followed immediately by CRLF
+            return Err(Error::Malformed);
+        }
+        reader.compact();
+    }
+
+    // The last chunk is followed by zero or more trailer fields and then an empty line
+    let mut trailers = http::HeaderMap::new();
+    loop {
+        let line = reader.read_line().await?;
+        if line.is_empty() {
+            break;
+        }
+        parse_trailer(&line, &mut trailers)?;
+    }
+    Ok((body, Trailers(trailers)))
+}
+
+/// Copies a chunked body from one stream to another without decoding it, following the chunk
+/// framing only far enough to know where the body ends. `buffered` holds any bytes of the body
+/// that were read along with the headers. Returns the number of bytes copied, and any bytes that
+/// were read from `from` past the end of the body.
+pub async fn copy_body<R, W>(
+    buffered: &[u8],
+    from: &mut R,
+    to: &mut W,
+) -> std::io::Result<(u64, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy_chunks(buffered, from, to, false).await.map(|(copied, _, leftover)| (copied, leftover))
+}
+
+/// Copies a chunked body from one stream to another like copy_body, but only writes the chunk
+/// data, for a receiver that marks the end of the body some other way. Returns the number of data
+/// bytes copied and the trailers that followed the body.
+pub async fn copy_decoded_body<R, W>(
+    buffered: &[u8],
+    from: &mut R,
+    to: &mut W,
+) -> std::io::Result<(u64, Trailers)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy_chunks(buffered, from, to, true).await.map(|(copied, trailers, _)| (copied, trailers))
+}
+
+async fn copy_chunks<R, W>(
+    buffered: &[u8],
+    from: &mut R,
+    to: &mut W,
+    decode: bool,
+) -> std::io::Result<(u64, Trailers, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let to_io_error = |err| match err {
+        Error::Io(err) => err,
+        Error::Incomplete => std::io::ErrorKind::UnexpectedEof.into(),
+        Error::Malformed | Error::TooLarge => std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "malformed chunked encoding",
+        ),
+    };
+    let mut reader = Reader {
+        stream: from,
+        buffer: buffered.to_vec(),
+        pos: 0,
+    };
+    let mut copied = 0;
+    loop {
+        let line = reader.read_line().await.map_err(to_io_error)?;
+        let size = parse_chunk_size(&line).map_err(to_io_error)?;
+        if !decode {
+            to.write_all(&line).await?;
+            to.write_all(b"\r\n").await?;
+            copied += line.len() as u64 + 2;
+        }
+        if size == 0 {
+            break;
+        }
+        let mut remaining = size;
+        while remaining > 0 {
+            let data = reader.read_some(remaining).await.map_err(to_io_error)?;
+            to.write_all(data).await?;
+            remaining -= data.len();
+            if remaining > 0 {
+                // The rest of the chunk may be a while coming, so pass on what has arrived
+                to.flush().await?;
+            }
+        }
+        if !reader.read_line().await.map_err(to_io_error)?.is_empty() {
+            // Chunk data must be followed immediately by CRLF
+            return Err(to_io_error(Error::Malformed));
+        }
+        if decode {
+            copied += size as u64;
+        } else {
+            to.write_all(b"\r\n").await?;
+            copied += size as u64 + 2;
+        }
+        // Pass each chunk on as soon as it is complete. A sender that trickles out small chunks
+        // (server-sent events, say) would otherwise be held up until a buffer's worth had arrived.
+        to.flush().await?;
+    }
+
+    // Pass along any trailers, up to and including the empty line that ends the body
+    let mut trailers = http::HeaderMap::new();
+    loop {
+        let line = reader.read_line().await.map_err(to_io_error)?;
+        if decode {
+            if line.is_empty() {
+                return Ok((copied, Trailers(trailers), reader.buffer.split_off(reader.pos)));
+            }
+            parse_trailer(&line, &mut trailers).map_err(to_io_error)?;
+            continue;
+        }
+        to.write_all(&line).await?;
+        to.write_all(b"\r\n").await?;
+        copied += line.len() as u64 + 2;
+        if line.is_empty() {
+            return Ok((copied, Trailers(trailers), reader.buffer.split_off(reader.pos)));
+        }
+    }
+}
+
+/// Appends `body` to `buffer` using the chunked transfer coding, followed by the last chunk and
+/// any trailers.
+pub fn encode(body: &[u8], trailers: Option<&Trailers>, buffer: &mut Vec<u8>) {
+    if !body.is_empty() {
+        buffer.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        buffer.extend_from_slice(body);
+        buffer.extend_from_slice(b"\r\n");
+    }
+    buffer.extend_from_slice(b"0\r\n");
+    if let Some(Trailers(trailers)) = trailers {
+        for (name, value) in trailers {
+            buffer.extend_from_slice(format!("{}: ", name).as_bytes());
+            buffer.extend_from_slice(value.as_bytes());
+            buffer.extend_from_slice(b"\r\n");
+        }
+    }
+    buffer.extend_from_slice(b"\r\n");
+}
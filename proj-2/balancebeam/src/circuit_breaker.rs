@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// When to take an upstream out of rotation based on how the requests sent to it go.
+#[derive(Debug, Clone)]
+pub struct BreakerSettings {
+    /// Consecutive failed requests (connection errors or 5xx responses) that open the circuit.
+    /// 0 disables passive health checking.
+    pub failure_threshold: usize,
+    /// How long an upstream is ejected the first time its circuit opens. The ejection doubles
+    /// every time the circuit opens again without having closed in between.
+    pub ejection_time: Duration,
+    /// The longest an upstream is ever ejected for
+    pub max_ejection_time: Duration,
+    /// Trial requests that have to succeed in a row before a half-open circuit closes
+    pub half_open_requests: usize,
+}
+
+/// The state of one upstream's circuit, as shown by the admin API.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /// Requests flow normally
+    Closed,
+    /// The upstream is ejected and gets no requests until the ejection runs out
+    Open,
+    /// The ejection has run out, and trial requests are sent one at a time to see whether the
+    /// upstream has recovered
+    HalfOpen,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half-open",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Circuit {
+    Closed {
+        /// Requests that have failed since the last one that succeeded
+        failures: usize,
+    },
+    Open {
+        until: Instant,
+        /// Times the circuit has opened since it was last closed, including this one
+        ejections: u32,
+    },
+    HalfOpen {
+        /// Trial requests that have succeeded so far
+        successes: usize,
+        /// When the trial request in flight was sent, if there is one
+        trial_started: Option<Instant>,
+        ejections: u32,
+    },
+}
+
+/// A circuit breaker for every upstream that has had a request fail recently, keyed by address so
+/// that it survives config reloads.
+#[derive(Debug, Default)]
+pub struct CircuitBreakers {
+    circuits: HashMap<String, Circuit>,
+}
+
+impl CircuitBreakers {
+    pub fn state(&self, upstream: &str, now: Instant) -> State {
+        match self.circuits.get(upstream) {
+            None | Some(Circuit::Closed { .. }) => State::Closed,
+            Some(Circuit::Open { until, .. }) if now < *until => State::Open,
+            Some(Circuit::Open { .. }) | Some(Circuit::HalfOpen { .. }) => State::HalfOpen,
+        }
+    }
+
+    /// Whether a request may be sent to an upstream now. A half-open circuit lets one trial
+    /// request through at a time; if a trial never reports back, another is allowed once an
+    /// ejection's worth of time has passed.
+    pub fn is_available(&self, upstream: &str, settings: &BreakerSettings, now: Instant) -> bool {
+        match self.circuits.get(upstream) {
+            None | Some(Circuit::Closed { .. }) => true,
+            Some(Circuit::Open { until, .. }) => now >= *until,
+            Some(Circuit::HalfOpen { trial_started, .. }) => match trial_started {
+                Some(started) => now.duration_since(*started) >= settings.ejection_time,
+                None => true,
+            },
+        }
+    }
+
+    /// How long it will be before is_available lets a request through to an upstream: until an
+    /// open circuit goes half-open, or a trial request in flight is given up on. None if a request
+    /// can be sent now.
+    pub fn available_in(&self, upstream: &str, settings: &BreakerSettings, now: Instant) -> Option<Duration> {
+        let until = match self.circuits.get(upstream)? {
+            Circuit::Open { until, .. } => *until,
+            Circuit::HalfOpen { trial_started: Some(started), .. } => *started + settings.ejection_time,
+            _ => return None,
+        };
+        if until > now {
+            Some(until - now)
+        } else {
+            None
+        }
+    }
+
+    /// Notes that a request is being sent to an upstream, which makes it a trial request if the
+    /// circuit isn't closed.
+    pub fn begin(&mut self, upstream: &str, now: Instant) {
+        if let Some(circuit) = self.circuits.get_mut(upstream) {
+            match circuit {
+                Circuit::Closed { .. } => {}
+                Circuit::Open { ejections, .. } => {
+                    log::info!("Sending a trial request to ejected upstream {}", upstream);
+                    *circuit = Circuit::HalfOpen {
+                        successes: 0,
+                        trial_started: Some(now),
+                        ejections: *ejections,
+                    };
+                }
+                Circuit::HalfOpen { trial_started, .. } => *trial_started = Some(now),
+            }
+        }
+    }
+
+    pub fn record_success(&mut self, upstream: &str, settings: &BreakerSettings) {
+        let closed = match self.circuits.get_mut(upstream) {
+            None => return,
+            Some(Circuit::Closed { .. }) => true,
+            // A request sent before the circuit opened
+            Some(Circuit::Open { .. }) => false,
+            Some(Circuit::HalfOpen {
+                successes,
+                trial_started,
+                ..
+            }) => {
+                *successes += 1;
+                *trial_started = None;
+                if *successes >= settings.half_open_requests {
+                    log::info!("Upstream {} has recovered, closing its circuit", upstream);
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        if closed {
+            // A closed circuit with no failures is the same as none at all
+            self.circuits.remove(upstream);
+        }
+    }
+
+    /// Counts a failed request against an upstream. Returns true if this ejected the upstream.
+    pub fn record_failure(&mut self, upstream: &str, settings: &BreakerSettings, now: Instant) -> bool {
+        if settings.failure_threshold == 0 {
+            return false;
+        }
+        let circuit = self
+            .circuits
+            .entry(upstream.to_string())
+            .or_insert(Circuit::Closed { failures: 0 });
+        let ejections = match circuit {
+            Circuit::Closed { failures } => {
+                *failures += 1;
+                if *failures < settings.failure_threshold {
+                    return false;
+                }
+                1
+            }
+            Circuit::Open { .. } => return false,
+            Circuit::HalfOpen { ejections, .. } => *ejections + 1,
+        };
+        let ejection_time = settings
+            .ejection_time
+            .checked_mul(1 << (ejections - 1).min(16))
+            .unwrap_or(settings.max_ejection_time)
+            .min(settings.max_ejection_time);
+        log::info!("Ejecting upstream {} for {:?} after failed requests", upstream, ejection_time);
+        *circuit = Circuit::Open {
+            until: now + ejection_time,
+            ejections,
+        };
+        true
+    }
+
+    /// Lets trial requests through to an ejected upstream straight away, since an active health
+    /// check has just found it working.
+    pub fn health_check_passed(&mut self, upstream: &str, now: Instant) {
+        if let Some(Circuit::Open { until, .. }) = self.circuits.get_mut(upstream) {
+            *until = (*until).min(now);
+        }
+    }
+
+    pub fn remove(&mut self, upstream: &str) {
+        self.circuits.remove(upstream);
+    }
+}
@@ -0,0 +1,577 @@
+use crate::CmdOptions;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Settings read from the file given with `--config`. Everything is optional: settings left out
+/// of the file keep the value given on the command line (or its default), and settings in the
+/// file take precedence over the command line. The file is TOML unless its name ends in `.yaml`
+/// or `.yml`. For example:
+///
+/// ```toml
+/// strategy = "weighted"
+/// drain_timeout = 10
+///
+/// [[upstreams]]
+/// address = "10.0.0.1:8080"
+/// weight = 3
+///
+/// [[upstreams]]
+/// address = "https://10.0.0.2:8443"
+/// server_name = "api.internal"
+///
+/// [health_check]
+/// interval = 5
+/// path = "/healthz"
+/// status = "200-299"
+/// fall = 3
+///
+/// [rate_limit]
+/// max_requests_per_minute = 600
+///
+/// [[rate_limit.rules]]
+/// path_prefix = "/api"
+/// key = "header:x-api-key"
+/// limit = 100
+///
+/// [headers]
+/// forwarded = "both"
+/// request_id = true
+///
+/// [[headers.response]]
+/// action = "remove"
+/// name = "server"
+///
+/// [timeouts]
+/// client_header = 10
+/// upstream_read = 30
+///
+/// [retry]
+/// max_retries = 3
+/// budget = 10
+///
+/// [cache]
+/// size = 67108864
+/// max_entry_size = 1048576
+///
+/// [access_log]
+/// path = "/var/log/balancebeam/access.log"
+/// format = "json"
+///
+/// [[upstream_pools]]
+/// name = "images"
+/// upstreams = ["10.0.1.1:8080", "10.0.1.2:8080"]
+/// strategy = "least-connections"
+///
+/// [upstream_pools.health_check]
+/// path = "/ping"
+///
+/// [[routes]]
+/// host = "images.example.com"
+/// pool = "images"
+///
+/// [[routes]]
+/// path_prefix = "/static"
+/// method = "GET"
+/// pool = "images"
+/// ```
+///
+/// Requests that match none of the routes go to the top-level upstreams.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// IP/port to listen on. This is only read at startup; changing it requires a restart.
+    pub bind: Option<String>,
+    /// IP/port to serve the admin API on. Like bind, this is only read at startup.
+    pub admin_bind: Option<String>,
+    /// `http` or `tcp`
+    pub mode: Option<String>,
+    pub upstreams: Option<Vec<Upstream>>,
+    pub strategy: Option<String>,
+    pub hash_header: Option<String>,
+    pub max_buffered_body: Option<usize>,
+    /// Seconds an upgraded connection (e.g. a WebSocket) may sit idle
+    pub tunnel_idle_timeout: Option<u64>,
+    /// Seconds to let requests in flight finish when shutting down
+    pub drain_timeout: Option<u64>,
+    pub health_check: HealthCheckConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub rate_limit: RateLimitConfig,
+    pub pool: PoolConfig,
+    pub tls: TlsConfig,
+    pub upstream_tls: UpstreamTlsConfig,
+    pub headers: HeadersConfig,
+    pub access_log: AccessLogConfig,
+    pub timeouts: TimeoutsConfig,
+    pub retry: RetryConfig,
+    pub cache: CacheConfig,
+    /// Named groups of upstreams that routes can send requests to
+    pub upstream_pools: Option<Vec<UpstreamPoolConfig>>,
+    /// Tried in order; the first route that matches a request picks its pool
+    pub routes: Option<Vec<Route>>,
+}
+
+/// An upstream, written either as a plain `host:port` string or as a table with an address, an
+/// optional weight and, for `https://` upstreams, an optional name to verify its certificate
+/// against.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Upstream {
+    Address(String),
+    Weighted {
+        address: String,
+        #[serde(default = "default_weight")]
+        weight: usize,
+        server_name: Option<String>,
+    },
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+impl Upstream {
+    /// Returns the upstream's address, weight and server name. A plain address may carry its
+    /// weight as `host:port=weight`, like on the command line.
+    pub fn into_parts(self) -> Result<(String, usize, Option<String>), String> {
+        match self {
+            Upstream::Address(address) => {
+                let (address, weight) = crate::strategy::parse_upstream(&address)?;
+                Ok((address, weight, None))
+            }
+            Upstream::Weighted { address, weight, server_name } => Ok((address, weight, server_name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Seconds between active health checks
+    pub interval: Option<usize>,
+    pub path: Option<String>,
+    pub method: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    /// Status codes that pass, e.g. `"200-299,304"`
+    pub status: Option<String>,
+    pub body: Option<String>,
+    pub body_regex: Option<String>,
+    /// Seconds to wait for a response
+    pub timeout: Option<u64>,
+    pub rise: Option<usize>,
+    pub fall: Option<usize>,
+}
+
+/// Passive health checking: when to eject upstreams whose requests keep failing.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: Option<usize>,
+    /// Seconds an upstream is first ejected for
+    pub ejection_time: Option<u64>,
+    pub max_ejection_time: Option<u64>,
+    pub half_open_requests: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub max_requests_per_minute: Option<usize>,
+    pub algorithm: Option<String>,
+    /// Seconds max_requests_per_minute applies to
+    pub window: Option<u64>,
+    /// What requests are counted by: `ip`, `path-prefix` or `header:<name>`
+    pub key: Option<String>,
+    /// Limits for particular routes, taking precedence over max_requests_per_minute
+    pub rules: Option<Vec<RateLimitRule>>,
+}
+
+/// A rate limit for requests under a path prefix. The key, algorithm and window default to those
+/// set for the rate limit as a whole. A limit of 0 exempts the route from rate limiting.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRule {
+    pub path_prefix: String,
+    pub limit: usize,
+    pub key: Option<String>,
+    pub algorithm: Option<String>,
+    /// Seconds the limit applies to
+    pub window: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub max_idle: Option<usize>,
+    pub max_per_upstream: Option<usize>,
+    /// Seconds an idle connection is kept around
+    pub idle_timeout: Option<u64>,
+    /// Seconds to wait for a new connection to an upstream
+    pub connect_timeout: Option<u64>,
+}
+
+/// Seconds to wait on clients and upstreams before giving up on them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub client_header: Option<u64>,
+    pub client_body: Option<u64>,
+    pub keep_alive: Option<u64>,
+    pub upstream_read: Option<u64>,
+}
+
+/// When failed requests are retried on another upstream.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_retries: Option<usize>,
+    /// Milliseconds to wait at most before the first retry
+    pub backoff: Option<u64>,
+    /// Milliseconds to wait at most before any retry
+    pub max_backoff: Option<u64>,
+    /// Percentage retries may add to recent requests
+    pub budget: Option<usize>,
+    /// Retries per second allowed on top of the budget
+    pub budget_min: Option<usize>,
+}
+
+/// The in-memory response cache.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Bytes of responses to keep (0 turns caching off)
+    pub size: Option<usize>,
+    /// Bytes of the biggest response to keep
+    pub max_entry_size: Option<usize>,
+}
+
+/// Certificates for serving HTTPS. Certificate files are read again on every reload.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain used when no SNI certificate matches
+    pub cert: Option<String>,
+    /// PEM private key (PKCS#8) for cert
+    pub key: Option<String>,
+    pub sni: Option<Vec<SniCertificate>>,
+}
+
+/// A certificate served to clients that ask for `server_name`, which may start with `*.` to cover
+/// every subdomain one level down.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertificate {
+    pub server_name: String,
+    pub cert: String,
+    pub key: String,
+}
+
+/// How `https://` upstreams are verified, and the client certificate presented to them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// PEM CA bundle, replacing the system's trusted roots
+    pub ca: Option<String>,
+    pub client_cert: Option<String>,
+    /// PEM private key (PKCS#8) for client_cert
+    pub client_key: Option<String>,
+}
+
+/// How request and response headers are rewritten.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadersConfig {
+    /// `x-forwarded`, `forwarded`, `both` or `none`
+    pub forwarded: Option<String>,
+    pub via: Option<bool>,
+    pub request_id: Option<bool>,
+    /// Applied in order to every request, after the headers above have been added
+    pub request: Option<Vec<HeaderRule>>,
+    /// Applied in order to every response from an upstream
+    pub response: Option<Vec<HeaderRule>>,
+}
+
+/// A change to a header: `add` or `set` it to `value`, `remove` it, or `rename` it `to` another
+/// name.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRule {
+    pub action: String,
+    pub name: String,
+    pub value: Option<String>,
+    pub to: Option<String>,
+}
+
+/// A record of every request, kept apart from balancebeam's own log.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// File to write to, or `-` for stdout
+    pub path: Option<String>,
+    /// `common`, `combined` or `json`
+    pub format: Option<String>,
+    /// Bytes the file may grow to before it is rotated
+    pub max_size: Option<u64>,
+    /// Rotated files to keep
+    pub max_files: Option<usize>,
+}
+
+/// A named group of upstreams. The strategy and health check settings left out default to the
+/// top-level ones.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamPoolConfig {
+    pub name: String,
+    pub upstreams: Vec<Upstream>,
+    pub strategy: Option<String>,
+    pub hash_header: Option<String>,
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+}
+
+/// Sends the requests that match everything given here to a pool, which is either one of the
+/// upstream_pools or `default` for the top-level upstreams. A host may start with `*.` to cover
+/// every subdomain one level down.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+    pub path_regex: Option<String>,
+    pub method: Option<String>,
+    /// Headers the request has to carry, with exactly these values
+    pub headers: Option<BTreeMap<String, String>>,
+    pub pool: String,
+}
+
+/// Reads and parses a configuration file.
+pub fn load(path: &str) -> Result<Config, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read config file {}: {}", path, err))?;
+    if path.ends_with(".yaml") || path.ends_with(".yml") {
+        serde_yaml::from_str(&contents)
+            .map_err(|err| format!("Invalid config file {}: {}", path, err))
+    } else {
+        toml::from_str(&contents).map_err(|err| format!("Invalid config file {}: {}", path, err))
+    }
+}
+
+impl Config {
+    /// Overrides the command-line options with whatever is set in this config.
+    pub fn apply(self, options: &mut CmdOptions) {
+        if let Some(bind) = self.bind {
+            options.bind = bind;
+        }
+        if let Some(upstreams) = self.upstreams {
+            options.upstream_server_names.clear();
+            options.upstream = upstreams
+                .into_iter()
+                .map(|upstream| match upstream {
+                    Upstream::Address(address) => address,
+                    Upstream::Weighted { address, weight, server_name } => {
+                        if let Some(server_name) = server_name {
+                            options.upstream_server_names.insert(address.clone(), server_name);
+                        }
+                        format!("{}={}", address, weight)
+                    }
+                })
+                .collect();
+        }
+        if self.admin_bind.is_some() {
+            options.admin_bind = self.admin_bind;
+        }
+        if let Some(mode) = self.mode {
+            options.mode = mode;
+        }
+        if let Some(strategy) = self.strategy {
+            options.strategy = strategy;
+        }
+        if self.hash_header.is_some() {
+            options.hash_header = self.hash_header;
+        }
+        if let Some(max_buffered_body) = self.max_buffered_body {
+            options.max_buffered_body = max_buffered_body;
+        }
+        if let Some(tunnel_idle_timeout) = self.tunnel_idle_timeout {
+            options.tunnel_idle_timeout = tunnel_idle_timeout;
+        }
+        if let Some(drain_timeout) = self.drain_timeout {
+            options.drain_timeout = drain_timeout;
+        }
+        self.health_check.apply(options);
+        if let Some(failure_threshold) = self.circuit_breaker.failure_threshold {
+            options.passive_failure_threshold = failure_threshold;
+        }
+        if let Some(ejection_time) = self.circuit_breaker.ejection_time {
+            options.ejection_time = ejection_time;
+        }
+        if let Some(max_ejection_time) = self.circuit_breaker.max_ejection_time {
+            options.max_ejection_time = max_ejection_time;
+        }
+        if let Some(half_open_requests) = self.circuit_breaker.half_open_requests {
+            options.half_open_requests = half_open_requests;
+        }
+        if let Some(max_requests_per_minute) = self.rate_limit.max_requests_per_minute {
+            options.max_requests_per_minute = max_requests_per_minute;
+        }
+        if let Some(algorithm) = self.rate_limit.algorithm {
+            options.rate_limit_algorithm = algorithm;
+        }
+        if let Some(window) = self.rate_limit.window {
+            options.rate_limit_window = window;
+        }
+        if let Some(key) = self.rate_limit.key {
+            options.rate_limit_key = key;
+        }
+        if let Some(rules) = self.rate_limit.rules {
+            options.rate_limit_rules = rules;
+        }
+        if let Some(max_idle) = self.pool.max_idle {
+            options.pool_max_idle = max_idle;
+        }
+        if let Some(max_per_upstream) = self.pool.max_per_upstream {
+            options.pool_max_per_upstream = max_per_upstream;
+        }
+        if let Some(idle_timeout) = self.pool.idle_timeout {
+            options.pool_idle_timeout = idle_timeout;
+        }
+        if let Some(connect_timeout) = self.pool.connect_timeout {
+            options.connect_timeout = connect_timeout;
+        }
+        if let Some(client_header) = self.timeouts.client_header {
+            options.client_header_timeout = client_header;
+        }
+        if let Some(client_body) = self.timeouts.client_body {
+            options.client_body_timeout = client_body;
+        }
+        if let Some(keep_alive) = self.timeouts.keep_alive {
+            options.keep_alive_timeout = keep_alive;
+        }
+        if let Some(upstream_read) = self.timeouts.upstream_read {
+            options.upstream_read_timeout = upstream_read;
+        }
+        if let Some(max_retries) = self.retry.max_retries {
+            options.max_retries = max_retries;
+        }
+        if let Some(backoff) = self.retry.backoff {
+            options.retry_backoff = backoff;
+        }
+        if let Some(max_backoff) = self.retry.max_backoff {
+            options.retry_max_backoff = max_backoff;
+        }
+        if let Some(budget) = self.retry.budget {
+            options.retry_budget = budget;
+        }
+        if let Some(budget_min) = self.retry.budget_min {
+            options.retry_budget_min = budget_min;
+        }
+        if let Some(size) = self.cache.size {
+            options.cache_size = size;
+        }
+        if let Some(max_entry_size) = self.cache.max_entry_size {
+            options.cache_max_entry_size = max_entry_size;
+        }
+        if self.tls.cert.is_some() {
+            options.tls_cert = self.tls.cert;
+        }
+        if self.tls.key.is_some() {
+            options.tls_key = self.tls.key;
+        }
+        if let Some(sni) = self.tls.sni {
+            options.tls_sni = sni;
+        }
+        if self.upstream_tls.ca.is_some() {
+            options.upstream_ca = self.upstream_tls.ca;
+        }
+        if self.upstream_tls.client_cert.is_some() {
+            options.upstream_client_cert = self.upstream_tls.client_cert;
+        }
+        if self.upstream_tls.client_key.is_some() {
+            options.upstream_client_key = self.upstream_tls.client_key;
+        }
+        if let Some(forwarded) = self.headers.forwarded {
+            options.forwarded_headers = forwarded;
+        }
+        if let Some(via) = self.headers.via {
+            options.via = via;
+        }
+        if let Some(request_id) = self.headers.request_id {
+            options.request_id = request_id;
+        }
+        if let Some(rules) = self.headers.request {
+            options.request_header_rules = rules;
+        }
+        if let Some(rules) = self.headers.response {
+            options.response_header_rules = rules;
+        }
+        if self.access_log.path.is_some() {
+            options.access_log = self.access_log.path;
+        }
+        if let Some(format) = self.access_log.format {
+            options.access_log_format = format;
+        }
+        if let Some(max_size) = self.access_log.max_size {
+            options.access_log_max_size = max_size;
+        }
+        if let Some(max_files) = self.access_log.max_files {
+            options.access_log_max_files = max_files;
+        }
+        if let Some(upstream_pools) = self.upstream_pools {
+            options.upstream_pools = upstream_pools;
+        }
+        if let Some(routes) = self.routes {
+            options.routes = routes;
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    /// Overrides the active health check options with whatever is set here.
+    pub fn apply(self, options: &mut CmdOptions) {
+        if let Some(interval) = self.interval {
+            options.active_health_check_interval = interval;
+        }
+        if let Some(path) = self.path {
+            options.active_health_check_path = path;
+        }
+        if let Some(method) = self.method {
+            options.active_health_check_method = method;
+        }
+        if let Some(headers) = self.headers {
+            options.active_health_check_header = headers
+                .into_iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect();
+        }
+        if let Some(status) = self.status {
+            options.active_health_check_status = status;
+        }
+        if self.body.is_some() {
+            options.active_health_check_body = self.body;
+        }
+        if self.body_regex.is_some() {
+            options.active_health_check_body_regex = self.body_regex;
+        }
+        if let Some(timeout) = self.timeout {
+            options.active_health_check_timeout = timeout;
+        }
+        if let Some(rise) = self.rise {
+            options.active_health_check_rise = rise;
+        }
+        if let Some(fall) = self.fall {
+            options.active_health_check_fall = fall;
+        }
+    }
+}
+
+impl UpstreamPoolConfig {
+    /// Overrides the strategy and health check options with the pool's own.
+    pub fn apply(&self, options: &mut CmdOptions) {
+        if let Some(strategy) = &self.strategy {
+            options.strategy = strategy.clone();
+        }
+        if self.hash_header.is_some() {
+            options.hash_header = self.hash_header.clone();
+        }
+        self.health_check.clone().apply(options);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::{config, CmdOptions};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Headers that only apply to a single connection, which a proxy must not pass on (RFC 7230
+/// section 6.1, RFC 2616 section 13.5.1). Transfer-Encoding is left alone, since bodies are
+/// relayed with the framing they arrived with.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "upgrade",
+    "proxy-authenticate",
+    "proxy-authorization",
+];
+
+/// Headers that say where a message's body ends or where a request is going. The Connection
+/// header can't make these hop-by-hop: dropping Content-Length or Transfer-Encoding would let a
+/// client smuggle a second request past us in the body of the first.
+const NEVER_HOP_BY_HOP: &[&str] = &["content-length", "transfer-encoding", "host"];
+
+/// Name balancebeam goes by in Via headers.
+const VIA_PSEUDONYM: &str = "balancebeam";
+
+const REQUEST_ID: &str = "x-request-id";
+
+/// Which headers tell upstreams who the client is and how it reached us.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForwardedStyle {
+    /// X-Forwarded-For, X-Forwarded-Proto and X-Forwarded-Host
+    XForwarded,
+    /// Forwarded (RFC 7239)
+    Forwarded,
+    Both,
+    None,
+}
+
+impl ForwardedStyle {
+    pub fn parse(style: &str) -> Result<ForwardedStyle, String> {
+        match style {
+            "x-forwarded" => Ok(ForwardedStyle::XForwarded),
+            "forwarded" => Ok(ForwardedStyle::Forwarded),
+            "both" => Ok(ForwardedStyle::Both),
+            "none" => Ok(ForwardedStyle::None),
+            _ => Err(format!(
+                "Unknown forwarded headers {:?} (expected x-forwarded, forwarded, both or none)",
+                style
+            )),
+        }
+    }
+}
+
+/// A change to make to the headers of every request or response.
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// Adds a value, keeping any the header already has
+    Add(HeaderName, HeaderValue),
+    /// Replaces whatever values the header has
+    Set(HeaderName, HeaderValue),
+    Remove(HeaderName),
+    /// Moves every value of the first header to the second
+    Rename(HeaderName, HeaderName),
+}
+
+impl Rule {
+    pub fn parse(rule: &config::HeaderRule) -> Result<Rule, String> {
+        let name = parse_name(&rule.name)?;
+        let value = || match &rule.value {
+            Some(value) => HeaderValue::from_str(value)
+                .map_err(|_| format!("Invalid value {:?} for header {}", value, rule.name)),
+            None => Err(format!("Header rule to {} {} needs a value", rule.action, rule.name)),
+        };
+        match rule.action.as_str() {
+            "add" => Ok(Rule::Add(name, value()?)),
+            "set" => Ok(Rule::Set(name, value()?)),
+            "remove" => Ok(Rule::Remove(name)),
+            "rename" => match &rule.to {
+                Some(to) => Ok(Rule::Rename(name, parse_name(to)?)),
+                None => Err(format!("Header rule to rename {} needs a new name (to)", rule.name)),
+            },
+            action => Err(format!(
+                "Unknown header rule action {:?} (expected add, set, remove or rename)",
+                action
+            )),
+        }
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        match self {
+            Rule::Add(name, value) => {
+                headers.append(name, value.clone());
+            }
+            Rule::Set(name, value) => {
+                headers.insert(name, value.clone());
+            }
+            Rule::Remove(name) => {
+                headers.remove(name);
+            }
+            Rule::Rename(from, to) => {
+                let values: Vec<HeaderValue> = headers.get_all(from).iter().cloned().collect();
+                headers.remove(from);
+                for value in values {
+                    headers.append(to, value);
+                }
+            }
+        }
+    }
+}
+
+fn parse_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("Invalid header name {:?}", name))
+}
+
+/// How the headers of requests and responses are rewritten on their way through.
+#[derive(Debug, Clone)]
+pub struct HeaderRewrites {
+    pub forwarded: ForwardedStyle,
+    /// Whether to add ourselves to the Via header of requests and responses
+    pub via: bool,
+    /// Whether to give every request an X-Request-Id (unless it has one), and echo it back on
+    /// the response
+    pub request_id: bool,
+    pub request_rules: Vec<Rule>,
+    pub response_rules: Vec<Rule>,
+}
+
+impl HeaderRewrites {
+    pub fn from_options(options: &CmdOptions) -> Result<HeaderRewrites, String> {
+        Ok(HeaderRewrites {
+            forwarded: ForwardedStyle::parse(&options.forwarded_headers)?,
+            via: options.via,
+            request_id: options.request_id,
+            request_rules: options.request_header_rules.iter().map(Rule::parse).collect::<Result<_, _>>()?,
+            response_rules: options.response_header_rules.iter().map(Rule::parse).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Rewrites a request read from a client (over `scheme`, speaking `version`) before it is
+    /// forwarded: drops the headers that only applied to the client connection, adds the
+    /// forwarding headers, then applies the request rules. Returns the request's ID, if request
+    /// IDs are on.
+    pub fn rewrite_request(&self, request: &mut http::Request<Vec<u8>>, client_ip: &str,
+            scheme: &str, version: http::Version) -> Option<HeaderValue> {
+        let headers = request.headers_mut();
+        strip_hop_by_hop(headers);
+        if matches!(self.forwarded, ForwardedStyle::XForwarded | ForwardedStyle::Both) {
+            append_to_list(headers, "x-forwarded-for", client_ip);
+            headers.insert("x-forwarded-proto", HeaderValue::from_str(scheme).unwrap());
+            if let Some(host) = headers.get(http::header::HOST).cloned() {
+                headers.insert("x-forwarded-host", host);
+            }
+        }
+        if matches!(self.forwarded, ForwardedStyle::Forwarded | ForwardedStyle::Both) {
+            let mut element = format!("for={}", quote(&node(client_ip)));
+            if let Some(host) = headers.get(http::header::HOST).and_then(|host| host.to_str().ok()) {
+                element.push_str(&format!(";host={}", quote(host)));
+            }
+            element.push_str(&format!(";proto={}", scheme));
+            append_to_list(headers, "forwarded", &element);
+        }
+        if self.via {
+            append_to_list(headers, "via", &via_entry(version));
+        }
+        let request_id = if self.request_id {
+            let request_id = headers
+                .get(REQUEST_ID)
+                .cloned()
+                .unwrap_or_else(|| HeaderValue::from_str(&generate_request_id()).unwrap());
+            headers.insert(REQUEST_ID, request_id.clone());
+            Some(request_id)
+        } else {
+            None
+        };
+        for rule in self.request_rules.iter() {
+            rule.apply(headers);
+        }
+        request_id
+    }
+
+    /// Rewrites a response from an upstream before it is sent to the client: drops the headers
+    /// that only applied to the upstream connection, adds Via and the request's ID, then applies
+    /// the response rules.
+    pub fn rewrite_response(&self, response: &mut http::Response<Vec<u8>>,
+            request_id: Option<&HeaderValue>) {
+        let version = response.version();
+        let headers = response.headers_mut();
+        strip_hop_by_hop(headers);
+        if self.via {
+            append_to_list(headers, "via", &via_entry(version));
+        }
+        if let Some(request_id) = request_id {
+            headers.insert(REQUEST_ID, request_id.clone());
+        }
+        for rule in self.response_rules.iter() {
+            rule.apply(headers);
+        }
+    }
+}
+
+/// Removes the hop-by-hop headers, along with any other headers the Connection header names
+/// (except those in NEVER_HOP_BY_HOP). A request or response that switches protocols keeps its
+/// Upgrade header and `Connection: upgrade`, so that the switch can happen across the proxy.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty() && !NEVER_HOP_BY_HOP.contains(&name.as_str()))
+        .collect();
+    let upgrade: Vec<HeaderValue> = if named.iter().any(|name| name == "upgrade") {
+        headers.get_all(http::header::UPGRADE).iter().cloned().collect()
+    } else {
+        Vec::new()
+    };
+    for name in HOP_BY_HOP.iter().copied().chain(named.iter().map(String::as_str)) {
+        headers.remove(name);
+    }
+    if !upgrade.is_empty() {
+        headers.insert(http::header::CONNECTION, HeaderValue::from_static("upgrade"));
+        for value in upgrade {
+            headers.append(http::header::UPGRADE, value);
+        }
+    }
+}
+
+/// Adds an element to a comma-separated header, on the same line as any there already.
+fn append_to_list(headers: &mut HeaderMap, name: &'static str, element: &str) {
+    let existing: Vec<&[u8]> = headers.get_all(name).iter().map(|value| value.as_bytes()).collect();
+    let mut value = existing.join(&b", "[..]);
+    if !value.is_empty() {
+        value.extend_from_slice(b", ");
+    }
+    value.extend_from_slice(element.as_bytes());
+    headers.insert(name, HeaderValue::from_bytes(&value).unwrap());
+}
+
+/// How a client's address is written in a Forwarded header, where IPv6 addresses go in brackets.
+fn node(client_ip: &str) -> String {
+    if client_ip.contains(':') {
+        format!("[{}]", client_ip)
+    } else {
+        client_ip.to_string()
+    }
+}
+
+/// Quotes a Forwarded header value unless it is a plain token (RFC 7239 section 4).
+fn quote(value: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn via_entry(version: http::Version) -> String {
+    let protocol = match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    format!("{} {}", protocol, VIA_PSEUDONYM)
+}
+
+/// Generates a random (version 4) UUID to identify a request by.
+fn generate_request_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
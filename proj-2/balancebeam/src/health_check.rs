@@ -0,0 +1,185 @@
+use crate::upstream::Endpoint;
+use crate::{request, response, CmdOptions};
+use regex::Regex;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// How upstreams are probed by active health checks, and what counts as healthy.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub path: String,
+    pub method: http::Method,
+    /// Sent with every probe. A Host header here replaces the default of the upstream's address.
+    pub headers: Vec<(http::header::HeaderName, http::header::HeaderValue)>,
+    /// Status codes that pass the check
+    pub healthy_statuses: Vec<RangeInclusive<u16>>,
+    /// If set, the response body has to contain this
+    pub body_contains: Option<String>,
+    /// If set, the response body has to match this
+    pub body_regex: Option<Regex>,
+    /// Only check that the upstream accepts connections, without sending a request (tcp mode)
+    pub connect_only: bool,
+    /// A probe that takes longer than this fails
+    pub timeout: Duration,
+    /// Probes that have to pass in a row before an unhealthy upstream is marked healthy
+    pub rise: usize,
+    /// Probes that have to fail in a row before a healthy upstream is marked unhealthy
+    pub fall: usize,
+}
+
+/// Parses a list of status codes and ranges, such as `200-299,304`.
+pub fn parse_status_ranges(ranges: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    let invalid = || format!("Invalid health check status range {:?}", ranges);
+    ranges
+        .split(',')
+        .map(|range| {
+            let range = range.trim();
+            let (start, end) = match range.find('-') {
+                Some(dash) => (&range[..dash], &range[dash + 1..]),
+                None => (range, range),
+            };
+            let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+            let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+/// Parses a header given as `Name: value`.
+pub fn parse_header(
+    header: &str,
+) -> Result<(http::header::HeaderName, http::header::HeaderValue), String> {
+    let invalid = || format!("Invalid health check header {:?} (expected Name: value)", header);
+    let colon = header.find(':').ok_or_else(invalid)?;
+    let name = http::header::HeaderName::from_bytes(header[..colon].trim().as_bytes())
+        .map_err(|_| invalid())?;
+    let value = http::header::HeaderValue::from_str(header[colon + 1..].trim())
+        .map_err(|_| invalid())?;
+    Ok((name, value))
+}
+
+impl HealthCheck {
+    /// Builds the health check described by the --active-health-check-* options. With
+    /// `connect_only`, probes only check that upstreams accept connections.
+    pub fn from_options(options: &CmdOptions, connect_only: bool) -> Result<HealthCheck, String> {
+        Ok(HealthCheck {
+            path: options.active_health_check_path.clone(),
+            method: options.active_health_check_method.parse().map_err(|_| {
+                format!("Invalid health check method {:?}", options.active_health_check_method)
+            })?,
+            headers: options
+                .active_health_check_header
+                .iter()
+                .map(|header| parse_header(header))
+                .collect::<Result<_, _>>()?,
+            healthy_statuses: parse_status_ranges(&options.active_health_check_status)?,
+            body_contains: options.active_health_check_body.clone(),
+            body_regex: match &options.active_health_check_body_regex {
+                Some(regex) => Some(Regex::new(regex).map_err(|err| {
+                    format!("Invalid health check body regex {:?}: {}", regex, err)
+                })?),
+                None => None,
+            },
+            connect_only,
+            timeout: Duration::from_secs(options.active_health_check_timeout.max(1)),
+            rise: options.active_health_check_rise.max(1),
+            fall: options.active_health_check_fall.max(1),
+        })
+    }
+
+    /// Sends a probe to an upstream, returning why it failed if it did.
+    pub async fn probe(&self, upstream: &Endpoint) -> Result<(), String> {
+        match tokio::time::timeout(self.timeout, self.send_probe(upstream)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("No response within {:?}", self.timeout)),
+        }
+    }
+
+    async fn send_probe(&self, upstream: &Endpoint) -> Result<(), String> {
+        let mut upstream_conn = upstream
+            .connect(self.timeout)
+            .await
+            .map_err(|err| format!("Connection error: {}", err))?;
+        if self.connect_only {
+            return Ok(());
+        }
+        let mut builder = http::Request::builder()
+            .method(self.method.clone())
+            .uri(&self.path);
+        if !self.headers.iter().any(|(name, _)| name == http::header::HOST) {
+            builder = builder.header("Host", &upstream.address);
+        }
+        for (name, value) in self.headers.iter() {
+            builder = builder.header(name, value);
+        }
+        let request = builder.body(Vec::new()).unwrap();
+
+        request::write_to_stream(&request, &mut upstream_conn)
+            .await
+            .map_err(|_| "Failed to send request to upstream".to_string())?;
+        let response = response::read_from_stream(&mut upstream_conn, request.method())
+            .await
+            .map_err(|_| "Error reading response from server".to_string())?;
+
+        let status = response.status().as_u16();
+        if !self.healthy_statuses.iter().any(|range| range.contains(&status)) {
+            return Err(format!("Response status {} is not healthy", status));
+        }
+        if self.body_contains.is_some() || self.body_regex.is_some() {
+            let body = String::from_utf8_lossy(response.body());
+            if let Some(expected) = &self.body_contains {
+                if !body.contains(expected.as_str()) {
+                    return Err(format!("Response body does not contain {:?}", expected));
+                }
+            }
+            if let Some(regex) = &self.body_regex {
+                if !regex.is_match(&body) {
+                    return Err(format!("Response body does not match {:?}", regex.as_str()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Probe results for one upstream, which only change its health once enough of them agree.
+#[derive(Debug)]
+pub struct Tracker {
+    healthy: bool,
+    /// Consecutive probes that disagreed with `healthy`
+    streak: usize,
+}
+
+impl Default for Tracker {
+    /// Upstreams are assumed to be healthy until they fail enough probes
+    fn default() -> Tracker {
+        Tracker {
+            healthy: true,
+            streak: 0,
+        }
+    }
+}
+
+impl Tracker {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+
+    /// Counts a probe result, returning whether the upstream is now considered healthy.
+    pub fn record(&mut self, passed: bool, check: &HealthCheck) -> bool {
+        if passed == self.healthy {
+            self.streak = 0;
+            return self.healthy;
+        }
+        self.streak += 1;
+        let threshold = if self.healthy { check.fall } else { check.rise };
+        if self.streak >= threshold {
+            self.healthy = passed;
+            self.streak = 0;
+        }
+        self.healthy
+    }
+}
@@ -0,0 +1,550 @@
+use crate::body::{self, Framing};
+use crate::chunked::Trailers;
+use crate::rate_limiter::RateLimit;
+use crate::shutdown::Shutdown;
+use crate::timeout::ReadTimeout;
+use crate::access_log::RequestTimer;
+use crate::{current_state, log_access, response, ClientStream, Forwarded, ProxyState, ReportState,
+    SharedState, StreamingBody};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{Reason, RecvStream, SendStream};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::{mpsc, RwLock};
+
+/// Every HTTP/2 connection starts with this, whether the client negotiated HTTP/2 with ALPN or
+/// knew in advance that we speak it (h2c with prior knowledge, RFC 7540 section 3.4).
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers that only apply to a single HTTP/1.x connection, which HTTP/2 does without
+/// (RFC 7540 section 8.1.2.2).
+const CONNECTION_HEADERS: &[&str] =
+    &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// A client stream with the bytes that were read off the front of it to tell which protocol the
+/// client speaks put back.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let len = buf.len().min(this.prefix.len() - this.pos);
+            buf[..len].copy_from_slice(&this.prefix[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(len));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads just enough of what a client sends first to tell whether it speaks HTTP/2. Returns the
+/// stream with those bytes put back, and whether it does.
+pub async fn detect<S: AsyncRead + Unpin>(mut stream: S) -> io::Result<(Rewind<S>, bool)> {
+    let mut prefix = vec![0; PREFACE.len()];
+    let mut len = 0;
+    // An HTTP/1.x request line gives itself away by its first byte, so this only waits for more
+    // than one read if the client really is starting a preface
+    while len < PREFACE.len() && PREFACE.starts_with(&prefix[..len]) {
+        let bytes_read = stream.read(&mut prefix[len..]).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        len += bytes_read;
+    }
+    prefix.truncate(len);
+    let is_http2 = prefix == PREFACE;
+    Ok((Rewind { prefix, pos: 0, inner: stream }, is_http2))
+}
+
+/// Serves an HTTP/2 connection. Every stream the client opens is handled concurrently, and is
+/// forwarded to an upstream as an HTTP/1.1 request of its own, so requests multiplexed over one
+/// client connection may be balanced across different upstreams. Once we start shutting down, the
+/// client is sent a GOAWAY, and the connection closes when the streams it has open are done (or
+/// straight away, if it has none open). A connection with no streams open is closed once it has
+/// been idle for as long as an HTTP/1.x one would be.
+pub async fn serve<C: ClientStream>(client_conn: Rewind<C>, client_ip: String, scheme: &'static str,
+        shared_state: &SharedState, report_state: Arc<RwLock<ReportState>>,
+        rate_limit_count: Arc<RwLock<RateLimit>>, mut shutdown: Shutdown) {
+    let timeouts = current_state(shared_state).timeouts;
+    let handshake = tokio::time::timeout(timeouts.client_header, h2::server::handshake(client_conn));
+    let handshake = tokio::select! {
+        handshake = handshake => handshake,
+        _ = shutdown.draining() => return,
+    };
+    let mut connection = match handshake {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(error)) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", client_ip, error);
+            return;
+        }
+        Err(_) => {
+            log::info!("Timed out waiting for HTTP/2 handshake with {}", client_ip);
+            return;
+        }
+    };
+    log::debug!("Speaking HTTP/2 with {}", client_ip);
+    let mut going_away = false;
+    // Like an HTTP/1.x connection, the client gets the header timeout to start its first request,
+    // and the keep-alive timeout to start each one after that
+    let mut idle_timeout = timeouts.client_header;
+    let mut idle_since = Instant::now();
+    let mut open_streams = 0;
+    let (stream_finished, mut streams_finished) = mpsc::unbounded_channel();
+    loop {
+        let idle_deadline = tokio::time::delay_until((idle_since + idle_timeout).into());
+        let accepted = tokio::select! {
+            stream = connection.accept() => Some(stream),
+            _ = shutdown.draining(), if !going_away => None,
+            _ = streams_finished.recv() => {
+                open_streams -= 1;
+                idle_since = Instant::now();
+                continue;
+            }
+            _ = idle_deadline, if open_streams == 0 => {
+                log::debug!("Client sent no request for {:?}. Shutting down connection", idle_timeout);
+                return;
+            }
+        };
+        let (request, respond) = match accepted {
+            Some(Some(Ok(stream))) => stream,
+            Some(Some(Err(error))) => {
+                log::info!("HTTP/2 connection from {} failed: {}", client_ip, error);
+                return;
+            }
+            Some(None) => break,
+            // A client with nothing in flight has nothing to finish, and may never answer the
+            // GOAWAY, so it is just hung up on
+            None if open_streams == 0 => {
+                log::debug!("Shutting down idle HTTP/2 connection from {}", client_ip);
+                return;
+            }
+            None => {
+                log::debug!("Sending GOAWAY to {}", client_ip);
+                connection.graceful_shutdown();
+                going_away = true;
+                continue;
+            }
+        };
+        // Pick up the latest configuration for every request, so that a reload applies to
+        // connections that are already open
+        let state = current_state(shared_state);
+        let client_ip = client_ip.clone();
+        let report_state = Arc::clone(&report_state);
+        let rate_limit_count = Arc::clone(&rate_limit_count);
+        // Each stream holds up a shutdown until it is done
+        let shutdown = shutdown.clone();
+        let stream_finished = stream_finished.clone();
+        open_streams += 1;
+        idle_timeout = timeouts.keep_alive;
+        tokio::spawn(async move {
+            handle_stream(request, respond, &client_ip, scheme, &state, &report_state, &rate_limit_count)
+                .await;
+            let _ = stream_finished.send(());
+            drop(shutdown);
+        });
+    }
+    log::debug!("Client finished sending requests. Shutting down connection");
+}
+
+/// Forwards the request on a single HTTP/2 stream, and sends the upstream's response back on it.
+async fn handle_stream(request: http::Request<RecvStream>, mut respond: SendResponse<Bytes>,
+        client_ip: &str, scheme: &str, state: &ProxyState, report_state: &Arc<RwLock<ReportState>>,
+        rate_limit_count: &Arc<RwLock<RateLimit>>) {
+    let timer = RequestTimer::start();
+    let read = read_request(request, state.max_buffered_body, state.timeouts.client_body);
+    let (mut request, mut request_body) = match read.await {
+        Ok(request) => request,
+        Err(ReadError::TimedOut) => {
+            log::info!("Timed out reading HTTP/2 request from {}", client_ip);
+            let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+            send_response(&mut respond, client_ip, &response);
+            return;
+        }
+        Err(ReadError::Stream(error)) => {
+            log::info!("Error reading HTTP/2 request from {}: {}", client_ip, error);
+            respond.send_reset(error.reason().unwrap_or(Reason::INTERNAL_ERROR));
+            return;
+        }
+    };
+
+    if let Some(decision) = crate::rate_limit(client_ip, &request, state, rate_limit_count).await {
+        state.metrics.record_rate_limited();
+        let response = crate::make_rate_limited_response(&decision);
+        send_response(&mut respond, client_ip, &response);
+        log_access(state, client_ip, &request, timer, response.status(), response.body().len() as u64, None);
+        return;
+    }
+
+    // Add X-Forwarded-For and friends so that the upstream server knows the client's IP address
+    let request_id = state.headers.rewrite_request(&mut request, client_ip, scheme, http::Version::HTTP_2);
+
+    // The stream ends where the request body does, so nothing is ever read past it
+    let mut leftover = Vec::new();
+    let mut body_reader = request_body
+        .as_mut()
+        .map(|(framing, reader)| (*framing, ReadTimeout::new(reader, state.timeouts.client_body)));
+    let streamed_body = body_reader.as_mut().map(|(framing, reader)| (*framing, reader, &mut leftover));
+    let forwarded = crate::fetch(state, report_state, client_ip, &request, streamed_body)
+        .await
+        .map(|mut forwarded| {
+            state.headers.rewrite_response(&mut forwarded.response, request_id.as_ref());
+            forwarded
+        });
+    let (status, bytes, upstream) = match forwarded {
+        // An HTTP/2 stream can't switch protocols
+        Ok(Forwarded { response, upstream, .. })
+                if response.status() == http::StatusCode::SWITCHING_PROTOCOLS => {
+            log::warn!("Upstream switched protocols on an HTTP/2 request from {}", client_ip);
+            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            send_response(&mut respond, client_ip, &response);
+            (response.status(), response.body().len() as u64, upstream)
+        }
+        Ok(Forwarded { response, streaming_body: None, upstream }) => {
+            send_response(&mut respond, client_ip, &response);
+            (response.status(), response.body().len() as u64, upstream)
+        }
+        Ok(Forwarded { response, streaming_body: Some(streaming_body), upstream }) => {
+            let bytes = stream_response(state, &mut respond, client_ip, &response, streaming_body).await;
+            (response.status(), bytes, upstream)
+        }
+        Err(error) => {
+            let response = crate::make_error_response(state, report_state, error).await;
+            send_response(&mut respond, client_ip, &response);
+            (response.status(), response.body().len() as u64, None)
+        }
+    };
+    log_access(state, client_ip, &request, timer, status, bytes, upstream);
+}
+
+/// Why a request couldn't be read off an HTTP/2 stream.
+enum ReadError {
+    /// The client sent nothing more of the body for the client body timeout
+    TimedOut,
+    Stream(h2::Error),
+}
+
+impl From<h2::Error> for ReadError {
+    fn from(error: h2::Error) -> ReadError {
+        ReadError::Stream(error)
+    }
+}
+
+/// Waits up to `timeout` for `read` to finish, failing with ReadError::TimedOut if it doesn't.
+async fn within<T>(timeout: Duration, read: impl std::future::Future<Output = T>) -> Result<T, ReadError> {
+    tokio::time::timeout(timeout, read).await.map_err(|_| ReadError::TimedOut)
+}
+
+/// Turns an HTTP/2 request into the HTTP/1.1 request we send upstream. The body is read into the
+/// request if it is no bigger than max_buffered_body, with each read waiting up to `timeout`.
+/// Otherwise, the request body holds whatever part of it has been read, and the rest is returned
+/// as a reader to be streamed from, framed as the returned Framing says.
+async fn read_request(request: http::Request<RecvStream>, max_buffered_body: usize, timeout: Duration)
+        -> Result<(http::Request<Vec<u8>>, Option<(Framing, BodyReader)>), ReadError> {
+    let (parts, mut body) = request.into_parts();
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    let mut builder = http::Request::builder()
+        .method(parts.method.clone())
+        .uri(path)
+        .version(http::Version::HTTP_11);
+    if !parts.headers.contains_key(http::header::HOST) {
+        if let Some(authority) = parts.uri.authority() {
+            builder = builder.header(http::header::HOST, authority.as_str());
+        }
+    }
+    // HTTP/2 clients may split cookies into separate header fields, which have to be put back
+    // together for HTTP/1.1 (RFC 7540 section 8.1.2.5)
+    let cookies: Vec<&[u8]> = parts
+        .headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .map(|value| value.as_bytes())
+        .collect();
+    if !cookies.is_empty() {
+        builder = builder.header(http::header::COOKIE, cookies.join(&b"; "[..]));
+    }
+    for (name, value) in parts.headers.iter() {
+        if name != http::header::COOKIE {
+            builder = builder.header(name, value);
+        }
+    }
+    let mut request = builder.body(Vec::new()).unwrap();
+    if body.is_end_stream() {
+        return Ok((request, None));
+    }
+
+    let content_length = request
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    let mut finished = false;
+    if content_length.is_none_or(|length| length <= max_buffered_body) {
+        while request.body().len() <= max_buffered_body {
+            match within(timeout, body.data()).await? {
+                Some(data) => {
+                    let data = data?;
+                    let _ = body.flow_control().release_capacity(data.len());
+                    request.body_mut().extend_from_slice(&data);
+                }
+                None => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    if finished {
+        match within(timeout, body.trailers()).await?? {
+            // Only a chunked body can carry trailers to the upstream
+            Some(trailers) => {
+                request.headers_mut().remove(http::header::CONTENT_LENGTH);
+                request.headers_mut().insert(
+                    http::header::TRANSFER_ENCODING,
+                    http::HeaderValue::from_static("chunked"),
+                );
+                request.extensions_mut().insert(Trailers(trailers));
+            }
+            None => {
+                let length = request.body().len();
+                request.headers_mut().insert(http::header::CONTENT_LENGTH, length.into());
+            }
+        }
+        return Ok((request, None));
+    }
+    match content_length {
+        Some(length) => Ok((request, Some((Framing::Length(length), BodyReader::new(body, false))))),
+        None => {
+            // Without a length, the body goes upstream chunked. What has been read so far goes
+            // out as the first chunk.
+            let mut first_chunk = Vec::new();
+            encode_chunk(request.body(), &mut first_chunk);
+            *request.body_mut() = first_chunk;
+            request.headers_mut().insert(
+                http::header::TRANSFER_ENCODING,
+                http::HeaderValue::from_static("chunked"),
+            );
+            Ok((request, Some((Framing::Chunked, BodyReader::new(body, true)))))
+        }
+    }
+}
+
+/// Appends one chunk of a chunked body to `buffer`.
+fn encode_chunk(data: &[u8], buffer: &mut Vec<u8>) {
+    if !data.is_empty() {
+        buffer.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+        buffer.extend_from_slice(data);
+        buffer.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Reads the rest of an HTTP/2 request body, optionally in the chunked transfer coding so that it
+/// can be sent upstream without knowing its length.
+struct BodyReader {
+    body: RecvStream,
+    chunked: bool,
+    /// Bytes received from the client but not yet read
+    pending: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl BodyReader {
+    fn new(body: RecvStream, chunked: bool) -> BodyReader {
+        BodyReader {
+            body,
+            chunked,
+            pending: Vec::new(),
+            pos: 0,
+            finished: false,
+        }
+    }
+}
+
+impl AsyncRead for BodyReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.pending.len() {
+                let len = buf.len().min(this.pending.len() - this.pos);
+                buf[..len].copy_from_slice(&this.pending[this.pos..this.pos + len]);
+                this.pos += len;
+                return Poll::Ready(Ok(len));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(0));
+            }
+            this.pending.clear();
+            this.pos = 0;
+            match this.body.poll_data(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(data))) => {
+                    let _ = this.body.flow_control().release_capacity(data.len());
+                    if this.chunked {
+                        encode_chunk(&data, &mut this.pending);
+                    } else {
+                        this.pending.extend_from_slice(&data);
+                    }
+                }
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(io::Error::other(error))),
+                Poll::Ready(None) => {
+                    if this.chunked {
+                        let trailers = match this.body.poll_trailers(cx) {
+                            Poll::Pending => return Poll::Pending,
+                            Poll::Ready(Ok(trailers)) => trailers.map(Trailers),
+                            Poll::Ready(Err(error)) => return Poll::Ready(Err(io::Error::other(error))),
+                        };
+                        crate::chunked::encode(&[], trailers.as_ref(), &mut this.pending);
+                    }
+                    this.finished = true;
+                }
+            }
+        }
+    }
+}
+
+/// Sends a response body on an HTTP/2 stream, waiting for the client to make room for it (flow
+/// control) so that a slow client slows down the upstream rather than making us buffer.
+struct BodyWriter {
+    stream: SendStream<Bytes>,
+}
+
+impl AsyncWrite for BodyWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let stream = &mut self.get_mut().stream;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        stream.reserve_capacity(buf.len());
+        loop {
+            let capacity = stream.capacity();
+            if capacity > 0 {
+                let len = capacity.min(buf.len());
+                stream
+                    .send_data(Bytes::copy_from_slice(&buf[..len]), false)
+                    .map_err(io::Error::other)?;
+                return Poll::Ready(Ok(len));
+            }
+            match stream.poll_capacity(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Err(io::Error::other(error))),
+                Poll::Ready(None) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Data is handed to the connection as soon as it is written
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The status and headers of a response from an upstream, without the headers that HTTP/2 does
+/// without.
+fn response_head(response: &http::Response<Vec<u8>>) -> http::Response<()> {
+    let mut head = http::Response::new(());
+    *head.status_mut() = response.status();
+    // Headers named in Connection only apply to the upstream connection, too
+    let named: Vec<String> = response
+        .headers()
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    for (name, value) in response.headers() {
+        if !CONNECTION_HEADERS.contains(&name.as_str()) && !named.iter().any(|n| n == name.as_str()) {
+            head.headers_mut().append(name, value.clone());
+        }
+    }
+    head
+}
+
+fn send_response(respond: &mut SendResponse<Bytes>, client_ip: &str,
+        response: &http::Response<Vec<u8>>) {
+    log::info!("{} <- {}", client_ip, response::format_response_line(response));
+    let end_of_stream = response.body().is_empty();
+    let result = respond
+        .send_response(response_head(response), end_of_stream)
+        .and_then(|mut stream| {
+            if end_of_stream {
+                return Ok(());
+            }
+            stream.send_data(Bytes::copy_from_slice(response.body()), true)
+        });
+    if let Err(error) = result {
+        log::warn!("Failed to send response to client: {}", error);
+    }
+}
+
+/// Sends a response on an HTTP/2 stream, copying its body across from the upstream connection as
+/// it arrives. Returns the number of body bytes sent.
+async fn stream_response(state: &ProxyState, respond: &mut SendResponse<Bytes>, client_ip: &str,
+        response: &http::Response<Vec<u8>>, mut streaming_body: StreamingBody) -> u64 {
+    log::info!("{} <- {}", client_ip, response::format_response_line(response));
+    let stream = match respond.send_response(response_head(response), false) {
+        Ok(stream) => stream,
+        Err(error) => {
+            log::warn!("Failed to send response to client: {}", error);
+            return 0;
+        }
+    };
+    let mut writer = BodyWriter { stream };
+    let upstream_ip = &state.upstream_addresses[streaming_body.upstream_conn.idx];
+    let mut upstream_stream = ReadTimeout::new(&mut streaming_body.upstream_conn.stream, state.timeouts.upstream_read);
+    let copied =
+        body::copy_decoded(streaming_body.framing, response.body(), &mut upstream_stream, &mut writer).await;
+    let (bytes, finished) = match copied {
+        Ok((bytes, trailers)) => {
+            log::debug!("Streamed {} byte response body to client", bytes);
+            state.metrics.record_response_bytes(upstream_ip, bytes);
+            let finished = match trailers {
+                Some(Trailers(trailers)) if !trailers.is_empty() => writer.stream.send_trailers(trailers),
+                _ => writer.stream.send_data(Bytes::new(), true),
+            };
+            (bytes, finished)
+        }
+        Err(error) => {
+            log::warn!("Failed to stream response body to client: {}", error);
+            writer.stream.send_reset(Reason::INTERNAL_ERROR);
+            return 0;
+        }
+    };
+    if let Err(error) = finished {
+        log::warn!("Failed to finish response to client: {}", error);
+    }
+    if streaming_body.reusable {
+        state.pool.check_in(streaming_body.upstream_conn);
+    }
+    bytes
+}
@@ -1,27 +1,95 @@
+mod access_log;
+mod admin;
+mod body;
+mod cache;
+mod chunked;
+mod circuit_breaker;
+mod config;
+mod headers;
+mod health_check;
+mod http2;
+mod metrics;
+mod pool;
+mod rate_limiter;
 mod request;
 mod response;
+mod retry;
+mod routing;
+mod shutdown;
+mod strategy;
+mod timeout;
+mod tls;
+mod tunnel;
+mod upstream;
 
 #[macro_use]
 extern crate error_chain;
 
 use clap::Clap;
-use rand::{Rng, SeedableRng};
-use tokio::{net::TcpListener, net::TcpStream, stream::StreamExt, sync::RwLock, time};
+use tokio::{net::TcpListener, stream::StreamExt, sync::RwLock};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::collections::HashMap;
 use std::sync::Arc;
-use rand::{thread_rng, seq::SliceRandom};
-use std::time::Duration;
-use tokio::time::{Interval, Instant};
+use std::sync::atomic::AtomicUsize;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
-use tokio::sync::broadcast::{channel,Sender, Receiver, RecvError};
-use std::collections::HashMap;
+use access_log::{AccessLog, RequestTimer};
+use tokio::signal::unix::{signal, SignalKind};
+use strategy::ActiveConnection;
+use pool::{Pool, PoolSettings, PooledConnection};
+use body::Framing;
+use cache::{Cache, Lookup};
+use circuit_breaker::{BreakerSettings, CircuitBreakers};
+use headers::HeaderRewrites;
+use metrics::Metrics;
+use rate_limiter::{Decision, KeySource, Policy, RateLimit};
+use retry::{RetryBudget, RetrySettings};
+use routing::{Route, UpstreamPool};
+use shutdown::{Drain, Shutdown};
+use timeout::{ReadTimeout, Timeouts};
+use upstream::{Endpoint, UpstreamTls};
 
-error_chain! {}
+error_chain! {
+    errors {
+        /// Every upstream is unhealthy, drained or ejected, or refused to connect
+        NoUpstreamAvailable {
+            description("no upstream available")
+            display("All upstreams are dead.")
+        }
+        /// No route matches the request, and there are no top-level upstreams to fall back on
+        NoRoute {
+            description("no route")
+            display("No route matches the request.")
+        }
+        /// The client stopped sending the request body partway through
+        ClientTimeout {
+            description("client timed out")
+            display("Timed out waiting for the client to send the request.")
+        }
+        /// The client hung up, or sent a malformed body, partway through the request body
+        ClientAborted {
+            description("client aborted")
+            display("The client stopped sending the request partway through.")
+        }
+        /// No upstream could be connected to in time, or the upstream didn't respond in time
+        UpstreamTimeout {
+            description("upstream timed out")
+            display("Timed out waiting for the upstream.")
+        }
+    }
+}
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
-#[derive(Clap, Debug)]
+#[derive(Clap, Debug, Clone)]
 #[clap(about = "Fun with load balancing")]
 struct CmdOptions {
+    #[clap(
+        long,
+        about = "TOML or YAML file to read settings from, overriding the command line. Send \
+        SIGHUP to reload it"
+    )]
+    config: Option<String>,
     #[clap(
         short,
         long,
@@ -29,8 +97,50 @@ struct CmdOptions {
         default_value = "0.0.0.0:1100"
     )]
     bind: String,
-    #[clap(short, long, about = "Upstream host to forward requests to")]
+    #[clap(
+        long,
+        about = "What to balance: http (requests) or tcp (connections, relayed byte for byte)",
+        default_value = "http"
+    )]
+    mode: String,
+    #[clap(
+        short,
+        long,
+        about = "Upstream host to forward requests to, optionally weighted as host:port=weight. \
+        Prefix it with https:// to connect over TLS"
+    )]
     upstream: Vec<String>,
+    /// Server names to verify `https://` upstreams against, keyed by upstream address, which can
+    /// only be set in the config file
+    #[clap(skip)]
+    upstream_server_names: HashMap<String, String>,
+    #[clap(
+        long,
+        about = "PEM CA bundle to verify https:// upstreams with (the system's roots if not set)"
+    )]
+    upstream_ca: Option<String>,
+    #[clap(
+        long,
+        about = "PEM client certificate to present to https:// upstreams that ask for one"
+    )]
+    upstream_client_cert: Option<String>,
+    #[clap(
+        long,
+        about = "PEM private key (PKCS#8) for --upstream-client-cert"
+    )]
+    upstream_client_key: Option<String>,
+    #[clap(
+        long,
+        about = "Load balancing strategy: random, round-robin, least-connections, weighted or \
+        consistent-hash",
+        default_value = "random"
+    )]
+    strategy: String,
+    #[clap(
+        long,
+        about = "Header to hash on for the consistent-hash strategy (defaults to the client IP)"
+    )]
+    hash_header: Option<String>,
     #[clap(
         long,
         about = "Perform active health checks on this interval (in seconds)",
@@ -45,10 +155,293 @@ struct CmdOptions {
     active_health_check_path: String,
     #[clap(
         long,
-        about = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
+        about = "HTTP method to send active health checks with",
+        default_value = "GET"
+    )]
+    active_health_check_method: String,
+    #[clap(
+        long,
+        about = "Header to send with active health checks, as \"Name: value\" (may be repeated)"
+    )]
+    active_health_check_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Status codes that pass an active health check, e.g. 200-299,304",
+        default_value = "200"
+    )]
+    active_health_check_status: String,
+    #[clap(
+        long,
+        about = "Text the response body has to contain to pass an active health check"
+    )]
+    active_health_check_body: Option<String>,
+    #[clap(
+        long,
+        about = "Regular expression the response body has to match to pass an active health check"
+    )]
+    active_health_check_body_regex: Option<String>,
+    #[clap(
+        long,
+        about = "Fail an active health check that gets no response within this long (in seconds)",
+        default_value = "5"
+    )]
+    active_health_check_timeout: u64,
+    #[clap(
+        long,
+        about = "Active health checks that must pass in a row to mark an unhealthy upstream healthy",
+        default_value = "1"
+    )]
+    active_health_check_rise: usize,
+    #[clap(
+        long,
+        about = "Active health checks that must fail in a row to mark a healthy upstream unhealthy",
+        default_value = "1"
+    )]
+    active_health_check_fall: usize,
+    #[clap(
+        long,
+        about = "Eject an upstream after this many requests to it fail in a row, with a connection \
+        error or a 5xx response (0 = never)",
+        default_value = "5"
+    )]
+    passive_failure_threshold: usize,
+    #[clap(
+        long,
+        about = "How long an upstream is ejected for the first time (in seconds). Each further \
+        ejection before it recovers doubles this",
+        default_value = "30"
+    )]
+    ejection_time: u64,
+    #[clap(
+        long,
+        about = "The longest an upstream is ever ejected for (in seconds)",
+        default_value = "300"
+    )]
+    max_ejection_time: u64,
+    #[clap(
+        long,
+        about = "Trial requests that must succeed before an ejected upstream is let back in",
+        default_value = "1"
+    )]
+    half_open_requests: usize,
+    #[clap(
+        long,
+        about = "Retry a failed request on another upstream up to this many times. Requests that \
+        couldn't be sent are always retried; others only if their method is idempotent",
+        default_value = "2"
+    )]
+    max_retries: usize,
+    #[clap(
+        long,
+        about = "Wait up to this long (in milliseconds) before the first retry, doubling with \
+        every retry after that",
+        default_value = "25"
+    )]
+    retry_backoff: u64,
+    #[clap(
+        long,
+        about = "Never wait longer than this (in milliseconds) before a retry",
+        default_value = "1000"
+    )]
+    retry_max_backoff: u64,
+    #[clap(
+        long,
+        about = "Retries may add at most this percentage to the requests of the last 10 seconds",
+        default_value = "20"
+    )]
+    retry_budget: usize,
+    #[clap(
+        long,
+        about = "Retries allowed per second on top of --retry-budget",
+        default_value = "10"
+    )]
+    retry_budget_min: usize,
+    #[clap(
+        long,
+        about = "Maximum number of requests to accept from each client per minute (0 = unlimited)",
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        about = "Rate limiting algorithm: fixed-window, sliding-window-log, sliding-window-counter \
+        or token-bucket",
+        default_value = "fixed-window"
+    )]
+    rate_limit_algorithm: String,
+    #[clap(
+        long,
+        about = "Length of the rate limiting window in seconds; --max-requests-per-minute applies \
+        per window",
+        default_value = "60"
+    )]
+    rate_limit_window: u64,
+    #[clap(
+        long,
+        about = "What clients are told apart by for rate limiting: ip, path-prefix (all requests \
+        share one limit) or header:<name> (e.g. an API key, falling back to the IP)",
+        default_value = "ip"
+    )]
+    rate_limit_key: String,
+    /// Per-route rate limits, which can only be set in the config file
+    #[clap(skip)]
+    rate_limit_rules: Vec<config::RateLimitRule>,
+    /// Named groups of upstreams, which can only be set in the config file
+    #[clap(skip)]
+    upstream_pools: Vec<config::UpstreamPoolConfig>,
+    /// Rules sending requests to upstream pools, which can only be set in the config file
+    #[clap(skip)]
+    routes: Vec<config::Route>,
+    #[clap(
+        long,
+        about = "Maximum number of idle connections to keep open to each upstream (0 = no pooling)",
+        default_value = "8"
+    )]
+    pool_max_idle: usize,
+    #[clap(
+        long,
+        about = "Maximum number of connections to open to each upstream (0 = unlimited)",
+        default_value = "0"
+    )]
+    pool_max_per_upstream: usize,
+    #[clap(
+        long,
+        about = "Close pooled upstream connections that have been idle this long (in seconds)",
+        default_value = "60"
+    )]
+    pool_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "Give up on connecting to an upstream after this long (in seconds) and try another",
+        default_value = "5"
+    )]
+    connect_timeout: u64,
+    #[clap(
+        long,
+        about = "Give up on an upstream that takes longer than this (in seconds) to start its \
+        response, or to send more of it, and answer 504",
+        default_value = "60"
+    )]
+    upstream_read_timeout: u64,
+    #[clap(
+        long,
+        about = "Answer 408 to a client that takes longer than this (in seconds) to send the \
+        request line and headers",
+        default_value = "10"
+    )]
+    client_header_timeout: u64,
+    #[clap(
+        long,
+        about = "Answer 408 to a client that sends nothing more of a request body for this long \
+        (in seconds)",
+        default_value = "30"
+    )]
+    client_body_timeout: u64,
+    #[clap(
+        long,
+        about = "Close a client connection that sits idle between requests for this long (in \
+        seconds)",
+        default_value = "60"
+    )]
+    keep_alive_timeout: u64,
+    #[clap(
+        long,
+        about = "Bodies bigger than this many bytes are streamed through instead of being buffered",
+        default_value = "1048576"
+    )]
+    max_buffered_body: usize,
+    #[clap(
+        long,
+        about = "Close an upgraded connection (e.g. a WebSocket), or a connection in tcp mode, once \
+        nothing has been sent either way for this long (in seconds)",
+        default_value = "300"
+    )]
+    tunnel_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "On SIGTERM or SIGINT, stop accepting connections and give requests in flight this \
+        long (in seconds) to finish before exiting",
+        default_value = "30"
+    )]
+    drain_timeout: u64,
+    #[clap(
+        long,
+        about = "Keep up to this many bytes of upstream responses in memory, and answer GET and \
+        HEAD requests from them while they are fresh (0 = no caching)",
+        default_value = "0"
+    )]
+    cache_size: usize,
+    #[clap(
+        long,
+        about = "Never cache a response bigger than this many bytes",
+        default_value = "1048576"
+    )]
+    cache_max_entry_size: usize,
+    #[clap(
+        long,
+        about = "Headers telling upstreams about the client: x-forwarded (X-Forwarded-For, \
+        -Proto and -Host), forwarded (RFC 7239), both or none",
+        default_value = "x-forwarded"
+    )]
+    forwarded_headers: String,
+    #[clap(
+        long,
+        about = "Add balancebeam to the Via header of requests and responses"
+    )]
+    via: bool,
+    #[clap(
+        long,
+        about = "Give requests without an X-Request-Id header a random one, and send it back on \
+        the response"
+    )]
+    request_id: bool,
+    /// Changes to make to request headers, which can only be set in the config file
+    #[clap(skip)]
+    request_header_rules: Vec<config::HeaderRule>,
+    /// Changes to make to response headers, which can only be set in the config file
+    #[clap(skip)]
+    response_header_rules: Vec<config::HeaderRule>,
+    #[clap(
+        long,
+        about = "File to write a record of every request to, or - for stdout (disabled if not set)"
+    )]
+    access_log: Option<String>,
+    #[clap(
+        long,
+        about = "Access log format: common, combined or json (one object per line)",
+        default_value = "combined"
+    )]
+    access_log_format: String,
+    #[clap(
+        long,
+        about = "Rotate the access log once it would grow past this many bytes (0 = never)",
+        default_value = "104857600"
+    )]
+    access_log_max_size: u64,
+    #[clap(
+        long,
+        about = "Number of rotated access logs to keep, as <file>.1 (the newest) to <file>.N",
+        default_value = "5"
+    )]
+    access_log_max_files: usize,
+    #[clap(
+        long,
+        about = "IP/port to serve the admin API on (disabled if not set)"
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        about = "PEM certificate chain to serve HTTPS with (plain HTTP if not set)"
+    )]
+    tls_cert: Option<String>,
+    #[clap(
+        long,
+        about = "PEM private key (PKCS#8) for --tls-cert"
+    )]
+    tls_key: Option<String>,
+    /// Certificates for particular server names, which can only be set in the config file
+    #[clap(skip)]
+    tls_sni: Vec<config::SniCertificate>,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -57,27 +450,327 @@ struct CmdOptions {
 /// You should add fields to this struct in later milestones.
 #[derive(Debug, Clone)]
 struct ProxyState {
-    /// How frequently we check whether upstream servers are alive (Milestone 4)
-    active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
+    /// Whether we balance HTTP requests or TCP connections
+    mode: Mode,
+    /// Groups of upstreams, each with its own strategy and active health checks. The first is
+    /// the default pool, made up of the top-level upstreams.
+    pools: Vec<UpstreamPool>,
+    /// Decide which pool each request goes to
+    routes: Vec<Route>,
+    /// Number of requests currently in flight to each upstream, indexed like upstream_addresses
+    active_connections: Arc<Vec<AtomicUsize>>,
+    /// Idle keep-alive connections to each upstream, indexed like upstream_addresses
+    pool: Arc<Pool>,
+    /// When upstreams are ejected because requests to them fail (passive health checks)
+    circuit_breaker: BreakerSettings,
+    /// When failed requests are retried
+    retry: RetrySettings,
+    /// Rate limits, each covering the requests under a path prefix (Milestone 5). The limit from
+    /// --max-requests-per-minute covers "/".
+    rate_limit_rules: Vec<rate_limiter::Rule>,
+    /// Addresses of servers that we are proxying to, across all pools
     upstream_addresses: Vec<String>,
+    /// How to connect to each upstream, indexed like upstream_addresses
+    upstream_endpoints: Vec<Endpoint>,
+    /// How `https://` upstreams are verified, kept for upstreams added through the admin API
+    upstream_tls: UpstreamTls,
+    /// Weight of each upstream, indexed like upstream_addresses
+    upstream_weights: Vec<usize>,
+    /// How request and response headers are rewritten
+    headers: HeaderRewrites,
+    /// Where a record of every request is written, if anywhere
+    access_log: Option<Arc<AccessLog>>,
+    /// Responses kept to answer later requests with, if caching is on. A reload starts with an
+    /// empty cache.
+    cache: Option<Arc<Cache>>,
+    /// How long to wait on slow clients and upstreams
+    timeouts: Timeouts,
+    /// Request and response bodies bigger than this are streamed instead of buffered
+    max_buffered_body: usize,
+    /// How long an upgraded connection may sit idle before it is closed
+    tunnel_idle_timeout: Duration,
+    /// How long to wait for connections to finish when shutting down
+    drain_timeout: Duration,
+    /// Certificates to terminate TLS with, if the listener serves HTTPS
+    tls: Option<Arc<tls::Tls>>,
+    /// Counters exported on the admin API's /metrics endpoint. These are carried over whenever
+    /// the state is rebuilt.
+    metrics: Arc<Metrics>,
 }
 
-type Report = Vec<String>;
+/// What balancebeam balances.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// HTTP requests, each of which may go to a different upstream
+    Http,
+    /// TCP connections, each relayed to one upstream as they are
+    Tcp,
+}
 
-#[derive(Debug)]
-struct ReportState {
-    content: Report
+impl Mode {
+    fn parse(mode: &str) -> Result<Mode> {
+        match mode {
+            "http" => Ok(Mode::Http),
+            "tcp" => Ok(Mode::Tcp),
+            _ => Err(format!("Unknown mode {:?} (expected http or tcp)", mode).into()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Http => "http",
+            Mode::Tcp => "tcp",
+        }
+    }
+}
+
+/// A connection from a client: either a plain TCP stream or one wrapped in TLS.
+trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for T {}
+
+/// A response body that has not been read from the upstream yet and has to be streamed to the
+/// client. After a 101 Switching Protocols response, this holds the upstream connection to tunnel
+/// to instead.
+struct StreamingBody {
+    upstream_conn: PooledConnection,
+    framing: Framing,
+    /// Whether the upstream connection can go back into the pool once the body has been copied
+    reusable: bool,
+    /// Keeps the request counted as in flight until the body has been copied
+    _active: ActiveConnection,
+}
+
+/// The response to a forwarded request.
+struct Forwarded {
+    response: http::Response<Vec<u8>>,
+    streaming_body: Option<StreamingBody>,
+    /// Index of the upstream that answered and how long it took to send the response head, unless
+    /// the response came out of the cache
+    upstream: Option<(usize, Duration)>,
+}
+
+impl ProxyState {
+    /// Builds the proxy state described by a set of options. A fresh state is built every time the
+    /// configuration is reloaded, and then takes over the connections of the one it replaces
+    /// (see next_pool).
+    fn from_options(options: &CmdOptions, metrics: Arc<Metrics>) -> Result<ProxyState> {
+        let upstream_tls = UpstreamTls::new(
+            options.upstream_ca.as_deref(),
+            options.upstream_client_cert.as_deref(),
+            options.upstream_client_key.as_deref(),
+        )?;
+        // The upstreams of every pool go into one list, the top-level upstreams first. Each
+        // upstream can only be in one pool.
+        let mut pool_upstreams = vec![Vec::new()];
+        for upstream in options.upstream.iter() {
+            let (address, weight) = strategy::parse_upstream(upstream)?;
+            let server_name = options.upstream_server_names.get(&address).cloned();
+            pool_upstreams[0].push((address, weight, server_name));
+        }
+        for pool in options.upstream_pools.iter() {
+            pool_upstreams.push(pool
+                .upstreams
+                .iter()
+                .map(|upstream| upstream.clone().into_parts())
+                .collect::<std::result::Result<Vec<_>, _>>()?);
+        }
+        let mut upstream_addresses = Vec::new();
+        let mut upstream_endpoints = Vec::new();
+        let mut weights = Vec::new();
+        let mut pool_members = Vec::new();
+        for upstreams in pool_upstreams {
+            let mut members = Vec::new();
+            for (address, weight, server_name) in upstreams {
+                let endpoint = Endpoint::parse(&address, server_name.as_deref(), &upstream_tls)?;
+                if upstream_addresses.contains(&endpoint.address) {
+                    return Err(format!("Upstream {} is listed more than once", endpoint.address).into());
+                }
+                members.push(upstream_addresses.len());
+                upstream_addresses.push(endpoint.address.clone());
+                upstream_endpoints.push(endpoint);
+                weights.push(weight);
+            }
+            pool_members.push(members);
+        }
+        let mode = Mode::parse(&options.mode)?;
+        let mut pool_members = pool_members.into_iter();
+        let mut pools = vec![UpstreamPool::new(routing::DEFAULT_POOL, pool_members.next().unwrap(),
+            &weights, options, mode == Mode::Tcp)?];
+        for (pool, members) in options.upstream_pools.iter().zip(pool_members) {
+            if pools.iter().any(|existing| existing.name == pool.name) {
+                return Err(format!("There is more than one upstream pool named {:?}", pool.name).into());
+            }
+            let mut pool_options = options.clone();
+            pool.apply(&mut pool_options);
+            pools.push(UpstreamPool::new(&pool.name, members, &weights, &pool_options, mode == Mode::Tcp)?);
+        }
+        let routes = options
+            .routes
+            .iter()
+            .map(|route| Route::parse(route, &pools))
+            .collect::<std::result::Result<_, _>>()?;
+        let algorithm = rate_limiter::Algorithm::parse(&options.rate_limit_algorithm)?;
+        let window = Duration::from_secs(options.rate_limit_window.max(1));
+        let key = KeySource::parse(&options.rate_limit_key)?;
+        let mut rate_limit_rules = Vec::new();
+        for rule in options.rate_limit_rules.iter() {
+            rate_limit_rules.push(rate_limiter::Rule {
+                path_prefix: rule.path_prefix.clone(),
+                key: match &rule.key {
+                    Some(key) => KeySource::parse(key)?,
+                    None => key.clone(),
+                },
+                policy: Policy {
+                    algorithm: match &rule.algorithm {
+                        Some(algorithm) => rate_limiter::Algorithm::parse(algorithm)?,
+                        None => algorithm,
+                    },
+                    limit: rule.limit,
+                    window: rule.window.map_or(window, |window| Duration::from_secs(window.max(1))),
+                },
+            });
+        }
+        if options.max_requests_per_minute > 0 {
+            rate_limit_rules.push(rate_limiter::Rule {
+                path_prefix: "/".to_string(),
+                key,
+                policy: Policy {
+                    algorithm,
+                    limit: options.max_requests_per_minute,
+                    window,
+                },
+            });
+        }
+        let tls = match (&options.tls_cert, &options.tls_key) {
+            (Some(cert), Some(key)) => {
+                let server_names: Vec<(String, String, String)> = options
+                    .tls_sni
+                    .iter()
+                    .map(|sni| (sni.server_name.clone(), sni.cert.clone(), sni.key.clone()))
+                    .collect();
+                Some(Arc::new(tls::Tls::load(cert, key, &server_names)?))
+            }
+            (None, None) if options.tls_sni.is_empty() => None,
+            (None, None) => return Err("SNI certificates need a default certificate, set with \
+                --tls-cert and --tls-key".into()),
+            _ => return Err("--tls-cert and --tls-key have to be given together".into()),
+        };
+        let pool_settings = PoolSettings {
+            max_idle: options.pool_max_idle,
+            max_per_upstream: options.pool_max_per_upstream,
+            idle_timeout: Duration::from_secs(options.pool_idle_timeout),
+            connect_timeout: Duration::from_secs(options.connect_timeout.max(1)),
+        };
+        Ok(ProxyState {
+            mode,
+            pools,
+            routes,
+            active_connections: Arc::new(upstream_addresses.iter().map(|_| AtomicUsize::new(0)).collect()),
+            pool: Arc::new(Pool::new(pool_settings, upstream_addresses.len())),
+            upstream_addresses,
+            upstream_endpoints,
+            upstream_tls,
+            upstream_weights: weights,
+            circuit_breaker: BreakerSettings {
+                failure_threshold: options.passive_failure_threshold,
+                ejection_time: Duration::from_secs(options.ejection_time.max(1)),
+                max_ejection_time: Duration::from_secs(options.max_ejection_time.max(options.ejection_time).max(1)),
+                half_open_requests: options.half_open_requests.max(1),
+            },
+            retry: RetrySettings::from_options(options),
+            rate_limit_rules,
+            headers: HeaderRewrites::from_options(options)?,
+            access_log: AccessLog::from_options(options)?.map(Arc::new),
+            cache: Cache::from_options(options, Arc::clone(&metrics)).map(Arc::new),
+            timeouts: Timeouts::from_options(options),
+            max_buffered_body: options.max_buffered_body,
+            tunnel_idle_timeout: Duration::from_secs(options.tunnel_idle_timeout.max(1)),
+            drain_timeout: Duration::from_secs(options.drain_timeout),
+            tls,
+            metrics,
+        })
+    }
+
+    /// Returns a copy of this state that proxies to a different set of (endpoint, weight)
+    /// upstreams. Upstreams stay in the pools they were in, and new ones join the default pool.
+    /// The strategies, counters and connection pool are rebuilt, since they are indexed by
+    /// upstream.
+    fn with_upstreams(&self, upstreams: Vec<(Endpoint, usize)>) -> Result<ProxyState> {
+        let (upstream_endpoints, upstream_weights): (Vec<Endpoint>, Vec<usize>) =
+            upstreams.into_iter().unzip();
+        let upstream_addresses: Vec<String> =
+            upstream_endpoints.iter().map(|endpoint| endpoint.address.clone()).collect();
+        let mut pools = Vec::new();
+        for (pool_idx, pool) in self.pools.iter().enumerate() {
+            let members = upstream_addresses
+                .iter()
+                .enumerate()
+                .filter(|(_, address)| {
+                    match self.upstream_addresses.iter().position(|old| old == *address) {
+                        Some(old_idx) => pool.members.contains(&old_idx),
+                        None => pool_idx == 0,
+                    }
+                })
+                .map(|(idx, _)| idx)
+                .collect();
+            pools.push(pool.with_members(members, &upstream_weights)?);
+        }
+        Ok(ProxyState {
+            pools,
+            active_connections: Arc::new(upstream_addresses.iter().map(|_| AtomicUsize::new(0)).collect()),
+            pool: Arc::new(self.next_pool(&upstream_endpoints, self.pool.settings().clone())),
+            upstream_addresses,
+            upstream_endpoints,
+            upstream_weights,
+            ..self.clone()
+        })
+    }
+
+    /// Builds the connection pool for a state that replaces this one and proxies to
+    /// `upstream_endpoints`. Upstreams that this state proxies to as well keep their idle
+    /// connections, and the connections they have open (which requests against this state may
+    /// still be using) go on counting against max_per_upstream.
+    fn next_pool(&self, upstream_endpoints: &[Endpoint], settings: PoolSettings) -> Pool {
+        let previous: Vec<Option<usize>> = upstream_endpoints
+            .iter()
+            .map(|endpoint| {
+                self.upstream_endpoints
+                    .iter()
+                    .position(|old| old.same_destination(endpoint))
+            })
+            .collect();
+        self.pool.rebuild(settings, &previous)
+    }
+
+    /// Returns the index of the pool a request goes to: the pool of the first route it matches,
+    /// or else the default pool. If there are routes but no top-level upstreams, requests that
+    /// match no route go nowhere. Connections in tcp mode always go to the default pool.
+    fn route(&self, request: Option<&http::Request<Vec<u8>>>) -> Option<usize> {
+        match request.and_then(|request| routing::select(&self.routes, request)) {
+            Some(pool) => Some(pool),
+            None if request.is_some() && !self.routes.is_empty() && self.pools[0].members.is_empty() => None,
+            None => Some(0),
+        }
+    }
 }
 
+/// The ProxyState currently in effect. Reloading the configuration swaps in a new one, while
+/// requests already in flight hold on to the one they started with.
+type SharedState = Arc<parking_lot::RwLock<Arc<ProxyState>>>;
+
+type Report = Vec<String>;
+
 #[derive(Debug)]
-struct RateLimit {
-    map: HashMap<String, usize>
+struct ReportState {
+    /// Upstreams that failed their last active health check
+    content: Report,
+    /// Upstreams taken out of rotation through the admin API
+    drained: Report,
+    /// Upstreams ejected because requests to them have been failing (passive health checks)
+    circuits: CircuitBreakers,
+    /// Recent requests and retries, which limit how many more retries can be made
+    retry_budget: RetryBudget,
 }
 
 #[tokio::main]
@@ -85,18 +778,28 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     
     pretty_env_logger::init();
 
     // Parse the command line arguments passed to this program
-    let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
-    }
+    let cmd_options = CmdOptions::parse();
+    let options = match load_options(&cmd_options) {
+        Ok(options) => options,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let state = match ProxyState::from_options(&options, Arc::new(Metrics::default())) {
+        Ok(state) => state,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
     // Start listening for connections
     let mut listener = match TcpListener::bind(&options.bind).await {
@@ -106,58 +809,166 @@ async fn main() {
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+    // With port 0, the OS picks the port, so say which one it picked
+    let bound = |listener: &TcpListener, requested: &str| {
+        listener.local_addr().map_or_else(|_| requested.to_string(), |addr| addr.to_string())
+    };
+    log::info!("Listening for requests on {}", bound(&listener, &options.bind));
 
-    // Handle incoming connections
-    let state = ProxyState {
-        upstream_addresses: options.upstream,
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
+    let admin_listener = match &options.admin_bind {
+        Some(admin_bind) => match TcpListener::bind(admin_bind).await {
+            Ok(listener) => {
+                log::info!("Serving the admin API on {}", bound(&listener, admin_bind));
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
     };
 
+    // Handle incoming connections
     log::info!("ProxyState settings = {:?}", state);
+    let state: SharedState = Arc::new(parking_lot::RwLock::new(Arc::new(state)));
     //runtime setup
     let runtime = Runtime::new().expect("failed to start new Runtime");
 
     //create report_state
-    let report_state = Arc::new(RwLock::new(ReportState{ content: vec![] }));
+    let report_state = Arc::new(RwLock::new(ReportState {
+        content: vec![],
+        drained: vec![],
+        circuits: CircuitBreakers::default(),
+        retry_budget: RetryBudget::default(),
+    }));
 
     //health check
-    let clone_state = state.clone();
+    let clone_state = Arc::clone(&state);
     let report_state_clone = Arc::clone(&report_state);
-    let health_check_handle = runtime.spawn(async move {
-        health_check(&clone_state, report_state_clone).await;
+    runtime.spawn(async move {
+        health_check(clone_state, report_state_clone).await;
+    });
+
+    //evict pooled connections that have sat idle for too long
+    let clone_state = Arc::clone(&state);
+    runtime.spawn(async move {
+        loop {
+            let pool = Arc::clone(&current_state(&clone_state).pool);
+            tokio::time::delay_for(pool.idle_timeout().max(Duration::from_secs(1))).await;
+            pool.evict_expired();
+        }
+    });
+
+    //reload the config file on SIGHUP
+    let clone_state = Arc::clone(&state);
+    let bind = options.bind.clone();
+    runtime.spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                log::error!("Could not listen for SIGHUP: {}", err);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            reload(&cmd_options, &bind, &clone_state);
+        }
     });
 
     //rate limit count
-    let rate_limit_count = Arc::new(RwLock::new(RateLimit{map: HashMap::new()})); 
+    let rate_limit_count = Arc::new(RwLock::new(RateLimit::default()));
     let limit  = Arc::clone(&rate_limit_count);
 
-    if state.max_requests_per_minute > 0 {
-        runtime.spawn(async move {
-            let duration = Duration::from_secs(60);
-            loop {
-                tokio::time::delay_for(duration).await;
-                log::info!("rate limit clock ticking.");
-                {
-                    let mut limit = limit.write().await;
-                    limit.map = HashMap::new();
-                    log::info!("rate limit map reset = {:?}", limit.map);
-                }
-            }
-        });
+    // Every key expires on its own schedule; this only forgets keys that have expired, so that
+    // the map doesn't keep growing
+    let clone_state = Arc::clone(&state);
+    runtime.spawn(async move {
+        loop {
+            let window = current_state(&clone_state)
+                .rate_limit_rules
+                .iter()
+                .map(|rule| rule.policy.window)
+                .min()
+                .unwrap_or_else(|| Duration::from_secs(60));
+            tokio::time::delay_for(window).await;
+            limit.write().await.evict_expired(Instant::now());
+        }
+    });
+
+    //admin API
+    if let Some(admin_listener) = admin_listener {
+        let admin = admin::Admin {
+            shared_state: Arc::clone(&state),
+            report_state: Arc::clone(&report_state),
+            rate_limit_count: Arc::clone(&rate_limit_count),
+        };
+        runtime.spawn(admin::serve(admin_listener, admin));
     }
 
-    while let Some(stream) = listener.next().await {
+    // Stop accepting connections on SIGTERM or SIGINT, and let the ones that are open finish
+    let (mut terminations, mut interrupts) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(terminations), Ok(interrupts)) => (terminations, interrupts),
+        (Err(err), _) | (_, Err(err)) => {
+            log::error!("Could not listen for SIGTERM and SIGINT: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let drain = Drain::new();
+
+    loop {
+        let stream = tokio::select! {
+            stream = listener.next() => match stream {
+                Some(stream) => stream,
+                None => break,
+            },
+            _ = terminations.recv() => {
+                log::info!("Received SIGTERM");
+                break;
+            }
+            _ = interrupts.recv() => {
+                log::info!("Received SIGINT");
+                break;
+            }
+        };
         match stream {
             Ok(stream) => {
-                let state_clone = state.clone();
+                let state_clone = Arc::clone(&state);
                 let report_state_clone = Arc::clone(&report_state);
                 let rate_limit_count_clone = Arc::clone(&rate_limit_count);
+                let mut shutdown = drain.handle();
 
                 runtime.spawn(async move {
-                    handle_connection(stream, &state_clone, report_state_clone, rate_limit_count_clone).await;
+                    let client_ip = match stream.peer_addr() {
+                        Ok(addr) => addr.ip().to_string(),
+                        Err(_) => return,
+                    };
+                    log::info!("Connection received from {}", client_ip);
+                    // Whether to expect TLS is decided per connection, so that a reload can
+                    // change certificates
+                    let (tls, timeouts) = {
+                        let state = current_state(&state_clone);
+                        (state.tls.clone(), state.timeouts)
+                    };
+                    match tls {
+                        None => serve_client(stream, client_ip, "http", &state_clone,
+                            report_state_clone, rate_limit_count_clone, shutdown).await,
+                        // The handshake has to be over in the time the client has to send its
+                        // request headers, and is abandoned if we start shutting down first
+                        Some(tls) => {
+                            let handshake = tokio::time::timeout(timeouts.client_header, tls.accept(stream));
+                            let accepted = tokio::select! {
+                                accepted = handshake => accepted,
+                                _ = shutdown.draining() => return,
+                            };
+                            match accepted {
+                                Ok(Ok(stream)) => serve_client(stream, client_ip, "https", &state_clone,
+                                    report_state_clone, rate_limit_count_clone, shutdown).await,
+                                Ok(Err(error)) => log::info!("{} from {}", error, client_ip),
+                                Err(_) => log::info!("Timed out waiting for TLS handshake from {}", client_ip),
+                            }
+                        }
+                    }
                 });
             }
             Err(e) => {
@@ -166,227 +977,860 @@ async fn main() {
             }
         }
     }
-    
+
+    // Closing the listener turns new connections away while the open ones drain
+    drop(listener);
+    let drain_timeout = current_state(&state).drain_timeout;
+    log::info!("Shutting down; waiting up to {:?} for open connections to finish", drain_timeout);
+    if drain.drain(drain_timeout).await {
+        log::info!("All connections finished");
+    } else {
+        log::warn!("Closing the connections that are still open");
+    }
     runtime.shutdown_background();
     log::info!("shut down.");
 }
 
-async fn get_report(report_state: &Arc<RwLock<ReportState>>) -> Report {
+/// Combines the command-line options with the config file, if one was given.
+fn load_options(cmd_options: &CmdOptions) -> Result<CmdOptions> {
+    let mut options = cmd_options.clone();
+    if let Some(path) = &cmd_options.config {
+        config::load(path)?.apply(&mut options);
+    }
+    if options.upstream.is_empty() && options.upstream_pools.is_empty() {
+        return Err("At least one upstream server must be specified using the --upstream option \
+            or the config file.".into());
+    }
+    Ok(options)
+}
+
+/// Re-reads the config file and swaps in a new ProxyState built from it. Requests that are
+/// already in flight finish with the state they started with; every request read after the swap
+/// uses the new one, including later requests on connections that are already open. If the new
+/// config is invalid, the current state is kept.
+fn reload(cmd_options: &CmdOptions, bind: &str, shared_state: &SharedState) {
+    log::info!("Reloading configuration");
+    let options = match load_options(cmd_options) {
+        Ok(options) => options,
+        Err(err) => {
+            log::error!("Keeping the current configuration: {}", err);
+            return;
+        }
+    };
+    if options.bind != bind {
+        log::warn!("Still listening on {}; changing the bind address requires a restart", bind);
+    }
+    let previous = current_state(shared_state);
+    match ProxyState::from_options(&options, Arc::clone(&previous.metrics)) {
+        Ok(mut state) => {
+            state.pool = Arc::new(previous.next_pool(&state.upstream_endpoints, state.pool.settings().clone()));
+            log::info!("ProxyState settings = {:?}", state);
+            *shared_state.write() = Arc::new(state);
+        }
+        Err(err) => log::error!("Keeping the current configuration: {}", err),
+    }
+}
+
+/// Returns the ProxyState that is currently in effect.
+fn current_state(shared_state: &SharedState) -> Arc<ProxyState> {
+    Arc::clone(&shared_state.read())
+}
+
+/// Returns the upstreams that should not be sent new requests: those that failed their health
+/// check, those that are being drained and those whose circuit is open.
+async fn get_report(state: &ProxyState, report_state: &Arc<RwLock<ReportState>>) -> Report {
     let report = report_state.read().await;
-    report.content.to_owned()
+    let now = Instant::now();
+    let ejected = state
+        .upstream_addresses
+        .iter()
+        .filter(|address| !report.circuits.is_available(address, &state.circuit_breaker, now));
+    report.content.iter().chain(report.drained.iter()).chain(ejected).cloned().collect()
 }
 
-async fn connect_to_upstream(state: &ProxyState, report_state: Arc<RwLock<ReportState>>) -> Result<TcpStream> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
-    let report = get_report(&report_state).await;
+/// Records how a request to an upstream went with the upstream's circuit breaker.
+async fn record_outcome(state: &ProxyState, report_state: &Arc<RwLock<ReportState>>,
+        upstream: &str, success: bool) {
+    let mut report = report_state.write().await;
+    if success {
+        report.circuits.record_success(upstream, &state.circuit_breaker);
+    } else if report.circuits.record_failure(upstream, &state.circuit_breaker, Instant::now()) {
+        state.metrics.record_ejection(upstream);
+    }
+}
 
-    loop {
-        let idx = rng.gen_range(0, state.upstream_addresses.len());
+/// Waits out the backoff before another attempt at a request, if it may be retried: it hasn't used
+/// up its retries (counted in `retries`), and the retry budget has room. Returns false if it may
+/// not be.
+async fn before_retry(state: &ProxyState, report_state: &Arc<RwLock<ReportState>>,
+        retries: &mut usize) -> bool {
+    if *retries >= state.retry.max_retries {
+        return false;
+    }
+    if !report_state.write().await.retry_budget.try_retry(&state.retry, Instant::now()) {
+        log::warn!("Retry budget is spent, not retrying");
+        state.metrics.record_retry(false);
+        return false;
+    }
+    state.metrics.record_retry(true);
+    *retries += 1;
+    tokio::time::delay_for(state.retry.backoff(*retries)).await;
+    true
+}
+
+/// Checks out a connection to an upstream in the pool the request is routed to, picked by the
+/// pool's strategy. If an upstream can't be connected to, another is tried for as long as
+/// before_retry allows, and each upstream at most once. If none of them can be connected to,
+/// fails with NoUpstreamAvailable, or UpstreamTimeout if any of them timed out.
+async fn connect_to_upstream(state: &ProxyState, report_state: &Arc<RwLock<ReportState>>,
+        client_ip: &str, request: Option<&http::Request<Vec<u8>>>, excluded: &[usize],
+        retries: &mut usize) -> Result<PooledConnection> {
+    let pool = match state.route(request) {
+        Some(pool) => &state.pools[pool],
+        None => return Err(ErrorKind::NoRoute.into()),
+    };
+    let report = get_report(state, report_state).await;
+    let mut candidates: Vec<usize> = pool
+        .members
+        .iter()
+        .copied()
+        .filter(|idx| !report.contains(&state.upstream_addresses[*idx]) && !excluded.contains(idx))
+        .collect();
+    let target = strategy::Target {
+        client_ip,
+        request,
+        active_connections: &state.active_connections,
+    };
+
+    let mut timed_out = false;
+    while let Some(idx) = pool.strategy.select(&candidates, &target) {
         let upstream_ip = &state.upstream_addresses[idx];
-        
-        if report.contains(&upstream_ip) {
-            continue;
-        }
-        match TcpStream::connect(&upstream_ip).await {
-            Ok(stream) => {
-                return Ok(stream);
+        report_state.write().await.circuits.begin(upstream_ip, Instant::now());
+        match state.pool.check_out(idx, &state.upstream_endpoints[idx]).await {
+            Ok(conn) => {
+                return Ok(conn);
             },
-            Err(e) => {
-                log::info!("Server-down is detected. {}", upstream_ip);            
+            Err(error) => {
+                log::info!("Server-down is detected. {}: {}", upstream_ip, error);
+                timed_out |= error.kind() == std::io::ErrorKind::TimedOut;
+                record_outcome(state, report_state, upstream_ip, false).await;
+                candidates.retain(|candidate| *candidate != idx);
+                if !candidates.is_empty() && !before_retry(state, report_state, retries).await {
+                    break;
+                }
             }
         }
     }
 
-    let errmsg = "All upstreams are dead.";
-    log::error!("{}",errmsg);
-    return Err(errmsg.into());
+    let error = Error::from(if timed_out { ErrorKind::UpstreamTimeout } else { ErrorKind::NoUpstreamAvailable });
+    log::error!("{}", error);
+    Err(error)
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("{} <- {}", client_ip, response::format_response_line(&response));
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+async fn send_response<C: ClientStream>(client_conn: &mut C, client_ip: &str,
+        response: &http::Response<Vec<u8>>) {
+    log::info!("{} <- {}", client_ip, response::format_response_line(response));
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
-        return;
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState, 
-        report_state: Arc<RwLock<ReportState>>, rate_limit_count: Arc<RwLock<RateLimit>>) {
+/// Sends a response to the client, copying its body across from the upstream connection as it
+/// arrives. Returns the number of body bytes sent, and false if the client connection can't carry
+/// another request afterwards.
+async fn stream_response<C: ClientStream>(state: &ProxyState, client_conn: &mut C, client_ip: &str,
+        response: &http::Response<Vec<u8>>, mut streaming_body: StreamingBody) -> (u64, bool) {
+    log::info!("{} <- {}", client_ip, response::format_response_line(response));
+    if let Err(error) = response::write_head_to_stream(response, client_conn).await {
+        log::warn!("Failed to send response to client: {}", error);
+        return (0, false);
+    }
+    let upstream_ip = &state.upstream_addresses[streaming_body.upstream_conn.idx];
+    let mut upstream_stream = ReadTimeout::new(&mut streaming_body.upstream_conn.stream, state.timeouts.upstream_read);
+    let bytes = match body::copy(streaming_body.framing, response.body(), &mut upstream_stream, client_conn).await {
+        Ok((bytes, leftover)) => {
+            // The upstream only sends a response when asked, so anything after this one is junk
+            // that rules out using the connection again
+            streaming_body.reusable &= leftover.is_empty();
+            log::debug!("Streamed {} byte response body to client", bytes);
+            state.metrics.record_response_bytes(upstream_ip, bytes);
+            bytes
+        }
+        Err(error) => {
+            log::warn!("Failed to stream response body to client: {}", error);
+            return (0, false);
+        }
+    };
+    if streaming_body.reusable {
+        state.pool.check_in(streaming_body.upstream_conn);
+    }
+    // Without Content-Length or chunked encoding, the client only knows the body has ended when
+    // we hang up
+    (bytes, streaming_body.framing != Framing::UntilClose)
+}
+
+/// Adds a request that has been answered to the access log, if there is one. `upstream` is the
+/// index of the upstream that answered and how long it took, unless balancebeam answered itself.
+fn log_access(state: &ProxyState, client_ip: &str, request: &http::Request<Vec<u8>>,
+        timer: RequestTimer, status: http::StatusCode, bytes: u64, upstream: Option<(usize, Duration)>) {
+    if let Some(access_log) = &state.access_log {
+        access_log.record(&access_log::Entry {
+            client_ip,
+            request,
+            timer,
+            status,
+            bytes,
+            upstream: upstream.map(|(idx, _)| state.upstream_addresses[idx].as_str()),
+            upstream_latency: upstream.map(|(_, latency)| latency),
+        });
+    }
+}
 
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// Serves a newly accepted client connection as the configured mode says. `scheme` is `https` if
+/// the connection is over TLS, and `http` otherwise. `shutdown` tells the connection when
+/// balancebeam starts shutting down, and holds up the shutdown until the connection is closed.
+async fn serve_client<C: ClientStream>(client_conn: C, client_ip: String, scheme: &'static str,
+        shared_state: &SharedState, report_state: Arc<RwLock<ReportState>>,
+        rate_limit_count: Arc<RwLock<RateLimit>>, shutdown: Shutdown) {
+    match current_state(shared_state).mode {
+        Mode::Http => handle_connection(client_conn, client_ip, scheme, shared_state, report_state,
+            rate_limit_count, shutdown).await,
+        // A relayed connection can't be asked to finish up, so it runs until the drain deadline
+        Mode::Tcp => relay_connection(client_conn, client_ip, shared_state, report_state).await,
+    }
+}
 
-    if state.max_requests_per_minute > 0 {
-        if rate_limit(&client_ip, &state, rate_limit_count).await {
-            let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Relays a client connection to an upstream picked by the strategy, without looking at what is
+/// sent over it (tcp mode). The upstream is picked once, when the client connects.
+async fn relay_connection<C: ClientStream>(mut client_conn: C, client_ip: String,
+        shared_state: &SharedState, report_state: Arc<RwLock<ReportState>>) {
+    let state = current_state(shared_state);
+    let _connection = state.metrics.client_connected();
+    report_state.write().await.retry_budget.record_request(Instant::now());
+    let mut upstream_conn = match connect_to_upstream(&state, &report_state, &client_ip, None, &[], &mut 0).await {
+        Ok(conn) => conn,
+        // There is no way to tell the client what went wrong, so just hang up
+        Err(_) => return,
+    };
+    let upstream_ip = &state.upstream_addresses[upstream_conn.idx];
+    let _active = ActiveConnection::new(&state.active_connections, upstream_conn.idx);
+    record_outcome(&state, &report_state, upstream_ip, true).await;
+    log::info!("{} <-> {}: relaying connection", client_ip, upstream_ip);
+    match tunnel::run(&mut client_conn, &mut upstream_conn.stream, state.tunnel_idle_timeout).await {
+        Ok((sent, received)) => {
+            log::info!("{} <-> {}: connection closed after {} bytes up, {} bytes down", client_ip,
+                upstream_ip, sent, received);
+            state.metrics.record_request_bytes(upstream_ip, sent);
+            state.metrics.record_response_bytes(upstream_ip, received);
         }
+        Err(error) => log::info!("{} <-> {}: connection closed: {}", client_ip, upstream_ip, error),
     }
+}
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(&state, report_state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+/// Relays bytes between a client and an upstream that have switched to another protocol.
+/// `pipelined` is whatever the client sent after its upgrade request, which it didn't wait for
+/// the switch to send, and goes to the upstream first.
+async fn tunnel<C: ClientStream>(state: &ProxyState, client_conn: &mut C, client_ip: &str,
+        pipelined: &[u8], mut streaming_body: StreamingBody) {
+    let upstream_ip = &state.upstream_addresses[streaming_body.upstream_conn.idx];
+    log::info!("{} <-> {}: tunnel open", client_ip, upstream_ip);
+    let upstream_stream = &mut streaming_body.upstream_conn.stream;
+    if let Err(error) = upstream_stream.write_all(pipelined).await {
+        log::info!("{} <-> {}: tunnel closed: {}", client_ip, upstream_ip, error);
+        return;
+    }
+    match tunnel::run(client_conn, upstream_stream, state.tunnel_idle_timeout).await {
+        Ok((sent, received)) => {
+            let sent = sent + pipelined.len() as u64;
+            log::info!("{} <-> {}: tunnel closed after {} bytes up, {} bytes down", client_ip,
+                upstream_ip, sent, received);
+            state.metrics.record_request_bytes(upstream_ip, sent);
+            state.metrics.record_response_bytes(upstream_ip, received);
+        }
+        Err(error) => log::info!("{} <-> {}: tunnel closed: {}", client_ip, upstream_ip, error),
+    }
+}
+
+async fn handle_connection<C: ClientStream>(client_conn: C, client_ip: String, scheme: &'static str,
+        shared_state: &SharedState, report_state: Arc<RwLock<ReportState>>,
+        rate_limit_count: Arc<RwLock<RateLimit>>, mut shutdown: Shutdown) {
+    let (_connection, client_header_timeout) = {
+        let state = current_state(shared_state);
+        (state.metrics.client_connected(), state.timeouts.client_header)
+    };
+
+    // HTTP/2 clients announce themselves with a connection preface, whether they negotiated
+    // HTTP/2 during the TLS handshake or are speaking it over plain TCP (h2c). A client that
+    // sends nothing at all is given as long as it would have had to send its request headers, or
+    // until we start shutting down.
+    let detected = tokio::select! {
+        detected = tokio::time::timeout(client_header_timeout, http2::detect(client_conn)) => detected,
+        _ = shutdown.draining() => {
+            log::debug!("Shutting down idle client connection");
+            return;
+        }
+    };
+    let mut client_conn = match detected {
+        Ok(Ok((client_conn, true))) => {
+            return http2::serve(client_conn, client_ip, scheme, shared_state, report_state,
+                rate_limit_count, shutdown).await
+        }
+        Ok(Ok((client_conn, false))) => client_conn,
+        Ok(Err(error)) => {
+            log::info!("Error reading from client stream: {}", error);
+            return;
+        }
+        Err(_) => {
+            log::info!("Timed out waiting for a request from {}", client_ip);
             return;
         }
     };
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
+    let mut keep_alive = false;
+    // Whatever the client has sent after the end of the last request, which starts the next one
+    let mut pipelined = Vec::new();
     loop {
         // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => request,
+        let (max_buffered_body, timeouts) = {
+            let state = current_state(shared_state);
+            (state.max_buffered_body, state.timeouts)
+        };
+        // Once we are shutting down, a client that isn't in the middle of a request is hung up on
+        let head = request::read_head_from_stream(&mut client_conn, &mut pipelined, max_buffered_body,
+            &timeouts, keep_alive, shutdown.draining());
+        keep_alive = true;
+        let (mut request, request_framing) = match head.await {
+            Ok(head) => head,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
                 return;
             }
+            Err(request::Error::IdleTimeout) => {
+                log::debug!("Client sent no request for {:?}. Shutting down connection", timeouts.keep_alive);
+                return;
+            }
+            Err(request::Error::Interrupted) => {
+                log::debug!("Shutting down idle client connection");
+                return;
+            }
             // Handle I/O error in reading from the client
             Err(request::Error::ConnectionError(io_err)) => {
                 log::info!("Error reading request from client stream: {}", io_err);
                 return;
             }
+            // What's left of the request may still arrive, so the connection can't be reused
+            Err(request::Error::RequestTimeout) => {
+                log::info!("Timed out reading request from {}", client_ip);
+                let mut response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                response.headers_mut().insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+                send_response(&mut client_conn, &client_ip, &response).await;
+                return;
+            }
             Err(error) => {
                 log::debug!("Error parsing request: {:?}", error);
                 let response = response::make_http_error(match error {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::InvalidChunkedEncoding => http::StatusCode::BAD_REQUEST,
                     request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    request::Error::UnsupportedTransferEncoding => http::StatusCode::NOT_IMPLEMENTED,
+                    request::Error::IdleTimeout | request::Error::RequestTimeout => {
+                        http::StatusCode::REQUEST_TIMEOUT
+                    }
+                    request::Error::Interrupted => http::StatusCode::SERVICE_UNAVAILABLE,
                     request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
                 });
-                send_response(&mut client_conn, &response).await;
+                send_response(&mut client_conn, &client_ip, &response).await;
                 continue;
             }
         };
+
+        let timer = RequestTimer::start();
+        // Pick up the latest configuration for every request, so that a reload applies to
+        // connections that are already open
+        let state = current_state(shared_state);
+        // The Connection header is only meant for us, and is dropped before forwarding
+        let wants_close = request::wants_close(&request);
+
+        // Every request counts against the rate limit, not just the first on each connection
+        if let Some(decision) = rate_limit(&client_ip, &request, &state, &rate_limit_count).await {
+            state.metrics.record_rate_limited();
+            let response = make_rate_limited_response(&decision);
+            send_response(&mut client_conn, &client_ip, &response).await;
+            log_access(&state, &client_ip, &request, timer, response.status(), response.body().len() as u64, None);
+            if request_framing.is_some() || wants_close {
+                // The request body hasn't been read, so we can't tell where the next request
+                // starts
+                return;
+            }
+            continue;
+        }
+
+        // Add X-Forwarded-For and friends so that the upstream server knows the client's IP
+        // address. (We're the ones connecting directly to the upstream server, so without them,
+        // the upstream server will only know our IP, not the client's.)
+        let version = request.version();
+        let request_id = state.headers.rewrite_request(&mut request, &client_ip, scheme, version);
+
+        // A large request body is still sitting in the client connection, and is copied to the
+        // upstream as it arrives
+        let mut body_reader = ReadTimeout::new(&mut client_conn, state.timeouts.client_body);
+        let request_body = request_framing.map(|framing| (framing, &mut body_reader, &mut pipelined));
+        let Forwarded { mut response, streaming_body, upstream } =
+            match fetch(&state, &report_state, &client_ip, &request, request_body).await {
+            Ok(forwarded) => forwarded,
+            Err(error) => {
+                let response = make_error_response(&state, &report_state, error).await;
+                send_response(&mut client_conn, &client_ip, &response).await;
+                log_access(&state, &client_ip, &request, timer, response.status(), response.body().len() as u64, None);
+                if request_framing.is_some() {
+                    // Some of the request body may not have been read, so we can't tell where the
+                    // next request starts
+                    return;
+                }
+                continue;
+            }
+        };
+        state.headers.rewrite_response(&mut response, request_id.as_ref());
+        // Once the upstream has switched protocols, the connection is no longer HTTP, and is
+        // relayed as it is until either side hangs up. The response has to keep its
+        // `Connection: upgrade`, so a client that wanted the connection closed (or a shutdown)
+        // only gets it closed once the tunnel is done.
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            send_response(&mut client_conn, &client_ip, &response).await;
+            log_access(&state, &client_ip, &request, timer, response.status(), 0, upstream);
+            if let Some(streaming_body) = streaming_body {
+                tunnel(&state, &mut client_conn, &client_ip, &pipelined, streaming_body).await;
+            }
+            return;
+        }
+        // Once we are shutting down, keep-alive connections are closed after their current response
+        let wants_close = wants_close || shutdown.is_draining();
+        // The client is only told to expect us to hang up when we are going to
+        let until_close = matches!(&streaming_body, Some(body) if body.framing == Framing::UntilClose);
+        if wants_close || until_close {
+            response.headers_mut().insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+        }
+        // Forward the response to the client
+        let (bytes, reusable) = match streaming_body {
+            None => {
+                send_response(&mut client_conn, &client_ip, &response).await;
+                (response.body().len() as u64, true)
+            }
+            Some(streaming_body) => {
+                stream_response(&state, &mut client_conn, &client_ip, &response, streaming_body).await
+            }
+        };
+        log_access(&state, &client_ip, &request, timer, response.status(), bytes, upstream);
+        if !reusable {
+            return;
+        }
+        log::debug!("Forwarded response to client");
+        if wants_close {
+            log::debug!("Client asked to close the connection, or we are shutting down. Shutting down connection");
+            return;
+        }
+    }
+}
+
+/// Answers a request from the response cache if it can, and otherwise forwards it with
+/// forward_request and offers the response to the cache. A stale response with validators is
+/// revalidated with a conditional request instead of being fetched again. The X-Cache header on
+/// the response says which of these happened.
+async fn fetch<B: AsyncRead + Unpin>(state: &ProxyState, report_state: &Arc<RwLock<ReportState>>,
+        client_ip: &str, request: &http::Request<Vec<u8>>, request_body: Option<(Framing, &mut B, &mut Vec<u8>)>)
+        -> Result<Forwarded> {
+    let cache = match &state.cache {
+        Some(cache) => cache,
+        None => return forward_request(state, report_state, client_ip, request, request_body).await,
+    };
+    // A request whose body is still being read is no GET or HEAD
+    let lookup = if request_body.is_some() { Lookup::Bypass } else { cache.lookup(request, Instant::now()) };
+    // A response that is streamed is too big to keep
+    let store = |forwarded: &Forwarded| {
+        if forwarded.streaming_body.is_none() {
+            cache.store(request, &forwarded.response, Instant::now());
+        }
+    };
+    let (status, mut forwarded) = match lookup {
+        Lookup::Fresh(response) => {
+            log::debug!("Answering {} from the cache", request::format_request_line(request));
+            (cache::Status::Hit, Forwarded { response, streaming_body: None, upstream: None })
+        }
+        Lookup::Stale(conditions) => {
+            let conditional = cache::conditional_request(request, conditions);
+            let forwarded = forward_request::<B>(state, report_state, client_ip, &conditional, None).await?;
+            if forwarded.response.status() != http::StatusCode::NOT_MODIFIED {
+                store(&forwarded);
+                (cache::Status::Miss, forwarded)
+            } else if let Some(response) = cache.revalidate(request, &forwarded.response, Instant::now()) {
+                (cache::Status::Revalidated, Forwarded { response, ..forwarded })
+            } else {
+                // The stored response was evicted while it was being revalidated, and the 304
+                // doesn't answer what the client asked
+                let forwarded = forward_request::<B>(state, report_state, client_ip, request, None).await?;
+                store(&forwarded);
+                (cache::Status::Miss, forwarded)
+            }
+        }
+        Lookup::Miss => {
+            let forwarded = forward_request(state, report_state, client_ip, request, request_body).await?;
+            store(&forwarded);
+            (cache::Status::Miss, forwarded)
+        }
+        Lookup::Bypass => {
+            let forwarded = forward_request(state, report_state, client_ip, request, request_body).await?;
+            let status = forwarded.response.status();
+            // A request that may have changed what is at its URL makes the stored responses stale
+            if !request.method().is_safe() && (status.is_success() || status.is_redirection()) {
+                cache.invalidate(request);
+            }
+            (cache::Status::Bypass, forwarded)
+        }
+    };
+    state.metrics.record_cache_request(status.name());
+    forwarded.response.headers_mut().insert(cache::CACHE_STATUS, http::HeaderValue::from_static(status.header_value()));
+    Ok(forwarded)
+}
+
+/// Sends a request to an upstream picked by the strategy and returns the upstream's response.
+/// Every request is balanced on its own, so consecutive requests from one client connection may
+/// go to different upstreams. If an idempotent request fails partway, it is transparently
+/// retried on a different upstream, as far as before_retry allows.
+///
+/// If `request_body` is given, the rest of the request body is copied from the client connection
+/// as it is sent, and whatever was read past its end is left in the Vec that comes with it. Such a
+/// request can only be sent once, so it is never retried. If the response body is too big to
+/// buffer, it is returned as a StreamingBody for the caller to copy to the client. So is the
+/// connection itself after a 101 Switching Protocols response.
+async fn forward_request<B: AsyncRead + Unpin>(state: &ProxyState,
+        report_state: &Arc<RwLock<ReportState>>, client_ip: &str, request: &http::Request<Vec<u8>>,
+        mut request_body: Option<(Framing, &mut B, &mut Vec<u8>)>) -> Result<Forwarded> {
+    let streamed = request_body.is_some();
+    let mut failed = Vec::new();
+    let mut last_error = None;
+    let mut retries = 0;
+    report_state.write().await.retry_budget.record_request(Instant::now());
+    loop {
+        let connected = connect_to_upstream(state, report_state, client_ip, Some(request), &failed, &mut retries).await;
+        let mut upstream_conn = match connected {
+            Ok(conn) => conn,
+            // If every upstream has had a go, the client should hear how the last one failed
+            Err(error) => return Err(last_error.unwrap_or(error)),
+        };
+        let idx = upstream_conn.idx;
+        let upstream_ip = &state.upstream_addresses[idx];
+        let active = ActiveConnection::new(&state.active_connections, idx);
         log::info!(
             "{} -> {}: {}",
             client_ip,
             upstream_ip,
-            request::format_request_line(&request)
+            request::format_request_line(request)
         );
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // A pooled connection may have been closed by the upstream while it sat idle, so if a
+        // reused connection fails, try once more on a fresh one. An upstream that is just slow
+        // would be no quicker on a new connection. The upstream may have acted on the request
+        // before the connection failed, so only idempotent requests are sent again.
+        let sent = Instant::now();
+        let result = exchange(state, &mut upstream_conn, request, request_body.take()).await;
+        let stale = upstream_conn.reused && !streamed && request::is_idempotent(request);
+        let result = match result {
+            Err(error) if stale && !matches!(error.kind(), ErrorKind::UpstreamTimeout) => {
+                log::debug!("Pooled connection to {} went stale, reconnecting", upstream_ip);
+                match state.pool.reconnect(upstream_conn, &state.upstream_endpoints[idx]).await {
+                    Ok(mut conn) => exchange::<B>(state, &mut conn, request, None)
+                        .await
+                        .map(|response| (response, conn)),
+                    Err(connect_error) => {
+                        log::error!("Failed to connect to upstream {}: {}", upstream_ip, connect_error);
+                        Err(error)
+                    }
+                }
+            }
+            result => result.map(|response| (response, upstream_conn)),
+        };
+
+        let latency = sent.elapsed();
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+        // Connection errors, timeouts and 5xx responses count towards ejecting the upstream, but a
+        // client that stalls or gives up isn't the upstream's fault
+        if !matches!(&result, Err(Error(ErrorKind::ClientTimeout, _)) | Err(Error(ErrorKind::ClientAborted, _))) {
+            let success = matches!(&result, Ok(((response, _), _)) if !response.status().is_server_error());
+            record_outcome(state, report_state, upstream_ip, success).await;
+        }
+        match result {
+            Ok(((response, framing), upstream_conn)) => {
+                if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+                    // The connection now speaks whatever protocol was switched to, so it goes
+                    // back to the client to be tunnelled rather than into the pool
+                    let streaming_body = Some(StreamingBody {
+                        upstream_conn,
+                        framing: Framing::UntilClose,
+                        reusable: false,
+                        _active: active,
+                    });
+                    return Ok(Forwarded { response, streaming_body, upstream: Some((idx, latency)) });
+                }
+                let reusable = response::is_reusable(&response, request.method());
+                return match framing {
+                    None => {
+                        if reusable {
+                            state.pool.check_in(upstream_conn);
+                        }
+                        Ok(Forwarded { response, streaming_body: None, upstream: Some((idx, latency)) })
+                    }
+                    Some(framing) => {
+                        let streaming_body = Some(StreamingBody {
+                            upstream_conn,
+                            framing,
+                            reusable,
+                            _active: active,
+                        });
+                        Ok(Forwarded { response, streaming_body, upstream: Some((idx, latency)) })
+                    }
+                };
+            }
+            Err(error) => {
+                if streamed || !request::is_idempotent(request) || !before_retry(state, report_state, &mut retries).await {
+                    return Err(error);
+                }
+                log::info!("Upstream {} failed, retrying {} elsewhere", upstream_ip, request::format_request_line(request));
+                failed.push(idx);
+                last_error = Some(error);
+            }
+        }
+    }
+}
+
+/// Sends a request over an upstream connection and reads back the response. If `request_body` is
+/// given, the request body is streamed from the client connection after the headers, and anything
+/// the client sent after the body is put in its Vec, as the start of its next request. Only the
+/// response head is read if the response body is bigger than max_buffered_body; the returned
+/// Framing then says how to stream the rest of it.
+async fn exchange<B: AsyncRead + Unpin>(state: &ProxyState, upstream_conn: &mut PooledConnection,
+        request: &http::Request<Vec<u8>>, request_body: Option<(Framing, &mut B, &mut Vec<u8>)>)
+        -> Result<(http::Response<Vec<u8>>, Option<Framing>)> {
+    let upstream_ip = &state.upstream_addresses[upstream_conn.idx];
+    let started = std::time::Instant::now();
+    let mut client_failed = false;
+    let sent = match request_body {
+        Some((framing, client_conn, pipelined)) => {
+            match request::write_head_to_stream(request, &mut upstream_conn.stream).await {
+                Ok(()) => {
+                    let mut client_conn = body::Source::new(client_conn);
+                    let copied = body::copy(framing, request.body(), &mut client_conn, &mut upstream_conn.stream).await;
+                    client_failed = matches!(&copied, Err(error) if client_conn.is_to_blame(error));
+                    copied.map(|(bytes, leftover)| {
+                        *pipelined = leftover;
+                        bytes
+                    })
+                }
+                Err(error) => Err(error),
+            }
+        }
+        None => request::write_to_stream(request, &mut upstream_conn.stream)
+            .await
+            .map(|()| request.body().len() as u64),
+    };
+    match sent {
+        Ok(bytes) => {
+            log::debug!("Forwarded request with {} byte body to server", bytes);
+            state.metrics.record_request_bytes(upstream_ip, bytes);
+        }
+        // Only reads from the client time out while sending, so the client is to blame
+        Err(error) if error.kind() == std::io::ErrorKind::TimedOut => {
+            log::info!("Client stopped sending the request body: {}", error);
+            return Err(ErrorKind::ClientTimeout.into());
+        }
+        // Nor is the upstream at fault if the client hangs up partway through the body
+        Err(error) if client_failed => {
+            log::info!("Client stopped sending the request body: {}", error);
+            return Err(ErrorKind::ClientAborted.into());
+        }
+        Err(error) => {
             log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+            state.metrics.record_error(upstream_ip);
+            return Err("Failed to send request to upstream.".into());
         }
-        log::debug!("Forwarded request to server");
+    }
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
+    // Read the server's response
+    let mut upstream_stream = ReadTimeout::new(&mut upstream_conn.stream, state.timeouts.upstream_read);
+    match response::read_head_from_stream(&mut upstream_stream, request.method(), state.max_buffered_body).await {
+        Ok((response, framing)) => {
+            state.metrics.record_response(upstream_ip, response.status(), started.elapsed());
+            if framing.is_none() {
+                state.metrics.record_response_bytes(upstream_ip, response.body().len() as u64);
             }
-        };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+            Ok((response, framing))
+        }
+        Err(response::Error::ConnectionError(error)) if error.kind() == std::io::ErrorKind::TimedOut => {
+            log::error!("Upstream {} did not respond in time: {}", upstream_ip, error);
+            state.metrics.record_error(upstream_ip);
+            Err(ErrorKind::UpstreamTimeout.into())
+        }
+        Err(error) => {
+            log::error!("Error reading response from server: {:?}", error);
+            state.metrics.record_error(upstream_ip);
+            Err("Error reading response from upstream.".into())
+        }
     }
 }
 
 //Health check -- milestone 4
-async fn health_check(state: &ProxyState, report_state: Arc<RwLock<ReportState>>) {
-    let seconds = state.active_health_check_interval;
-    let duration = Duration::from_secs(seconds as u64);
-    let path = &state.active_health_check_path;
-    
-    log::info!("Health check start. -> interval {} seconds", seconds);
+async fn health_check(shared_state: SharedState, report_state: Arc<RwLock<ReportState>>) {
+    for pool in current_state(&shared_state).pools.iter() {
+        log::info!("Health check start. -> pool {} interval {} seconds", pool.name, pool.health_check_interval);
+    }
+    // Keyed by address, so that an upstream's recent results survive config reloads
+    let mut trackers: HashMap<String, health_check::Tracker> = HashMap::new();
+    // When each pool was last checked, keyed by name for the same reason
+    let mut last_checked: HashMap<String, Instant> = HashMap::new();
+    let started = Instant::now();
     loop {
-        tokio::time::delay_for(duration).await;
-        let mut failed_servers = vec![];
-        for ip in state.upstream_addresses.iter() {                             
-            let response = health_check_upstream(&ip, &path).await;
-            if response.is_err() {
-                failed_servers.push(ip.to_owned());
-            }   
+        // Each pool is checked on its own interval, so sleep until the next one is due
+        let next_check = |pool: &UpstreamPool, last_checked: &HashMap<String, Instant>| {
+            *last_checked.get(&pool.name).unwrap_or(&started)
+                + Duration::from_secs(pool.health_check_interval as u64)
+        };
+        let wake_at = current_state(&shared_state)
+            .pools
+            .iter()
+            .map(|pool| next_check(pool, &last_checked))
+            .min()
+            .unwrap_or(started);
+        tokio::time::delay_until(wake_at.into()).await;
+        // Check whichever pools are configured and due once we wake up. All of their upstreams
+        // are probed at once, so a slow one doesn't hold up the others.
+        let state = current_state(&shared_state);
+        let now = Instant::now();
+        let due: Vec<&UpstreamPool> = state
+            .pools
+            .iter()
+            .filter(|pool| next_check(pool, &last_checked) <= now)
+            .collect();
+        let probes: Vec<_> = due
+            .iter()
+            .flat_map(|pool| pool.members.iter().map(move |idx| (*pool, *idx)))
+            .map(|(pool, idx)| {
+                let endpoint = state.upstream_endpoints[idx].clone();
+                let check = pool.health_check.clone();
+                (pool, idx, tokio::spawn(async move { check.probe(&endpoint).await }))
+            })
+            .collect();
+        let mut passed_servers = vec![];
+        for (pool, idx, probe) in probes {
+            let ip = &state.upstream_addresses[idx];
+            let result = probe.await.unwrap_or_else(|err| Err(format!("Probe panicked: {}", err)));
+            match &result {
+                Ok(()) => log::info!("Health Check PASS. {} is running.", ip),
+                Err(reason) => log::info!("Health Check NOT PASS {} -> {}", ip, reason),
+            }
+            state.metrics.record_health_check(ip, result.is_ok());
+            let tracker = trackers.entry(ip.clone()).or_default();
+            if tracker.record(result.is_ok(), &pool.health_check) && result.is_ok() {
+                passed_servers.push(ip);
+            }
+        }
+        for pool in due {
+            last_checked.insert(pool.name.clone(), now);
         }
+        trackers.retain(|ip, _| state.upstream_addresses.contains(ip));
+        last_checked.retain(|name, _| state.pools.iter().any(|pool| pool.name == *name));
+        // Upstreams in pools that weren't due keep the health they had
+        let failed_servers: Report = state
+            .upstream_addresses
+            .iter()
+            .filter(|ip| trackers.get(*ip).is_some_and(|tracker| !tracker.is_healthy()))
+            .cloned()
+            .collect();
         {
             let mut report = report_state.write().await;
             if report.content != failed_servers {
                 report.content = failed_servers;
             }
-        }
-    }    
-}
-
-async fn health_check_upstream(upstream: &str, path: &str) -> Result<()>{  
-    log::info!("Health Check Start for {} : {}", upstream, path);  
-    if let Ok(mut upstream_conn) = TcpStream::connect(upstream).await {
-        let request: http::Request<Vec<u8>> = http::Request::builder()
-            .method(http::Method::GET)
-            .uri(path)
-            .header("Host", upstream)
-            .body(Vec::new())
-            .unwrap();
-
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::info!("Health Check Not PASS {} -> Failed to send request to upstream", upstream);
-            return Err("Failed to send request to upstream.".into());
-        };
-        
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
-            Ok(response) => response,
-            Err(error) => {
-                log::info!("Health Check NOT PASS {} -> Error reading response from server", upstream);
-                return Err("Error reading response from upstream.".into());
+            // Upstreams ejected by passive health checks get trial requests as soon as they
+            // pass an active one
+            let now = Instant::now();
+            for ip in passed_servers {
+                report.circuits.health_check_passed(ip, now);
             }
-        };
-
-        if response.status() == http::StatusCode::OK {
-            log::info!("Health Check PASS. {} is running.", upstream);
-            return Ok(())
-        } else {
-            log::info!("Health Check NOT PASS {} -> Response status is not Ok {}", upstream, response.status().as_u16());
-            return Err("Response status is not ok.".into());
         }
-    } else {
-        log::info!("Health Check NOT PASS {} -> connection error", upstream);
-        return Err("Connection Error".into());
     }
 }
 
 //rate limiting
-async fn rate_limit(client_ip: &String, state: &ProxyState, rate_limit_count: Arc<RwLock<RateLimit>>) -> bool {       
-    if rate_over(&client_ip, &state, &rate_limit_count).await {
-        return true;
+/// Counts a request against the rate limit rule for its path, returning the rule's decision if
+/// the request is over the limit and should be rejected.
+async fn rate_limit(client_ip: &str, request: &http::Request<Vec<u8>>, state: &ProxyState,
+        rate_limit_count: &Arc<RwLock<RateLimit>>) -> Option<Decision> {
+    let rule = match rate_limiter::select(&state.rate_limit_rules, request.uri().path()) {
+        Some(rule) if rule.policy.limit > 0 => rule,
+        _ => return None,
+    };
+    let key = rule.key(client_ip, request);
+    let decision = rate_limit_count.write().await.try_acquire(&key, &rule.policy, Instant::now());
+    if decision.allowed {
+        None
     } else {
-        let mut rate_limit_count = rate_limit_count.write().await; 
-        if let Some(value) = rate_limit_count.map.get_mut(client_ip) {
-            *value = *value + 1;
-        } else {
-            rate_limit_count.map.insert(client_ip.to_owned(), 1);
-        }
-        return false;
-    }   
+        Some(decision)
+    }
 }
 
-async fn rate_over(client_ip: &str, state: &ProxyState, rate_limit_count: &Arc<RwLock<RateLimit>>) -> bool {
-    let rate_limit_count = rate_limit_count.read().await;
-    log::info!("rate_limit_count = {:?}", rate_limit_count.map); 
-    let map = &rate_limit_count.map;
-    if let Some(value) = map.get(client_ip) {
-        if *value >= state.max_requests_per_minute {
-            return true;
-        }
+/// The response to send a client when its request could not be forwarded.
+async fn make_error_response(state: &ProxyState, report_state: &Arc<RwLock<ReportState>>, error: Error)
+        -> http::Response<Vec<u8>> {
+    match error.kind() {
+        ErrorKind::NoUpstreamAvailable => make_unavailable_response(state, report_state).await,
+        ErrorKind::NoRoute => response::make_http_error(http::StatusCode::NOT_FOUND),
+        ErrorKind::ClientTimeout => response::make_http_error(http::StatusCode::REQUEST_TIMEOUT),
+        ErrorKind::ClientAborted => response::make_http_error(http::StatusCode::BAD_REQUEST),
+        ErrorKind::UpstreamTimeout => response::make_http_error(http::StatusCode::GATEWAY_TIMEOUT),
+        _ => response::make_http_error(http::StatusCode::BAD_GATEWAY),
     }
-    return false;
+}
+
+/// Builds the 503 sent when no upstream can take a request. Clients are asked to come back when
+/// the first ejected upstream is let back in for a trial request. If no upstream is ejected, they
+/// are asked to come back after the next active health check, which is when an upstream is most
+/// likely to be back.
+async fn make_unavailable_response(state: &ProxyState, report_state: &Arc<RwLock<ReportState>>)
+        -> http::Response<Vec<u8>> {
+    let mut response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+    let now = Instant::now();
+    let reopens = {
+        let report = report_state.read().await;
+        state
+            .upstream_addresses
+            .iter()
+            .filter_map(|address| report.circuits.available_in(address, &state.circuit_breaker, now))
+            .min()
+    };
+    let retry_after = match reopens {
+        // Round up, so that a client that waits as long as it's told finds the upstream let back in
+        Some(wait) => wait.as_secs() + (wait.subsec_nanos() > 0) as u64,
+        None => state.pools.iter().map(|pool| pool.health_check_interval as u64).min().unwrap_or(1),
+    };
+    response.headers_mut().insert("Retry-After", retry_after.max(1).into());
+    response
+}
+
+/// Builds the 429 sent when a request is over its rate limit, with Retry-After and the
+/// RateLimit-Limit/Remaining/Reset headers telling the client when it can try again.
+fn make_rate_limited_response(decision: &Decision) -> http::Response<Vec<u8>> {
+    // Round up, so that a client that waits as long as it's told isn't turned away again
+    let seconds = |duration: Duration| duration.as_secs() + (duration.subsec_nanos() > 0) as u64;
+    let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+    let headers = response.headers_mut();
+    headers.insert("Retry-After", seconds(decision.retry_after).max(1).into());
+    headers.insert("RateLimit-Limit", decision.limit.into());
+    headers.insert("RateLimit-Remaining", decision.remaining.into());
+    headers.insert("RateLimit-Reset", seconds(decision.reset).into());
+    response
 }
\ No newline at end of file
@@ -0,0 +1,284 @@
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Number of observations that fell into each of LATENCY_BUCKETS (not cumulative)
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Counters kept for each upstream address.
+#[derive(Debug, Default)]
+struct UpstreamMetrics {
+    /// Responses received, by status code
+    responses: BTreeMap<u16, u64>,
+    /// Requests that failed without a response (connection refused, reset, malformed response...)
+    errors: u64,
+    /// Time from sending a request until its response head arrived
+    latency: Histogram,
+    /// Body bytes sent to the upstream
+    request_bytes: u64,
+    /// Body bytes received from the upstream
+    response_bytes: u64,
+    health_checks_passed: u64,
+    health_checks_failed: u64,
+    /// Times the upstream was ejected by its circuit breaker
+    ejections: u64,
+}
+
+/// Point-in-time values for one upstream, collected from the ProxyState when rendering.
+pub struct UpstreamGauges<'a> {
+    pub address: &'a str,
+    pub healthy: bool,
+    pub active_connections: usize,
+    pub idle_connections: usize,
+}
+
+/// Counters and histograms describing what the proxy has been doing, rendered in the Prometheus
+/// text format by the admin API's `/metrics` endpoint. Upstreams are labelled by address, so their
+/// counters survive config reloads.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    upstreams: Mutex<BTreeMap<String, UpstreamMetrics>>,
+    /// Client connections currently open
+    client_connections: AtomicUsize,
+    /// Requests rejected by the rate limiter
+    rate_limited: AtomicU64,
+    /// Requests retried on another upstream
+    retries: AtomicU64,
+    /// Retries that were not made because the retry budget was spent
+    retries_denied: AtomicU64,
+    /// Requests by how the response cache handled them
+    cache_requests: Mutex<BTreeMap<&'static str, u64>>,
+    /// Responses evicted from the cache to make room for others
+    cache_evictions: AtomicU64,
+}
+
+/// Point-in-time size of the response cache, collected from the ProxyState when rendering.
+pub struct CacheGauges {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// Counts a client connection as open for as long as it is alive.
+pub struct ClientConnection {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        self.metrics.client_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    fn with_upstream(&self, upstream: &str, f: impl FnOnce(&mut UpstreamMetrics)) {
+        let mut upstreams = self.upstreams.lock();
+        match upstreams.get_mut(upstream) {
+            Some(metrics) => f(metrics),
+            None => f(upstreams.entry(upstream.to_string()).or_default()),
+        }
+    }
+
+    pub fn client_connected(self: &Arc<Self>) -> ClientConnection {
+        self.client_connections.fetch_add(1, Ordering::Relaxed);
+        ClientConnection {
+            metrics: Arc::clone(self),
+        }
+    }
+
+    pub fn record_response(&self, upstream: &str, status: http::StatusCode, latency: Duration) {
+        self.with_upstream(upstream, |metrics| {
+            *metrics.responses.entry(status.as_u16()).or_default() += 1;
+            metrics.latency.observe(latency.as_secs_f64());
+        });
+    }
+
+    pub fn record_error(&self, upstream: &str) {
+        self.with_upstream(upstream, |metrics| metrics.errors += 1);
+    }
+
+    pub fn record_request_bytes(&self, upstream: &str, bytes: u64) {
+        self.with_upstream(upstream, |metrics| metrics.request_bytes += bytes);
+    }
+
+    pub fn record_response_bytes(&self, upstream: &str, bytes: u64) {
+        self.with_upstream(upstream, |metrics| metrics.response_bytes += bytes);
+    }
+
+    pub fn record_health_check(&self, upstream: &str, passed: bool) {
+        self.with_upstream(upstream, |metrics| {
+            if passed {
+                metrics.health_checks_passed += 1;
+            } else {
+                metrics.health_checks_failed += 1;
+            }
+        });
+    }
+
+    pub fn record_ejection(&self, upstream: &str) {
+        self.with_upstream(upstream, |metrics| metrics.ejections += 1);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self, allowed: bool) {
+        if allowed {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.retries_denied.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_cache_request(&self, result: &'static str) {
+        *self.cache_requests.lock().entry(result).or_default() += 1;
+    }
+
+    pub fn record_cache_evictions(&self, evictions: usize) {
+        self.cache_evictions.fetch_add(evictions as u64, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format. `gauges` holds the current
+    /// state of each configured upstream, and `cache` that of the response cache, if caching is
+    /// on.
+    pub fn render(&self, gauges: &[UpstreamGauges], cache: Option<CacheGauges>) -> String {
+        let mut out = String::new();
+        let upstreams = self.upstreams.lock();
+
+        header(&mut out, "balancebeam_upstream_responses_total", "counter",
+            "Responses received from each upstream, by status code.");
+        for (upstream, metrics) in upstreams.iter() {
+            for (code, count) in metrics.responses.iter() {
+                writeln!(out, "balancebeam_upstream_responses_total{{upstream=\"{}\",code=\"{}\"}} {}",
+                    escape(upstream), code, count).unwrap();
+            }
+        }
+        counter(&mut out, &upstreams, "balancebeam_upstream_errors_total",
+            "Requests to each upstream that failed without a response.", |m| m.errors);
+
+        header(&mut out, "balancebeam_upstream_latency_seconds", "histogram",
+            "Time from sending a request to an upstream until its response head arrived.");
+        for (upstream, metrics) in upstreams.iter() {
+            let upstream = escape(upstream);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(metrics.latency.buckets.iter()) {
+                cumulative += count;
+                writeln!(out, "balancebeam_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                    upstream, bound, cumulative).unwrap();
+            }
+            writeln!(out, "balancebeam_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+                upstream, metrics.latency.count).unwrap();
+            writeln!(out, "balancebeam_upstream_latency_seconds_sum{{upstream=\"{}\"}} {}",
+                upstream, metrics.latency.sum).unwrap();
+            writeln!(out, "balancebeam_upstream_latency_seconds_count{{upstream=\"{}\"}} {}",
+                upstream, metrics.latency.count).unwrap();
+        }
+
+        counter(&mut out, &upstreams, "balancebeam_upstream_request_bytes_total",
+            "Body bytes sent to each upstream.", |m| m.request_bytes);
+        counter(&mut out, &upstreams, "balancebeam_upstream_response_bytes_total",
+            "Body bytes received from each upstream.", |m| m.response_bytes);
+
+        header(&mut out, "balancebeam_health_checks_total", "counter",
+            "Active health checks of each upstream, by result.");
+        for (upstream, metrics) in upstreams.iter() {
+            writeln!(out, "balancebeam_health_checks_total{{upstream=\"{}\",result=\"pass\"}} {}",
+                escape(upstream), metrics.health_checks_passed).unwrap();
+            writeln!(out, "balancebeam_health_checks_total{{upstream=\"{}\",result=\"fail\"}} {}",
+                escape(upstream), metrics.health_checks_failed).unwrap();
+        }
+
+        counter(&mut out, &upstreams, "balancebeam_upstream_ejections_total",
+            "Times each upstream was ejected after requests to it kept failing.", |m| m.ejections);
+
+        gauge(&mut out, gauges, "balancebeam_upstream_healthy",
+            "Whether each upstream passed its last health check and isn't ejected.", |g| g.healthy as usize);
+        gauge(&mut out, gauges, "balancebeam_upstream_active_connections",
+            "Requests currently in flight to each upstream.", |g| g.active_connections);
+        gauge(&mut out, gauges, "balancebeam_upstream_idle_connections",
+            "Idle pooled connections to each upstream.", |g| g.idle_connections);
+
+        header(&mut out, "balancebeam_client_connections", "gauge",
+            "Client connections currently open.");
+        writeln!(out, "balancebeam_client_connections {}",
+            self.client_connections.load(Ordering::Relaxed)).unwrap();
+        header(&mut out, "balancebeam_rate_limited_total", "counter",
+            "Requests rejected by the rate limiter.");
+        writeln!(out, "balancebeam_rate_limited_total {}",
+            self.rate_limited.load(Ordering::Relaxed)).unwrap();
+        header(&mut out, "balancebeam_retries_total", "counter",
+            "Requests retried on another upstream.");
+        writeln!(out, "balancebeam_retries_total {}",
+            self.retries.load(Ordering::Relaxed)).unwrap();
+        header(&mut out, "balancebeam_retries_denied_total", "counter",
+            "Retries not made because the retry budget was spent.");
+        writeln!(out, "balancebeam_retries_denied_total {}",
+            self.retries_denied.load(Ordering::Relaxed)).unwrap();
+
+        header(&mut out, "balancebeam_cache_requests_total", "counter",
+            "Requests by how the response cache handled them.");
+        for (result, count) in self.cache_requests.lock().iter() {
+            writeln!(out, "balancebeam_cache_requests_total{{result=\"{}\"}} {}", result, count).unwrap();
+        }
+        header(&mut out, "balancebeam_cache_evictions_total", "counter",
+            "Responses evicted from the cache to make room for others.");
+        writeln!(out, "balancebeam_cache_evictions_total {}",
+            self.cache_evictions.load(Ordering::Relaxed)).unwrap();
+        if let Some(cache) = cache {
+            header(&mut out, "balancebeam_cache_entries", "gauge",
+                "Responses currently in the cache.");
+            writeln!(out, "balancebeam_cache_entries {}", cache.entries).unwrap();
+            header(&mut out, "balancebeam_cache_bytes", "gauge",
+                "Size of the responses currently in the cache.");
+            writeln!(out, "balancebeam_cache_bytes {}", cache.bytes).unwrap();
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn counter(out: &mut String, upstreams: &BTreeMap<String, UpstreamMetrics>, name: &str,
+        help: &str, value: impl Fn(&UpstreamMetrics) -> u64) {
+    header(out, name, "counter", help);
+    for (upstream, metrics) in upstreams.iter() {
+        writeln!(out, "{}{{upstream=\"{}\"}} {}", name, escape(upstream), value(metrics)).unwrap();
+    }
+}
+
+fn gauge(out: &mut String, gauges: &[UpstreamGauges], name: &str, help: &str,
+        value: impl Fn(&UpstreamGauges) -> usize) {
+    header(out, name, "gauge", help);
+    for upstream in gauges {
+        writeln!(out, "{}{{upstream=\"{}\"}} {}", name, escape(upstream.address), value(upstream)).unwrap();
+    }
+}
+
+/// Escapes a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,222 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::upstream::{Endpoint, UpstreamStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Settings for the upstream connection pool.
+#[derive(Debug, Clone)]
+pub struct PoolSettings {
+    /// Maximum number of idle connections kept open to each upstream (0 = no pooling)
+    pub max_idle: usize,
+    /// Maximum number of connections (idle or in use) open to each upstream (0 = unlimited)
+    pub max_per_upstream: usize,
+    /// Idle connections older than this are closed instead of being reused
+    pub idle_timeout: Duration,
+    /// Give up on connecting to an upstream after this long
+    pub connect_timeout: Duration,
+}
+
+struct IdleConnection {
+    stream: UpstreamStream,
+    idle_since: Instant,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+/// The pooled connections belonging to a single upstream. These are shared with the pools that
+/// replace this one when the upstreams or configuration change.
+struct UpstreamPool {
+    idle: Mutex<Vec<IdleConnection>>,
+    /// Limits the number of open connections when max_per_upstream is set
+    limit: Option<Arc<Semaphore>>,
+}
+
+impl UpstreamPool {
+    fn new(settings: &PoolSettings) -> UpstreamPool {
+        UpstreamPool {
+            idle: Mutex::new(Vec::new()),
+            limit: if settings.max_per_upstream > 0 {
+                Some(Arc::new(Semaphore::new(settings.max_per_upstream)))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Keeps idle keep-alive connections to each upstream around so that requests can reuse them
+/// instead of paying for a new TCP handshake every time. Upstreams are indexed like
+/// `ProxyState::upstream_addresses`.
+pub struct Pool {
+    settings: PoolSettings,
+    upstreams: Vec<Arc<UpstreamPool>>,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("settings", &self.settings)
+            .field("idle", &self.upstreams.iter().map(|u| u.idle.lock().len()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// A connection checked out of the pool. Hand it back with `Pool::check_in` once the response has
+/// been read; if it is simply dropped, the connection is closed.
+pub struct PooledConnection {
+    pub stream: UpstreamStream,
+    pub idx: usize,
+    /// True if this connection has carried a request before. A reused connection may have been
+    /// closed by the upstream while it sat idle, so a failure on it is worth retrying.
+    pub reused: bool,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Pool {
+    pub fn new(settings: PoolSettings, num_upstreams: usize) -> Pool {
+        let upstreams = (0..num_upstreams)
+            .map(|_| Arc::new(UpstreamPool::new(&settings)))
+            .collect();
+        Pool {
+            settings,
+            upstreams,
+        }
+    }
+
+    /// Builds the pool that replaces this one, for a new list of upstreams. `previous[i]` is the
+    /// index upstream i has in this pool, if it is in it. Those upstreams keep their idle
+    /// connections, and the connections they have open still count against max_per_upstream,
+    /// unless max_per_upstream itself has changed.
+    pub fn rebuild(&self, settings: PoolSettings, previous: &[Option<usize>]) -> Pool {
+        let same_limit = settings.max_per_upstream == self.settings.max_per_upstream;
+        let upstreams = previous
+            .iter()
+            .map(|idx| match idx {
+                Some(idx) if same_limit => Arc::clone(&self.upstreams[*idx]),
+                _ => Arc::new(UpstreamPool::new(&settings)),
+            })
+            .collect();
+        Pool {
+            settings,
+            upstreams,
+        }
+    }
+
+    /// Takes a live idle connection to the given upstream out of the pool, if there is one.
+    pub async fn take_idle(&self, idx: usize) -> Option<PooledConnection> {
+        loop {
+            // Most recently used connections are at the end and the least likely to have timed out
+            let mut conn = self.upstreams[idx].idle.lock().pop()?;
+            if conn.idle_since.elapsed() < self.settings.idle_timeout && is_open(&mut conn.stream).await {
+                return Some(PooledConnection {
+                    stream: conn.stream,
+                    idx,
+                    reused: true,
+                    permit: conn.permit,
+                });
+            }
+        }
+    }
+
+    /// Checks out a connection to the given upstream, reusing an idle one if possible and opening
+    /// a new one otherwise. If the upstream is at max_per_upstream, waits up to connect_timeout for
+    /// a connection to be released.
+    pub async fn check_out(&self, idx: usize, endpoint: &Endpoint) -> std::io::Result<PooledConnection> {
+        if let Some(conn) = self.take_idle(idx).await {
+            return Ok(conn);
+        }
+        self.connect(idx, endpoint).await
+    }
+
+    /// Opens a brand new connection to the given upstream, bypassing any idle connections.
+    pub async fn connect(&self, idx: usize, endpoint: &Endpoint) -> std::io::Result<PooledConnection> {
+        let permit = match &self.upstreams[idx].limit {
+            Some(limit) => {
+                let acquire = Arc::clone(limit).acquire_owned();
+                match tokio::time::timeout(self.settings.connect_timeout, acquire).await {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "timed out waiting for a free connection slot",
+                        ))
+                    }
+                }
+            }
+            None => None,
+        };
+        let stream = endpoint.connect(self.settings.connect_timeout).await?;
+        Ok(PooledConnection {
+            stream,
+            idx,
+            reused: false,
+            permit,
+        })
+    }
+
+    /// Replaces a connection that turned out to be dead with a new one to the same upstream. The
+    /// old connection is closed first, and the new one takes over its slot under
+    /// max_per_upstream, so that the request doesn't end up waiting on its own connection.
+    pub async fn reconnect(&self, conn: PooledConnection, endpoint: &Endpoint)
+            -> std::io::Result<PooledConnection> {
+        let PooledConnection { stream, idx, permit, .. } = conn;
+        drop(stream);
+        let stream = endpoint.connect(self.settings.connect_timeout).await?;
+        Ok(PooledConnection {
+            stream,
+            idx,
+            reused: false,
+            permit,
+        })
+    }
+
+    pub fn settings(&self) -> &PoolSettings {
+        &self.settings
+    }
+
+    /// Number of idle connections currently pooled for the given upstream.
+    pub fn idle_count(&self, idx: usize) -> usize {
+        self.upstreams[idx].idle.lock().len()
+    }
+
+    /// How long idle connections are kept open.
+    pub fn idle_timeout(&self) -> Duration {
+        self.settings.idle_timeout
+    }
+
+    /// Closes every idle connection that has outlived the idle timeout.
+    pub fn evict_expired(&self) {
+        for upstream in self.upstreams.iter() {
+            upstream
+                .idle
+                .lock()
+                .retain(|idle_conn| idle_conn.idle_since.elapsed() < self.settings.idle_timeout);
+        }
+    }
+
+    /// Returns a connection to the pool so that later requests can reuse it. The connection is
+    /// closed instead if the pool for its upstream is already full.
+    pub fn check_in(&self, conn: PooledConnection) {
+        let mut idle = self.upstreams[conn.idx].idle.lock();
+        idle.retain(|idle_conn| idle_conn.idle_since.elapsed() < self.settings.idle_timeout);
+        if idle.len() < self.settings.max_idle {
+            idle.push(IdleConnection {
+                stream: conn.stream,
+                idle_since: Instant::now(),
+                permit: conn.permit,
+            });
+        }
+    }
+}
+
+/// Checks whether an idle connection is still usable without waiting on it. An idle upstream
+/// connection should have nothing to read, so if the socket is readable the upstream has either
+/// hung up or sent something we never asked for; either way, we can't use it.
+async fn is_open(stream: &mut UpstreamStream) -> bool {
+    let mut buf = [0_u8; 1];
+    // The peek is polled once before the zero-length timeout gets a chance to fire. For a TLS
+    // connection this looks at the encrypted bytes, which are just as telling.
+    tokio::time::timeout(Duration::from_secs(0), stream.tcp_mut().peek(&mut buf))
+        .await
+        .is_err()
+}
@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How requests are counted against a rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// Counts requests in consecutive windows, starting with each key's first request. Cheap, but
+    /// a client can get up to twice the limit through by straddling a window boundary.
+    FixedWindow,
+    /// Remembers the time of every request in the last window. Exact, but keeps up to `limit`
+    /// timestamps per key.
+    SlidingWindowLog,
+    /// Estimates the number of requests in the last window from the counts of the current and
+    /// previous fixed windows, weighting the previous one by how much of it is still in range.
+    SlidingWindowCounter,
+    /// Each key has a bucket of `limit` tokens that refills at `limit` tokens per window. Every
+    /// request takes a token, so short bursts are allowed but the long-term rate is capped.
+    TokenBucket,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Result<Algorithm, String> {
+        match name {
+            "fixed-window" => Ok(Algorithm::FixedWindow),
+            "sliding-window-log" => Ok(Algorithm::SlidingWindowLog),
+            "sliding-window-counter" => Ok(Algorithm::SlidingWindowCounter),
+            "token-bucket" => Ok(Algorithm::TokenBucket),
+            _ => Err(format!(
+                "Unknown rate limiting algorithm {:?} (expected fixed-window, sliding-window-log, \
+                sliding-window-counter or token-bucket)",
+                name
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::FixedWindow => "fixed-window",
+            Algorithm::SlidingWindowLog => "sliding-window-log",
+            Algorithm::SlidingWindowCounter => "sliding-window-counter",
+            Algorithm::TokenBucket => "token-bucket",
+        }
+    }
+}
+
+/// A rate limit: at most `limit` requests per `window`, counted using `algorithm`.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    pub algorithm: Algorithm,
+    pub limit: usize,
+    pub window: Duration,
+}
+
+/// What is remembered about a single key. Which variant is used depends on the algorithm.
+#[derive(Debug)]
+enum KeyState {
+    FixedWindow {
+        started: Instant,
+        count: usize,
+    },
+    SlidingWindowLog {
+        requests: VecDeque<Instant>,
+    },
+    SlidingWindowCounter {
+        /// Start of the current fixed window
+        started: Instant,
+        current: usize,
+        previous: usize,
+    },
+    TokenBucket {
+        tokens: f64,
+        updated: Instant,
+    },
+}
+
+impl KeyState {
+    fn new(algorithm: Algorithm, limit: usize, now: Instant) -> KeyState {
+        match algorithm {
+            Algorithm::FixedWindow => KeyState::FixedWindow {
+                started: now,
+                count: 0,
+            },
+            Algorithm::SlidingWindowLog => KeyState::SlidingWindowLog {
+                requests: VecDeque::new(),
+            },
+            Algorithm::SlidingWindowCounter => KeyState::SlidingWindowCounter {
+                started: now,
+                current: 0,
+                previous: 0,
+            },
+            Algorithm::TokenBucket => KeyState::TokenBucket {
+                tokens: limit as f64,
+                updated: now,
+            },
+        }
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            KeyState::FixedWindow { .. } => Algorithm::FixedWindow,
+            KeyState::SlidingWindowLog { .. } => Algorithm::SlidingWindowLog,
+            KeyState::SlidingWindowCounter { .. } => Algorithm::SlidingWindowCounter,
+            KeyState::TokenBucket { .. } => Algorithm::TokenBucket,
+        }
+    }
+
+    /// Brings the state up to date, forgetting whatever has fallen out of the window.
+    fn advance(&mut self, policy: &Policy, now: Instant) {
+        let window = policy.window;
+        match self {
+            KeyState::FixedWindow { started, count } => {
+                if now.duration_since(*started) >= window {
+                    *started = now;
+                    *count = 0;
+                }
+            }
+            KeyState::SlidingWindowLog { requests } => {
+                while let Some(oldest) = requests.front() {
+                    if now.duration_since(*oldest) < window {
+                        break;
+                    }
+                    requests.pop_front();
+                }
+            }
+            KeyState::SlidingWindowCounter {
+                started,
+                current,
+                previous,
+            } => {
+                let windows_passed = now.duration_since(*started).as_nanos() / window.as_nanos().max(1);
+                if windows_passed == 1 {
+                    *previous = *current;
+                } else if windows_passed > 1 {
+                    *previous = 0;
+                }
+                if windows_passed >= 1 {
+                    *current = 0;
+                    *started += window * windows_passed as u32;
+                }
+            }
+            KeyState::TokenBucket { tokens, updated } => {
+                let refill_rate = policy.limit as f64 / window.as_secs_f64();
+                let refilled = now.duration_since(*updated).as_secs_f64() * refill_rate;
+                *tokens = (*tokens + refilled).min(policy.limit as f64);
+                *updated = now;
+            }
+        }
+    }
+
+    /// Number of requests currently counted against the limit. Must be called after advance.
+    fn usage(&self, policy: &Policy, now: Instant) -> f64 {
+        match self {
+            KeyState::FixedWindow { count, .. } => *count as f64,
+            KeyState::SlidingWindowLog { requests } => requests.len() as f64,
+            KeyState::SlidingWindowCounter {
+                started,
+                current,
+                previous,
+            } => {
+                let into_window = now.duration_since(*started).as_secs_f64() / policy.window.as_secs_f64();
+                *previous as f64 * (1.0 - into_window).max(0.0) + *current as f64
+            }
+            KeyState::TokenBucket { tokens, .. } => policy.limit as f64 - tokens,
+        }
+    }
+
+    /// Time until the key may make another request. Must be called after advance.
+    fn retry_after(&self, policy: &Policy, now: Instant) -> Duration {
+        let window = policy.window;
+        let limit = policy.limit as f64;
+        match self {
+            KeyState::FixedWindow { started, count } => {
+                if *count < policy.limit {
+                    Duration::from_secs(0)
+                } else {
+                    window - now.duration_since(*started)
+                }
+            }
+            KeyState::SlidingWindowLog { requests } => {
+                if requests.len() < policy.limit {
+                    return Duration::from_secs(0);
+                }
+                // Wait for enough of the oldest requests to leave the window
+                match requests.get(requests.len() - policy.limit) {
+                    Some(oldest) => window.checked_sub(now.duration_since(*oldest)).unwrap_or_default(),
+                    None => Duration::from_secs(0),
+                }
+            }
+            KeyState::SlidingWindowCounter {
+                started,
+                current,
+                previous,
+            } => {
+                let elapsed = now.duration_since(*started).as_secs_f64();
+                let window = window.as_secs_f64();
+                let current = *current as f64;
+                // Fraction of a window after which the previous window's weighted count, plus
+                // the request being let through, fits under the limit
+                let wait = if current + 1.0 <= limit {
+                    if *previous == 0 {
+                        0.0
+                    } else {
+                        let fraction = 1.0 - (limit - current - 1.0) / *previous as f64;
+                        fraction * window - elapsed
+                    }
+                } else {
+                    // Not until this window is over, and its count has faded enough as the
+                    // previous window
+                    let fraction = 1.0 - (limit - 1.0) / current;
+                    window - elapsed + fraction * window
+                };
+                Duration::from_secs_f64(wait.max(0.0))
+            }
+            KeyState::TokenBucket { tokens, .. } => {
+                let refill_rate = limit / window.as_secs_f64();
+                Duration::from_secs_f64((1.0 - tokens).max(0.0) / refill_rate)
+            }
+        }
+    }
+
+    /// Time until nothing is counted against the key's limit any more. Must be called after
+    /// advance.
+    fn reset(&self, policy: &Policy, now: Instant) -> Duration {
+        let window = policy.window;
+        match self {
+            KeyState::FixedWindow { started, .. } => window - now.duration_since(*started),
+            KeyState::SlidingWindowLog { requests } => match requests.back() {
+                Some(newest) => window.checked_sub(now.duration_since(*newest)).unwrap_or_default(),
+                None => Duration::from_secs(0),
+            },
+            KeyState::SlidingWindowCounter {
+                started,
+                current,
+                previous,
+            } => {
+                let into_window = now.duration_since(*started);
+                if *current > 0 {
+                    window * 2 - into_window
+                } else if *previous > 0 {
+                    window - into_window
+                } else {
+                    Duration::from_secs(0)
+                }
+            }
+            KeyState::TokenBucket { tokens, .. } => {
+                let refill_rate = policy.limit as f64 / window.as_secs_f64();
+                Duration::from_secs_f64((policy.limit as f64 - tokens).max(0.0) / refill_rate)
+            }
+        }
+    }
+
+    fn record(&mut self, now: Instant) {
+        match self {
+            KeyState::FixedWindow { count, .. } => *count += 1,
+            KeyState::SlidingWindowLog { requests } => requests.push_back(now),
+            KeyState::SlidingWindowCounter { current, .. } => *current += 1,
+            KeyState::TokenBucket { tokens, .. } => *tokens -= 1.0,
+        }
+    }
+}
+
+/// The outcome of counting a request against a rate limit, with what the client needs to know to
+/// stay under it.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: usize,
+    /// Requests the key can still make right away
+    pub remaining: usize,
+    /// Time until nothing is counted against the key's limit any more
+    pub reset: Duration,
+    /// Time until the key may make another request
+    pub retry_after: Duration,
+}
+
+/// Rate-limiting state for every key (e.g. client IP) that has made a request recently. Each key
+/// is tracked on its own, under the policy it was last counted against, and keys that no longer
+/// count against their limit are forgotten by evict_expired.
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    keys: HashMap<String, (Policy, KeyState)>,
+}
+
+impl RateLimit {
+    /// Counts a request from `key` if `policy` allows it. A request that is over the limit is
+    /// not counted.
+    pub fn try_acquire(&mut self, key: &str, policy: &Policy, now: Instant) -> Decision {
+        let (key_policy, state) = self
+            .keys
+            .entry(key.to_string())
+            .or_insert_with(|| (*policy, KeyState::new(policy.algorithm, policy.limit, now)));
+        if state.algorithm() != policy.algorithm {
+            // The algorithm was changed by a config reload, so start this key over
+            *state = KeyState::new(policy.algorithm, policy.limit, now);
+        }
+        *key_policy = *policy;
+        state.advance(policy, now);
+        let allowed = state.usage(policy, now) + 1.0 <= policy.limit as f64;
+        if allowed {
+            state.record(now);
+        }
+        let remaining = (policy.limit as f64 - state.usage(policy, now)).max(0.0).floor() as usize;
+        Decision {
+            allowed,
+            limit: policy.limit,
+            remaining,
+            reset: state.reset(policy, now),
+            retry_after: state.retry_after(policy, now),
+        }
+    }
+
+    /// Returns the number of requests currently counted against each key's limit, rounded up.
+    pub fn usage(&mut self, now: Instant) -> BTreeMap<String, usize> {
+        self.keys
+            .iter_mut()
+            .map(|(key, (policy, state))| {
+                state.advance(policy, now);
+                (key.clone(), state.usage(policy, now).ceil() as usize)
+            })
+            .filter(|(_, usage)| *usage > 0)
+            .collect()
+    }
+
+    /// Forgets keys that have nothing counted against their limit any more.
+    pub fn evict_expired(&mut self, now: Instant) {
+        self.keys.retain(|_, (policy, state)| {
+            state.advance(policy, now);
+            state.usage(policy, now) > 0.0
+        });
+    }
+}
+
+/// What a rule counts requests by.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySource {
+    /// Each client IP has its own limit
+    ClientIp,
+    /// Each value of a header (e.g. an API key) has its own limit. Requests without the header
+    /// are counted by client IP.
+    Header(String),
+    /// All requests under the rule's path prefix share one limit
+    PathPrefix,
+}
+
+impl KeySource {
+    pub fn parse(name: &str) -> Result<KeySource, String> {
+        match name {
+            "ip" => Ok(KeySource::ClientIp),
+            "path-prefix" => Ok(KeySource::PathPrefix),
+            _ => match name.strip_prefix("header:") {
+                Some(header) if http::header::HeaderName::from_bytes(header.as_bytes()).is_ok() => {
+                    Ok(KeySource::Header(header.to_lowercase()))
+                }
+                _ => Err(format!(
+                    "Unknown rate limiting key {:?} (expected ip, path-prefix or header:<name>)",
+                    name
+                )),
+            },
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            KeySource::ClientIp => "ip".to_string(),
+            KeySource::Header(header) => format!("header:{}", header),
+            KeySource::PathPrefix => "path-prefix".to_string(),
+        }
+    }
+}
+
+/// A rate limit that applies to requests whose path starts with `path_prefix`.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub path_prefix: String,
+    pub key: KeySource,
+    pub policy: Policy,
+}
+
+impl Rule {
+    /// Whether the rule covers a request path.
+    fn matches(&self, path: &str) -> bool {
+        has_path_prefix(path, &self.path_prefix)
+    }
+
+    /// Returns the key a request is counted under. Keys include the rule's prefix, so the same
+    /// client is counted separately by each rule.
+    pub fn key(&self, client_ip: &str, request: &http::Request<Vec<u8>>) -> String {
+        let header_value = match &self.key {
+            KeySource::Header(header) => request
+                .headers()
+                .get(header)
+                .and_then(|value| value.to_str().ok()),
+            _ => None,
+        };
+        match (&self.key, header_value) {
+            (KeySource::PathPrefix, _) => self.path_prefix.clone(),
+            (KeySource::Header(header), Some(value)) => {
+                format!("{} {}={}", self.path_prefix, header, value)
+            }
+            _ => format!("{} ip={}", self.path_prefix, client_ip),
+        }
+    }
+}
+
+/// Whether a request path falls under a prefix. Prefixes match whole path segments, so `/api`
+/// covers `/api` and `/api/users` but not `/apis`.
+pub fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+/// Returns the rule that applies to a request path: the one with the longest matching prefix,
+/// or the first of those if several are equally long.
+pub fn select<'a>(rules: &'a [Rule], path: &str) -> Option<&'a Rule> {
+    let mut selected: Option<&Rule> = None;
+    for rule in rules.iter().filter(|rule| rule.matches(path)) {
+        if selected.is_none_or(|selected| rule.path_prefix.len() > selected.path_prefix.len()) {
+            selected = Some(rule);
+        }
+    }
+    selected
+}
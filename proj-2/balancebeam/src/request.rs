@@ -1,12 +1,17 @@
+use crate::body::Framing;
+use crate::chunked;
+use crate::timeout::{ReadTimeout, Timeouts};
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
@@ -19,10 +24,29 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The request uses chunked transfer encoding, but the chunks are malformed or incomplete
+    InvalidChunkedEncoding,
+    /// The request uses a Transfer-Encoding other than chunked, so we can't tell where it ends
+    UnsupportedTransferEncoding,
+    /// Client kept the connection open without starting another request
+    IdleTimeout,
+    /// Client started a request, but didn't finish sending it in time
+    RequestTimeout,
+    /// We stopped waiting for the client to start another request (e.g. to shut down)
+    Interrupted,
+    /// Encountered an I/O error when reading/writing the stream
     ConnectionError(std::io::Error),
 }
 
+/// Blames a read that timed out on the client, and any other I/O error on the connection.
+fn read_error(err: std::io::Error) -> Error {
+    if err.kind() == std::io::ErrorKind::TimedOut {
+        Error::RequestTimeout
+    } else {
+        Error::ConnectionError(err)
+    }
+}
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -45,25 +69,30 @@ fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>,
     }
 }
 
-/// This function appends to a header value (adding a new header if the header is not already
-/// present). This is used to add the client's IP address to the end of the X-Forwarded-For list,
-/// or to add a new X-Forwarded-For header if one is not already present.
-///
-/// You won't need to touch this function.
-pub fn extend_header_value(
-    request: &mut http::Request<Vec<u8>>,
-    name: &'static str,
-    extend_value: &str,
-) {
-    let new_value = match request.headers().get(name) {
-        Some(existing_value) => {
-            [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
-        }
-        None => extend_value.as_bytes().to_owned(),
-    };
+/// Returns true if the client asked for the connection to be closed after this request. Like
+/// other hop-by-hop headers, Connection is not forwarded, so upstream connections stay open.
+pub fn wants_close(request: &http::Request<Vec<u8>>) -> bool {
     request
-        .headers_mut()
-        .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
+        .headers()
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("close"))
+}
+
+/// Returns true if sending this request twice has the same effect as sending it once (RFC 7231
+/// section 4.2.2), which makes it safe to retry on another upstream if the first one fails.
+pub fn is_idempotent(request: &http::Request<Vec<u8>>) -> bool {
+    matches!(
+        *request.method(),
+        http::Method::GET
+            | http::Method::HEAD
+            | http::Method::PUT
+            | http::Method::DELETE
+            | http::Method::OPTIONS
+            | http::Method::TRACE
+    )
 }
 
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
@@ -74,10 +103,11 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
@@ -100,55 +130,99 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 ///
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+/// `buffered` holds the start of the request, if the client sent it along with the one before.
+///
+/// With timeouts, the headers must arrive within the client header timeout. On a connection that
+/// is being kept alive, that only starts once the client starts the request, and until then the
+/// client gets the keep-alive timeout. If `interrupt` finishes before the client starts the
+/// request, we stop waiting for it.
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffered: Vec<u8>,
+    timeouts: Option<(&Timeouts, bool)>,
+    interrupt: impl Future<Output = ()>,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    tokio::pin!(interrupt);
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = buffered;
+    let mut bytes_read = request_buffer.len();
+    request_buffer.resize(MAX_HEADERS_SIZE.max(bytes_read), 0);
+    let mut deadline = timeouts.map(|(timeouts, keep_alive)| {
+        Instant::now() + if keep_alive && bytes_read == 0 { timeouts.keep_alive } else { timeouts.client_header }
+    });
     loop {
+        // A request sent along with the one before may already be complete
+        if bytes_read > 0 {
+            if let Some(request) = take_request(&request_buffer[..bytes_read])? {
+                return Ok(request);
+            }
+        }
+
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let read = async {
+            let read = stream.read(&mut request_buffer[bytes_read..]);
+            match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, read).await {
+                    Ok(result) => result.map_err(Error::ConnectionError),
+                    Err(_) if bytes_read == 0 => Err(Error::IdleTimeout),
+                    Err(_) => Err(Error::RequestTimeout),
+                },
+                None => read.await.map_err(Error::ConnectionError),
+            }
+        };
+        let new_bytes = if bytes_read == 0 {
+            tokio::select! {
+                result = read => result?,
+                _ = &mut interrupt => return Err(Error::Interrupted),
+            }
+        } else {
+            read.await?
+        };
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
         }
-        bytes_read += new_bytes;
-
-        // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
-            return Ok(request);
+        if let Some((timeouts, true)) = timeouts {
+            if bytes_read == 0 {
+                deadline = Some(Instant::now() + timeouts.client_header);
+            }
         }
+        bytes_read += new_bytes;
     }
 }
 
+/// Parses the request in `buffer`, if its headers are complete. Whatever follows the headers is
+/// put in the request body.
+fn take_request(buffer: &[u8]) -> Result<Option<http::Request<Vec<u8>>>, Error> {
+    Ok(parse_request(buffer)?.map(|(mut request, headers_len)| {
+        // We've read a complete set of headers. However, if this was a POST request, a request
+        // body might have been included as well, and we might have read part of the body out of
+        // the stream into header_buffer. We need to add those bytes to the Request body so that
+        // we don't lose them
+        request.body_mut().extend_from_slice(&buffer[headers_len..]);
+        request
+    }))
+}
+
 /// This function reads the body for a request from the stream. The client only sends a body if the
 /// Content-Length header is present; this function reads that number of bytes from the stream. It
 /// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
 ///
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
+        // Read up to 512 bytes at a time, and no further than the end of the body, where the next
+        // request on the connection starts
+        let mut buffer = vec![0_u8; min(512, content_length - request.body().len())];
         let bytes_read = stream.read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(read_error)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -161,14 +235,6 @@ async fn read_body(
             return Err(Error::ContentLengthMismatch);
         }
 
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
-        }
-
         // Store the received bytes in the request body
         request.body_mut().extend_from_slice(&buffer[..bytes_read]);
     }
@@ -178,11 +244,30 @@ async fn read_body(
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
 /// closes the connection prematurely or sends an invalid request.
 ///
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream<S: AsyncRead + Unpin>(stream: &mut S) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, Vec::new(), None, std::future::pending()).await?;
+    if request.headers().contains_key("transfer-encoding") {
+        // Transfer-Encoding overrides Content-Length (RFC 7230 section 3.3.3). Chunked is the only
+        // coding that tells us where the body ends, so any other coding is rejected.
+        if !chunked::is_chunked(request.headers()) {
+            return Err(Error::UnsupportedTransferEncoding);
+        }
+        request.headers_mut().remove("content-length");
+        let buffered = std::mem::take(request.body_mut());
+        let (body, trailers) = chunked::read_body(stream, buffered, MAX_BODY_SIZE)
+            .await
+            .map_err(|err| match err {
+                chunked::Error::Incomplete | chunked::Error::Malformed => {
+                    Error::InvalidChunkedEncoding
+                }
+                chunked::Error::TooLarge => Error::RequestBodyTooLarge,
+                chunked::Error::Io(err) => Error::ConnectionError(err),
+            })?;
+        *request.body_mut() = body;
+        request.extensions_mut().insert(trailers);
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
+    } else if let Some(content_length) = get_content_length(&request)? {
         if content_length > MAX_BODY_SIZE {
             return Err(Error::RequestBodyTooLarge);
         } else {
@@ -192,24 +277,90 @@ pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Ve
     Ok(request)
 }
 
+/// This function reads a request from a stream like read_from_stream, except that a body that is
+/// too big to buffer (more than max_buffered_body bytes, or chunked and so of unknown size) is left
+/// in the stream. In that case, the returned Framing says how to stream the rest of the body, and
+/// the request body holds whatever part of it was read along with the headers.
+///
+/// A client may send its next request without waiting for the response to this one (pipelining).
+/// `pipelined` holds whatever of this request was read along with the one before, and is left
+/// holding whatever of the next request was read along with this one. A chunked body that is
+/// streamed may run on into the next request too; body::copy returns what it read past its end.
+///
+/// Gives up on a client that is too slow to send the request, as `timeouts` says. `keep_alive`
+/// is whether the client has sent a request on this connection before. Waiting for the request
+/// to start is cut short with Error::Interrupted if `interrupt` finishes first.
+pub async fn read_head_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pipelined: &mut Vec<u8>,
+    max_buffered_body: usize,
+    timeouts: &Timeouts,
+    keep_alive: bool,
+    interrupt: impl Future<Output = ()>,
+) -> Result<(http::Request<Vec<u8>>, Option<Framing>), Error> {
+    let buffered = std::mem::take(pipelined);
+    let mut request = read_headers(stream, buffered, Some((timeouts, keep_alive)), interrupt).await?;
+    if request.headers().contains_key("transfer-encoding") {
+        // Transfer-Encoding overrides Content-Length (RFC 7230 section 3.3.3). Chunked is the only
+        // coding that tells us where the body ends, so any other coding is rejected.
+        if !chunked::is_chunked(request.headers()) {
+            return Err(Error::UnsupportedTransferEncoding);
+        }
+        request.headers_mut().remove("content-length");
+        return Ok((request, Some(Framing::Chunked)));
+    }
+    // Without Content-Length or Transfer-Encoding, a request has no body, and anything after its
+    // headers belongs to the next request
+    let content_length = get_content_length(&request)?.unwrap_or(0);
+    if request.body().len() > content_length {
+        *pipelined = request.body_mut().split_off(content_length);
+    }
+    if content_length > max_buffered_body {
+        return Ok((request, Some(Framing::Length(content_length))));
+    }
+    let mut stream = ReadTimeout::new(stream, timeouts.client_body);
+    read_body(&mut stream, &mut request, content_length).await?;
+    Ok((request, None))
+}
+
+/// Serializes the request line and headers, followed by the blank line that ends them.
+fn format_head(request: &http::Request<Vec<u8>>) -> Vec<u8> {
+    let mut buffer = format_request_line(request).into_bytes();
+    buffer.extend_from_slice(b"\r\n");
+    for (header_name, header_value) in request.headers() {
+        buffer.extend_from_slice(format!("{}: ", header_name).as_bytes());
+        buffer.extend_from_slice(header_value.as_bytes());
+        buffer.extend_from_slice(b"\r\n");
+    }
+    buffer.extend_from_slice(b"\r\n");
+    buffer
+}
+
+/// This function writes only the request line and headers to the provided stream, so that a body
+/// that is being streamed can follow.
+///
+pub async fn write_head_to_stream<S: AsyncWrite + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    stream.write_all(&format_head(request)).await
+}
+
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    // Assemble the whole message before writing it, so that it goes out in one write instead of
+    // one small packet per header
+    let mut buffer = format_head(request);
+    if chunked::is_chunked(request.headers()) {
+        chunked::encode(request.body(), request.extensions().get(), &mut buffer);
+    } else {
+        buffer.extend_from_slice(request.body());
     }
-    Ok(())
+    stream.write_all(&buffer).await
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
@@ -1,11 +1,13 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::body::Framing;
+use crate::chunked;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request
     IncompleteResponse,
@@ -17,7 +19,9 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The response uses chunked transfer encoding, but the chunks are malformed
+    InvalidChunkedEncoding,
+    /// Encountered an I/O error when reading/writing the stream
     ConnectionError(std::io::Error),
 }
 
@@ -52,12 +56,13 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
     let res = resp
         .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+        .map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -79,18 +84,35 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 ///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(stream: &mut S) -> Result<http::Response<Vec<u8>>, Error> {
+    read_headers_after(stream, Vec::new()).await
+}
+
+/// Like read_headers, but `already_read` holds the first bytes of the response, which were read
+/// from the stream earlier.
+async fn read_headers_after<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    already_read: Vec<u8>,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
     let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut bytes_read = already_read.len().min(MAX_HEADERS_SIZE);
+    response_buffer[..bytes_read].copy_from_slice(&already_read[..bytes_read]);
+    if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
+        response
+            .body_mut()
+            .extend_from_slice(&response_buffer[headers_len..bytes_read]);
+        response.body_mut().extend_from_slice(&already_read[bytes_read..]);
+        return Ok(response);
+    }
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut response_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
@@ -110,10 +132,36 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
     }
 }
 
+/// This function decodes a body sent with chunked transfer encoding, keeping any trailers in the
+/// response's extensions so that they can be passed on to the client.
+///
+async fn read_chunked_body<S: AsyncRead + Unpin>(stream: &mut S, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+    let buffered = std::mem::take(response.body_mut());
+    let (body, trailers) = chunked::read_body(stream, buffered, MAX_BODY_SIZE)
+        .await
+        .map_err(|err| match err {
+            chunked::Error::Incomplete => Error::IncompleteResponse,
+            chunked::Error::Malformed => Error::InvalidChunkedEncoding,
+            chunked::Error::TooLarge => Error::ResponseBodyTooLarge,
+            chunked::Error::Io(err) => Error::ConnectionError(err),
+        })?;
+    *response.body_mut() = body;
+    response.extensions_mut().insert(trailers);
+    Ok(())
+}
+
 /// This function reads the body for a response from the stream. If the Content-Length header is
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
 ///
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+async fn read_body<S: AsyncRead + Unpin>(stream: &mut S, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+    // Transfer-Encoding overrides Content-Length (RFC 7230 section 3.3.3)
+    if response.headers().contains_key("transfer-encoding") {
+        response.headers_mut().remove("content-length");
+        if chunked::is_chunked(response.headers()) {
+            return read_chunked_body(stream, response).await;
+        }
+    }
+
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
@@ -124,7 +172,7 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
         let bytes_read = stream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -157,41 +205,124 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
 /// closes the connection prematurely or sends an invalid response.
 ///
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
 ) -> Result<http::Response<Vec<u8>>, Error> {
     let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
+    if has_body(&response, request_method) {
+        read_body(stream, &mut response).await?;
+    }
+    Ok(response)
+}
+
+/// This function reads a response from a stream like read_from_stream, except that a body that is
+/// too big to buffer (more than max_buffered_body bytes, chunked, or delimited by the server
+/// hanging up) is left in the stream. In that case, the returned Framing says how to stream the
+/// rest of the body, and the response body holds whatever part of it was read along with the
+/// headers. Interim 1xx responses (other than 101 Switching Protocols) are skipped.
+///
+pub async fn read_head_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+    max_buffered_body: usize,
+) -> Result<(http::Response<Vec<u8>>, Option<Framing>), Error> {
+    let mut response = read_headers(stream).await?;
+    while response.status().is_informational()
+        && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+    {
+        log::debug!("Skipping interim response {}", format_response_line(&response));
+        // Anything read past the interim response's headers belongs to the next response
+        let leftover = std::mem::take(response.body_mut());
+        response = read_headers_after(stream, leftover).await?;
+    }
+    if !has_body(&response, request_method) {
+        return Ok((response, None));
+    }
+    if response.headers().contains_key("transfer-encoding") {
+        // Transfer-Encoding overrides Content-Length (RFC 7230 section 3.3.3)
+        response.headers_mut().remove("content-length");
+        if chunked::is_chunked(response.headers()) {
+            return Ok((response, Some(Framing::Chunked)));
+        }
+        return Ok((response, Some(Framing::UntilClose)));
+    }
+    match get_content_length(&response)? {
+        None => Ok((response, Some(Framing::UntilClose))),
+        Some(content_length) if response.body().len() > content_length => {
+            Err(Error::ContentLengthMismatch)
+        }
+        Some(content_length) if content_length > max_buffered_body => {
+            Ok((response, Some(Framing::Length(content_length))))
+        }
+        Some(_) => {
+            read_body(stream, &mut response).await?;
+            Ok((response, None))
+        }
+    }
+}
+
+/// Returns true if the connection a response was read from can carry another request: the server
+/// did not ask to close it, and the end of the body was marked by Content-Length or chunked
+/// encoding rather than by the server hanging up.
+pub fn is_reusable(response: &http::Response<Vec<u8>>, request_method: &http::Method) -> bool {
+    if let Some(connection) = response.headers().get("connection") {
+        if connection.as_bytes().eq_ignore_ascii_case(b"close") {
+            return false;
+        }
+    }
+    !has_body(response, request_method)
+        || chunked::is_chunked(response.headers())
+        || response.headers().contains_key("content-length")
+}
+
+/// A response may have a body as long as it is not responding to a HEAD request and as long as
+/// the response status code is not 1xx, 204 (no content), or 304 (not modified).
+fn has_body(response: &http::Response<Vec<u8>>, request_method: &http::Method) -> bool {
+    !(request_method == http::Method::HEAD
         || response.status().as_u16() < 200
         || response.status() == http::StatusCode::NO_CONTENT
         || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await?;
+}
+
+/// Serializes the status line and headers, followed by the blank line that ends them.
+fn format_head(response: &http::Response<Vec<u8>>) -> Vec<u8> {
+    let mut buffer = format_response_line(response).into_bytes();
+    buffer.extend_from_slice(b"\r\n");
+    for (header_name, header_value) in response.headers() {
+        buffer.extend_from_slice(format!("{}: ", header_name).as_bytes());
+        buffer.extend_from_slice(header_value.as_bytes());
+        buffer.extend_from_slice(b"\r\n");
     }
-    Ok(response)
+    buffer.extend_from_slice(b"\r\n");
+    buffer
+}
+
+/// This function writes only the status line and headers to the provided stream, so that a body
+/// that is being streamed can follow.
+///
+pub async fn write_head_to_stream<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    stream.write_all(&format_head(response)).await
 }
 
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    // Assemble the whole message before writing it, so that it goes out in one write instead of
+    // one small packet per header
+    let mut buffer = format_head(response);
+    if chunked::is_chunked(response.headers()) {
+        chunked::encode(response.body(), response.extensions().get(), &mut buffer);
+    } else {
+        buffer.extend_from_slice(response.body());
     }
-    Ok(())
+    stream.write_all(&buffer).await
 }
 
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
@@ -0,0 +1,102 @@
+use crate::CmdOptions;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How far back the retry budget looks when comparing retries to requests.
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Requests and retries are counted in buckets this long, so that the window slides in steps.
+const BUCKET_LENGTH: Duration = Duration::from_secs(1);
+
+/// When and how often failed requests are retried on another upstream.
+#[derive(Debug, Clone, Copy)]
+pub struct RetrySettings {
+    /// Retries per request, on top of the first attempt
+    pub max_retries: usize,
+    /// Longest wait before the first retry. The limit doubles with every retry after that.
+    pub backoff: Duration,
+    /// Longest wait before any retry
+    pub max_backoff: Duration,
+    /// Retries may add at most this fraction to the requests made within the budget window
+    pub budget_ratio: f64,
+    /// Retries allowed per second on top of the ratio, so that a quiet proxy can still retry
+    pub min_per_second: usize,
+}
+
+impl RetrySettings {
+    pub fn from_options(options: &CmdOptions) -> RetrySettings {
+        RetrySettings {
+            max_retries: options.max_retries,
+            backoff: Duration::from_millis(options.retry_backoff),
+            max_backoff: Duration::from_millis(options.retry_max_backoff.max(options.retry_backoff)),
+            budget_ratio: options.retry_budget as f64 / 100.0,
+            min_per_second: options.retry_budget_min,
+        }
+    }
+
+    /// Returns how long to wait before retry number `retry` (counting from 1): a random time up
+    /// to the backoff limit for that retry ("full jitter"), so that clients that failed together
+    /// don't all retry together.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let doublings = retry.saturating_sub(1).min(31) as u32;
+        let limit = self.backoff.checked_mul(1 << doublings).unwrap_or(self.max_backoff).min(self.max_backoff);
+        limit.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Requests and retries counted in one bucket.
+#[derive(Debug)]
+struct Bucket {
+    started: Instant,
+    requests: usize,
+    retries: usize,
+}
+
+/// Counts requests and retries across the whole proxy over the last BUDGET_WINDOW, and only
+/// allows a retry while retries are a small enough share of the traffic. When an upstream is
+/// down, retrying every failed request would multiply the load on the upstreams that are left;
+/// the budget lets retries paper over the odd failure without that.
+#[derive(Debug, Default)]
+pub struct RetryBudget {
+    buckets: VecDeque<Bucket>,
+}
+
+impl RetryBudget {
+    /// Counts a request (not a retry), which makes room for `budget_ratio` more retries.
+    pub fn record_request(&mut self, now: Instant) {
+        self.bucket(now).requests += 1;
+    }
+
+    /// Counts a retry if the budget has room for it. Returns false if it doesn't.
+    pub fn try_retry(&mut self, settings: &RetrySettings, now: Instant) -> bool {
+        self.bucket(now);
+        let requests: usize = self.buckets.iter().map(|bucket| bucket.requests).sum();
+        let retries: usize = self.buckets.iter().map(|bucket| bucket.retries).sum();
+        let allowed = settings.min_per_second as f64 * BUDGET_WINDOW.as_secs_f64()
+            + settings.budget_ratio * requests as f64;
+        if retries as f64 + 1.0 > allowed {
+            return false;
+        }
+        self.bucket(now).retries += 1;
+        true
+    }
+
+    /// Returns the bucket that `now` falls in, dropping the buckets that have left the window.
+    fn bucket(&mut self, now: Instant) -> &mut Bucket {
+        while let Some(oldest) = self.buckets.front() {
+            if now.duration_since(oldest.started) < BUDGET_WINDOW {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+        let current = self.buckets.back().is_some_and(|bucket| now.duration_since(bucket.started) < BUCKET_LENGTH);
+        if !current {
+            self.buckets.push_back(Bucket {
+                started: now,
+                requests: 0,
+                retries: 0,
+            });
+        }
+        self.buckets.back_mut().unwrap()
+    }
+}
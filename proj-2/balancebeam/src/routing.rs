@@ -0,0 +1,168 @@
+use crate::health_check::HealthCheck;
+use crate::strategy::{self, Strategy};
+use crate::{config, CmdOptions};
+use regex::Regex;
+use std::sync::Arc;
+
+/// Name of the pool made up of the top-level upstreams, which gets every request no route
+/// matches.
+pub const DEFAULT_POOL: &str = "default";
+
+/// A named group of upstreams that requests are balanced across, with its own strategy and
+/// active health checks.
+#[derive(Debug, Clone)]
+pub struct UpstreamPool {
+    pub name: String,
+    /// Indices of the pool's upstreams into ProxyState's upstream lists
+    pub members: Vec<usize>,
+    pub strategy: Arc<dyn Strategy>,
+    /// Name the strategy was built from, so it can be rebuilt when upstreams change
+    pub strategy_name: String,
+    /// Header the consistent-hash strategy hashes on
+    pub hash_header: Option<String>,
+    /// How frequently the pool's upstreams are checked (in seconds)
+    pub health_check_interval: usize,
+    pub health_check: HealthCheck,
+}
+
+impl UpstreamPool {
+    /// Builds a pool of upstreams, balanced and health checked as `options` say. `weights` holds
+    /// the weight of every upstream, not just the pool's.
+    pub fn new(name: &str, members: Vec<usize>, weights: &[usize], options: &CmdOptions,
+            connect_only: bool) -> Result<UpstreamPool, String> {
+        Ok(UpstreamPool {
+            name: name.to_string(),
+            strategy: build_strategy(&options.strategy, &members, weights, options.hash_header.clone())?,
+            members,
+            strategy_name: options.strategy.clone(),
+            hash_header: options.hash_header.clone(),
+            health_check_interval: options.active_health_check_interval,
+            health_check: HealthCheck::from_options(options, connect_only)?,
+        })
+    }
+
+    /// Returns a copy of this pool made up of different upstreams. The strategy is rebuilt, since
+    /// it is indexed by upstream.
+    pub fn with_members(&self, members: Vec<usize>, weights: &[usize]) -> Result<UpstreamPool, String> {
+        Ok(UpstreamPool {
+            strategy: build_strategy(&self.strategy_name, &members, weights, self.hash_header.clone())?,
+            members,
+            ..self.clone()
+        })
+    }
+}
+
+/// Builds a strategy over every upstream, in which only the pool's members carry any weight. The
+/// pool only ever offers its members as candidates, but the weighted and consistent-hash
+/// strategies also need their other upstreams' weights zeroed.
+fn build_strategy(name: &str, members: &[usize], weights: &[usize], hash_header: Option<String>)
+        -> Result<Arc<dyn Strategy>, String> {
+    let weights = weights
+        .iter()
+        .enumerate()
+        .map(|(idx, weight)| if members.contains(&idx) { *weight } else { 0 })
+        .collect();
+    strategy::build(name, weights, hash_header)
+}
+
+/// Sends the requests that match all of its conditions to a pool. A route without conditions
+/// matches every request.
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Lowercase host name, or `*.` followed by a parent domain
+    host: Option<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    method: Option<http::Method>,
+    headers: Vec<(http::header::HeaderName, String)>,
+    /// Index of the pool into ProxyState::pools
+    pub pool: usize,
+}
+
+impl Route {
+    pub fn parse(route: &config::Route, pools: &[UpstreamPool]) -> Result<Route, String> {
+        let pool = pools
+            .iter()
+            .position(|pool| pool.name == route.pool)
+            .ok_or_else(|| format!("Route to unknown pool {:?}", route.pool))?;
+        let headers = route
+            .headers
+            .iter()
+            .flatten()
+            .map(|(name, value)| {
+                let name = http::header::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("Invalid route header {:?}", name))?;
+                Ok((name, value.clone()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Route {
+            host: route.host.as_ref().map(|host| host.to_lowercase()),
+            path_prefix: route.path_prefix.clone(),
+            path_regex: match &route.path_regex {
+                Some(regex) => Some(Regex::new(regex).map_err(|err| {
+                    format!("Invalid route path regex {:?}: {}", regex, err)
+                })?),
+                None => None,
+            },
+            method: match &route.method {
+                Some(method) => Some(method.to_uppercase().parse().map_err(|_| {
+                    format!("Invalid route method {:?}", method)
+                })?),
+                None => None,
+            },
+            headers,
+            pool,
+        })
+    }
+
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        let path = request.uri().path();
+        self.host.as_ref().is_none_or(|host| host_matches(host, request))
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| crate::rate_limiter::has_path_prefix(path, prefix))
+            && self.path_regex.as_ref().is_none_or(|regex| regex.is_match(path))
+            && self.method.as_ref().is_none_or(|method| request.method() == method)
+            && self.headers.iter().all(|(name, expected)| {
+                request
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .any(|value| value.as_bytes() == expected.as_bytes())
+            })
+    }
+}
+
+/// Whether a request's Host header (without its port) is `host`, or a subdomain one level below
+/// the parent domain of a `*.` host.
+fn host_matches(host: &str, request: &http::Request<Vec<u8>>) -> bool {
+    let requested = match request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(requested) => requested.to_lowercase(),
+        None => return false,
+    };
+    let requested = match requested.rfind(':') {
+        // Don't cut an IPv6 address short
+        Some(colon) if !requested[colon..].contains(']') => &requested[..colon],
+        _ => &requested[..],
+    };
+    if requested == host {
+        return true;
+    }
+    match (host.strip_prefix("*."), requested.find('.')) {
+        (Some(parent), Some(dot)) => &requested[dot + 1..] == parent,
+        _ => false,
+    }
+}
+
+/// Returns the pool of the first route that matches a request, if any does.
+pub fn select(routes: &[Route], request: &http::Request<Vec<u8>>) -> Option<usize> {
+    routes
+        .iter()
+        .find(|route| route.matches(request))
+        .map(|route| route.pool)
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+/// Lets balancebeam shut down without cutting off requests that are in flight. Every connection
+/// holds a Shutdown handle, and drain waits for them all to be dropped.
+pub struct Drain {
+    draining: watch::Sender<bool>,
+    handle: Shutdown,
+    finished: mpsc::Receiver<()>,
+}
+
+/// A connection's view of a shutdown: whether one has started, and a way to wait until it does.
+/// The handle counts as in flight for as long as it (or any clone of it) is alive.
+#[derive(Clone)]
+pub struct Shutdown {
+    draining: watch::Receiver<bool>,
+    _in_flight: mpsc::Sender<()>,
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        let (draining, draining_receiver) = watch::channel(false);
+        let (in_flight, finished) = mpsc::channel(1);
+        Drain {
+            draining,
+            handle: Shutdown {
+                draining: draining_receiver,
+                _in_flight: in_flight,
+            },
+            finished,
+        }
+    }
+
+    /// Returns a handle for a new connection to hold.
+    pub fn handle(&self) -> Shutdown {
+        self.handle.clone()
+    }
+
+    /// Tells every connection that we are shutting down, then waits up to `deadline` for them to
+    /// finish. Returns false if some of them were still going at the deadline.
+    pub async fn drain(self, deadline: Duration) -> bool {
+        let Drain { draining, handle, mut finished } = self;
+        drop(handle);
+        // This only fails if nobody is listening, in which case there is nothing to drain
+        let _ = draining.broadcast(true);
+        // Nothing is ever sent, so this returns once every handle has been dropped
+        tokio::time::timeout(deadline, finished.recv()).await.is_ok()
+    }
+}
+
+impl Shutdown {
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Returns once a shutdown has started.
+    pub async fn draining(&mut self) {
+        while !self.is_draining() {
+            if self.draining.recv().await.is_none() {
+                // Drain was dropped without draining, so no shutdown is coming
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Number of points each upstream gets on the consistent hashing ring (multiplied by the
+/// upstream's weight). More points spread keys more evenly at the cost of a bigger ring.
+const VIRTUAL_NODES_PER_UPSTREAM: usize = 100;
+
+/// Everything a strategy might want to know about the client when choosing an upstream.
+pub struct Target<'a> {
+    /// IP address of the client we are picking an upstream for
+    pub client_ip: &'a str,
+    /// The request being forwarded, if one has been read already
+    pub request: Option<&'a http::Request<Vec<u8>>>,
+    /// Number of requests currently in flight to each upstream, indexed like upstream_addresses
+    pub active_connections: &'a [AtomicUsize],
+}
+
+/// A load balancing strategy. Given the indices of the upstreams that are currently considered
+/// healthy, a strategy picks the one that the next request should be sent to.
+pub trait Strategy: std::fmt::Debug + Send + Sync {
+    /// Returns one of `candidates`, or None if `candidates` is empty.
+    fn select(&self, candidates: &[usize], target: &Target) -> Option<usize>;
+}
+
+/// Picks an upstream uniformly at random. This is what balancebeam has always done.
+#[derive(Debug)]
+pub struct Random;
+
+impl Strategy for Random {
+    fn select(&self, candidates: &[usize], _target: &Target) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        Some(candidates[rng.gen_range(0, candidates.len())])
+    }
+}
+
+/// Cycles through the upstreams in order, skipping any that are not candidates.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl Strategy for RoundRobin {
+    fn select(&self, candidates: &[usize], _target: &Target) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(candidates[turn % candidates.len()])
+    }
+}
+
+/// Picks the upstream with the fewest outstanding requests. Ties are broken at random so that
+/// idle upstreams share the load evenly.
+#[derive(Debug)]
+pub struct LeastConnections;
+
+impl Strategy for LeastConnections {
+    fn select(&self, candidates: &[usize], target: &Target) -> Option<usize> {
+        let count = |idx: &usize| target.active_connections[*idx].load(Ordering::Relaxed);
+        let fewest = candidates.iter().map(count).min()?;
+        let tied: Vec<usize> = candidates
+            .iter()
+            .filter(|idx| count(idx) == fewest)
+            .copied()
+            .collect();
+        Random.select(&tied, target)
+    }
+}
+
+/// Picks an upstream at random, with each upstream's chance proportional to its weight.
+#[derive(Debug)]
+pub struct Weighted {
+    weights: Vec<usize>,
+}
+
+impl Strategy for Weighted {
+    fn select(&self, candidates: &[usize], _target: &Target) -> Option<usize> {
+        let total: usize = candidates.iter().map(|idx| self.weights[*idx]).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        let mut point = rng.gen_range(0, total);
+        for idx in candidates {
+            if point < self.weights[*idx] {
+                return Some(*idx);
+            }
+            point -= self.weights[*idx];
+        }
+        None
+    }
+}
+
+/// Maps each client onto a hash ring so that the same client keeps landing on the same upstream.
+/// The key is the client's IP address, or the value of `header` if one is configured and the
+/// request carries it. When an upstream goes away, only the clients that were mapped to it move.
+#[derive(Debug)]
+pub struct ConsistentHash {
+    header: Option<String>,
+    /// (point on the ring, upstream index), sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+impl ConsistentHash {
+    fn new(weights: &[usize], header: Option<String>) -> ConsistentHash {
+        let mut ring = Vec::new();
+        for (idx, weight) in weights.iter().enumerate() {
+            for replica in 0..VIRTUAL_NODES_PER_UPSTREAM * weight {
+                ring.push((hash(&(idx, replica)), idx));
+            }
+        }
+        ring.sort_unstable();
+        ConsistentHash { header, ring }
+    }
+
+    fn key<'a>(&self, target: &Target<'a>) -> &'a [u8] {
+        if let (Some(name), Some(request)) = (&self.header, target.request) {
+            if let Some(value) = request.headers().get(name.as_str()) {
+                return value.as_bytes();
+            }
+        }
+        target.client_ip.as_bytes()
+    }
+}
+
+impl Strategy for ConsistentHash {
+    fn select(&self, candidates: &[usize], target: &Target) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let point = hash(&self.key(target));
+        let start = self.ring.partition_point(|(node, _)| *node < point);
+        // Walk clockwise from the key's position until we hit an upstream we are allowed to use
+        self.ring[start..]
+            .iter()
+            .chain(self.ring[..start].iter())
+            .map(|(_, idx)| *idx)
+            .find(|idx| candidates.contains(idx))
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Builds the strategy named on the command line. `weights` holds one entry per upstream.
+pub fn build(
+    name: &str,
+    weights: Vec<usize>,
+    hash_header: Option<String>,
+) -> Result<Arc<dyn Strategy>, String> {
+    match name {
+        "random" => Ok(Arc::new(Random)),
+        "round-robin" => Ok(Arc::new(RoundRobin::default())),
+        "least-connections" => Ok(Arc::new(LeastConnections)),
+        "weighted" => Ok(Arc::new(Weighted { weights })),
+        "consistent-hash" => Ok(Arc::new(ConsistentHash::new(&weights, hash_header))),
+        _ => Err(format!(
+            "Unknown strategy {:?} (expected random, round-robin, least-connections, weighted or \
+            consistent-hash)",
+            name
+        )),
+    }
+}
+
+/// Splits an upstream given as `host:port=weight` into its address and weight. Upstreams without
+/// an explicit weight get a weight of 1.
+pub fn parse_upstream(upstream: &str) -> Result<(String, usize), String> {
+    match upstream.rfind('=') {
+        Some(pos) => {
+            let weight = upstream[pos + 1..]
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid weight in upstream {:?}", upstream))?;
+            Ok((upstream[..pos].to_string(), weight))
+        }
+        None => Ok((upstream.to_string(), 1)),
+    }
+}
+
+/// Counts one in-flight request to an upstream for as long as it is alive, so that the
+/// least-connections strategy can see how busy each upstream is.
+pub struct ActiveConnection {
+    counts: Arc<Vec<AtomicUsize>>,
+    idx: usize,
+}
+
+impl ActiveConnection {
+    pub fn new(counts: &Arc<Vec<AtomicUsize>>, idx: usize) -> ActiveConnection {
+        counts[idx].fetch_add(1, Ordering::Relaxed);
+        ActiveConnection {
+            counts: Arc::clone(counts),
+            idx,
+        }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.counts[self.idx].fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::CmdOptions;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::time::Delay;
+
+/// How long we wait on clients and upstreams before giving up on them.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// For a client to send the request line and headers, once it has started a request (or
+    /// from when it connected, for its first request). A TLS handshake, and the start of an
+    /// HTTP/2 connection, each get this long too.
+    pub client_header: Duration,
+    /// For a client to send more of a request body
+    pub client_body: Duration,
+    /// For a client to start its next request on a connection it is keeping alive
+    pub keep_alive: Duration,
+    /// For an upstream to start its response, or to send more of a response body
+    pub upstream_read: Duration,
+}
+
+impl Timeouts {
+    pub fn from_options(options: &CmdOptions) -> Timeouts {
+        Timeouts {
+            client_header: Duration::from_secs(options.client_header_timeout.max(1)),
+            client_body: Duration::from_secs(options.client_body_timeout.max(1)),
+            keep_alive: Duration::from_secs(options.keep_alive_timeout.max(1)),
+            upstream_read: Duration::from_secs(options.upstream_read_timeout.max(1)),
+        }
+    }
+}
+
+/// A reader that fails with TimedOut if a read waits longer than `timeout` for data. The timer
+/// restarts with every read, so a slow but steady sender is only cut off if it stalls.
+pub struct ReadTimeout<'a, R> {
+    inner: &'a mut R,
+    timeout: Duration,
+    delay: Option<Delay>,
+}
+
+impl<'a, R> ReadTimeout<'a, R> {
+    pub fn new(inner: &'a mut R, timeout: Duration) -> ReadTimeout<'a, R> {
+        ReadTimeout { inner, timeout, delay: None }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ReadTimeout<'_, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.delay = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                let timeout = this.timeout;
+                let delay = this.delay.get_or_insert_with(|| tokio::time::delay_for(timeout));
+                match Pin::new(delay).poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("nothing received for {:?}", timeout),
+                    ))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+}
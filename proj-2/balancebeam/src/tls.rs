@@ -0,0 +1,184 @@
+use std::fmt;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tls::{TlsAcceptor, TlsStream};
+
+/// The largest TLS record, which bounds how much of a ClientHello we look at.
+const MAX_RECORD_SIZE: usize = 16384 + 5;
+
+/// Terminates TLS on client connections. The certificate is picked by the server name the client
+/// asks for (SNI), falling back to the default one for clients that don't ask for a name or ask
+/// for one we have no certificate for.
+pub struct Tls {
+    default: TlsAcceptor,
+    /// Certificates for particular server names. A name may start with `*.` to cover every
+    /// subdomain one level down.
+    by_name: Vec<(String, TlsAcceptor)>,
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.by_name.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("Tls").field("server_names", &names).finish()
+    }
+}
+
+/// Reads a PEM certificate chain and its PKCS#8 PEM private key.
+fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+    let cert = std::fs::read(cert_path)
+        .map_err(|err| format!("Could not read certificate {}: {}", cert_path, err))?;
+    let key = std::fs::read(key_path)
+        .map_err(|err| format!("Could not read private key {}: {}", key_path, err))?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+        .map_err(|err| format!("Invalid certificate {} or key {}: {}", cert_path, key_path, err))?;
+    // Clients that support HTTP/2 are offered it during the handshake (ALPN)
+    let acceptor = native_tls::TlsAcceptor::builder(identity)
+        .accept_alpn(&["h2", "http/1.1"])
+        .build()
+        .map_err(|err| format!("Could not use certificate {}: {}", cert_path, err))?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+impl Tls {
+    /// Loads the default certificate and the certificate for each of `server_names`, given as
+    /// (name, certificate path, key path). The files are read again every time the config is
+    /// reloaded, so renewed certificates are picked up by sending SIGHUP.
+    pub fn load(
+        cert_path: &str,
+        key_path: &str,
+        server_names: &[(String, String, String)],
+    ) -> Result<Tls, String> {
+        let mut by_name = Vec::new();
+        for (name, cert_path, key_path) in server_names {
+            by_name.push((name.to_lowercase(), load_acceptor(cert_path, key_path)?));
+        }
+        Ok(Tls {
+            default: load_acceptor(cert_path, key_path)?,
+            by_name,
+        })
+    }
+
+    fn acceptor_for(&self, server_name: Option<&str>) -> &TlsAcceptor {
+        let server_name = match server_name {
+            Some(server_name) => server_name.to_lowercase(),
+            None => return &self.default,
+        };
+        let exact = self.by_name.iter().find(|(name, _)| *name == server_name);
+        let wildcard = || {
+            let parent = &server_name[server_name.find('.')? + 1..];
+            self.by_name
+                .iter()
+                .find(|(name, _)| name.strip_prefix("*.") == Some(parent))
+        };
+        match exact.or_else(wildcard) {
+            Some((_, acceptor)) => acceptor,
+            None => &self.default,
+        }
+    }
+
+    /// Performs the TLS handshake on a newly accepted connection.
+    pub async fn accept(&self, mut stream: TcpStream) -> Result<TlsStream<TcpStream>, String> {
+        let server_name = peek_server_name(&mut stream).await;
+        log::debug!("TLS client asked for server name {:?}", server_name);
+        self.acceptor_for(server_name.as_deref())
+            .accept(stream)
+            .await
+            .map_err(|err| format!("TLS handshake failed: {}", err))
+    }
+}
+
+/// Looks at the ClientHello waiting in the stream, without consuming it, and returns the server
+/// name the client asked for.
+async fn peek_server_name(stream: &mut TcpStream) -> Option<String> {
+    let mut buf = vec![0; MAX_RECORD_SIZE];
+    // The ClientHello nearly always arrives in one piece, but give the rest of it a little while
+    // if it doesn't
+    for _ in 0..20 {
+        let len = stream.peek(&mut buf).await.ok()?;
+        if len == 0 {
+            return None;
+        }
+        if len >= 5 {
+            let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            if len >= 5 + record_len || len == buf.len() {
+                return parse_server_name(&buf[..len]);
+            }
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    None
+}
+
+/// Reads big-endian fields off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        Some(self.take(1)?[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+    }
+
+    /// Takes a field preceded by its length, which is `len_size` bytes long.
+    fn vec(&mut self, len_size: usize) -> Option<Reader<'a>> {
+        let len = match len_size {
+            1 => self.u8()?,
+            2 => self.u16()?,
+            3 => {
+                let bytes = self.take(3)?;
+                u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize
+            }
+            _ => return None,
+        };
+        Some(Reader(self.take(len)?))
+    }
+}
+
+/// Finds the server_name extension in a TLS record holding a ClientHello (RFC 8446 section 4.1.2,
+/// RFC 6066 section 3).
+fn parse_server_name(record: &[u8]) -> Option<String> {
+    let mut record = Reader(record);
+    // Content type 22 is a handshake
+    if record.u8()? != 22 {
+        return None;
+    }
+    record.take(2)?; // legacy_record_version
+    let mut handshake = record.vec(2)?;
+    // Handshake type 1 is a ClientHello
+    if handshake.u8()? != 1 {
+        return None;
+    }
+    let mut hello = handshake.vec(3)?;
+    hello.take(2 + 32)?; // legacy_version, random
+    hello.vec(1)?; // legacy_session_id
+    hello.vec(2)?; // cipher_suites
+    hello.vec(1)?; // legacy_compression_methods
+    let mut extensions = hello.vec(2)?;
+    while let Some(extension_type) = extensions.u16() {
+        let mut extension = extensions.vec(2)?;
+        if extension_type != 0 {
+            continue;
+        }
+        let mut names = extension.vec(2)?;
+        while let Some(name_type) = names.u8() {
+            let name = names.vec(2)?;
+            // Name type 0 is a host name
+            if name_type == 0 {
+                return String::from_utf8(name.0.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
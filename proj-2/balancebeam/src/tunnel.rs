@@ -0,0 +1,66 @@
+use parking_lot::Mutex;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the buffer used for each direction of a tunnel.
+const BUFFER_SIZE: usize = 8192;
+
+/// Relays bytes both ways between a client and an upstream once they have switched protocols
+/// (e.g. to WebSocket), until both sides have hung up or nothing has been sent either way for
+/// `idle_timeout`. When one side stops sending, the other side's write half is shut down, so a
+/// half-closed connection keeps working in the other direction. Returns the number of bytes sent
+/// from the client to the upstream and from the upstream to the client.
+pub async fn run<C, U>(client: &mut C, upstream: &mut U, idle_timeout: Duration) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let last_activity = Mutex::new(Instant::now());
+    let relay = async {
+        tokio::try_join!(
+            relay(&mut client_read, &mut upstream_write, &last_activity),
+            relay(&mut upstream_read, &mut client_write, &last_activity),
+        )
+    };
+    tokio::select! {
+        result = relay => result,
+        _ = wait_until_idle(&last_activity, idle_timeout) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("idle for {:?}", idle_timeout),
+        )),
+    }
+}
+
+/// Copies one direction of a tunnel until the sender hangs up.
+async fn relay<R, W>(from: &mut R, to: &mut W, last_activity: &Mutex<Instant>) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = [0_u8; BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let bytes_read = from.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            to.shutdown().await?;
+            return Ok(copied);
+        }
+        to.write_all(&buffer[..bytes_read]).await?;
+        copied += bytes_read as u64;
+        *last_activity.lock() = Instant::now();
+    }
+}
+
+/// Returns once nothing has gone through the tunnel for `idle_timeout`.
+async fn wait_until_idle(last_activity: &Mutex<Instant>, idle_timeout: Duration) {
+    loop {
+        let deadline = *last_activity.lock() + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::delay_until(deadline.into()).await;
+    }
+}
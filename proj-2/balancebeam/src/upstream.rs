@@ -0,0 +1,195 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tls::{TlsConnector, TlsStream};
+
+/// How balancebeam verifies and authenticates itself to `https://` upstreams. The same settings
+/// apply to every upstream.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    connector: TlsConnector,
+}
+
+impl fmt::Debug for UpstreamTls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UpstreamTls").finish()
+    }
+}
+
+impl UpstreamTls {
+    /// Builds the TLS settings for upstreams. Upstream certificates are checked against the CA
+    /// bundle at `ca_path` if one is given, and against the system's trusted roots otherwise. A
+    /// client certificate and key are presented to upstreams that ask for one (mTLS).
+    pub fn new(
+        ca_path: Option<&str>,
+        client_cert_path: Option<&str>,
+        client_key_path: Option<&str>,
+    ) -> Result<UpstreamTls, String> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca_path) = ca_path {
+            let bundle = std::fs::read(ca_path)
+                .map_err(|err| format!("Could not read CA bundle {}: {}", ca_path, err))?;
+            let certs = native_tls::Certificate::stack_from_pem(&bundle)
+                .map_err(|err| format!("Invalid CA bundle {}: {}", ca_path, err))?;
+            for cert in certs {
+                builder.add_root_certificate(cert);
+            }
+            builder.disable_built_in_roots(true);
+        }
+        match (client_cert_path, client_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = std::fs::read(cert_path).map_err(|err| {
+                    format!("Could not read client certificate {}: {}", cert_path, err)
+                })?;
+                let key = std::fs::read(key_path)
+                    .map_err(|err| format!("Could not read client key {}: {}", key_path, err))?;
+                let identity = native_tls::Identity::from_pkcs8(&cert, &key).map_err(|err| {
+                    format!("Invalid client certificate {} or key {}: {}", cert_path, key_path, err)
+                })?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err("--upstream-client-cert and --upstream-client-key have to be given \
+                    together"
+                    .to_string())
+            }
+        }
+        let connector = builder
+            .build()
+            .map_err(|err| format!("Could not set up TLS for upstreams: {}", err))?;
+        Ok(UpstreamTls {
+            connector: TlsConnector::from(connector),
+        })
+    }
+}
+
+/// Where and how to connect to an upstream.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    /// host:port to dial
+    pub address: String,
+    /// For `https://` upstreams, the name to ask for (SNI) and verify the certificate against
+    server_name: Option<String>,
+    tls: Option<UpstreamTls>,
+}
+
+impl Endpoint {
+    /// Parses an upstream given as `host:port`, `http://host:port` or `https://host:port`.
+    /// `https://` upstreams are verified against `server_name` if given, and against their host
+    /// otherwise.
+    pub fn parse(
+        upstream: &str,
+        server_name: Option<&str>,
+        tls: &UpstreamTls,
+    ) -> Result<Endpoint, String> {
+        if let Some(address) = upstream.strip_prefix("https://") {
+            let host = match address.rfind(':') {
+                Some(colon) => &address[..colon],
+                None => return Err(format!("Upstream {:?} needs a port", upstream)),
+            };
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            Ok(Endpoint {
+                address: address.to_string(),
+                server_name: Some(server_name.unwrap_or(host).to_string()),
+                tls: Some(tls.clone()),
+            })
+        } else {
+            Ok(Endpoint {
+                address: upstream.trim_start_matches("http://").to_string(),
+                server_name: None,
+                tls: None,
+            })
+        }
+    }
+
+    /// Whether this is an `https://` upstream.
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Whether a connection to `other` could be used as a connection to this upstream.
+    pub fn same_destination(&self, other: &Endpoint) -> bool {
+        self.address == other.address && self.server_name == other.server_name && self.is_tls() == other.is_tls()
+    }
+
+    /// Opens a connection to the upstream, performing the TLS handshake if it is an `https://`
+    /// upstream. Gives up after `timeout`.
+    pub async fn connect(&self, timeout: Duration) -> io::Result<UpstreamStream> {
+        let connect = async {
+            let stream = TcpStream::connect(&self.address).await?;
+            match (&self.tls, &self.server_name) {
+                (Some(tls), Some(server_name)) => {
+                    let stream = tls.connector.connect(server_name, stream).await.map_err(|err| {
+                        io::Error::other(format!("TLS handshake with {} failed: {}", self.address, err))
+                    })?;
+                    Ok(UpstreamStream::Tls(Box::new(stream)))
+                }
+                _ => Ok(UpstreamStream::Plain(stream)),
+            }
+        };
+        match tokio::time::timeout(timeout, connect).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Timed out connecting to {}", self.address),
+            )),
+        }
+    }
+}
+
+/// A connection to an upstream, encrypted or not.
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl UpstreamStream {
+    /// The TCP connection underneath.
+    pub fn tcp_mut(&mut self) -> &mut TcpStream {
+        match self {
+            UpstreamStream::Plain(stream) => stream,
+            UpstreamStream::Tls(stream) => stream.get_mut(),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
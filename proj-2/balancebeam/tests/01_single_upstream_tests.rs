@@ -1,7 +1,11 @@
 mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -99,3 +103,201 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Pooled upstream connections go stale when the upstream restarts. Make sure balancebeam notices
+/// and opens fresh connections instead of failing the requests that would have reused them.
+#[tokio::test]
+async fn test_pooled_connections_survive_upstream_restart() {
+    let (balancebeam, upstream) = setup().await;
+    let upstream_address = upstream.address.clone();
+
+    log::info!("Sending requests to fill the connection pool");
+    for i in 0..3 {
+        let path = format!("/before-restart-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Restarting the upstream server");
+    Box::new(upstream).stop().await;
+    let upstream = EchoServer::new_at_address(upstream_address).await;
+
+    log::info!("Sending requests that would reuse stale pooled connections");
+    for i in 0..3 {
+        let path = format!("/after-restart-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 3,
+        "Restarted upstream server did not receive the expected number of requests"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Starts a server that answers the first request on each connection and hangs up on the second,
+/// like an upstream that closes keep-alive connections just as they are reused. Returns its address
+/// and the number of requests it has received.
+async fn start_one_request_server() -> (String, Arc<AtomicUsize>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests_clone = Arc::clone(&requests);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let requests = Arc::clone(&requests_clone);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(len) => request.extend_from_slice(&buf[..len]),
+                    }
+                }
+                requests.fetch_add(1, Ordering::SeqCst);
+                let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                if stream.write_all(response.as_bytes()).await.is_err() {
+                    return;
+                }
+                if let Ok(len) = stream.read(&mut buf).await {
+                    if len > 0 {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+        }
+    });
+    (address, requests)
+}
+
+/// A request whose pooled connection turns out to be dead should get a fresh connection, even if
+/// the dead one holds the only connection slot the upstream is allowed
+#[tokio::test]
+async fn test_stale_connection_at_connection_limit() {
+    init_logging();
+    let (upstream, _) = start_one_request_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--pool-max-per-upstream", "1", "--max-retries", "0", "--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    for i in 0..3 {
+        let response_text = tokio::time::timeout(Duration::from_secs(5), balancebeam.get(&format!("/{}", i)))
+            .await
+            .expect("Request got stuck waiting for a connection")
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "ok");
+    }
+    log::info!("All done :)");
+}
+
+/// A request that isn't idempotent may have been acted on before its pooled connection failed, so
+/// it shouldn't be sent again on a fresh one
+#[tokio::test]
+async fn test_stale_connection_does_not_resend_post() {
+    init_logging();
+    let (upstream, requests) = start_one_request_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--max-retries", "0", "--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    assert_eq!(balancebeam.get("/first").await.expect("Error sending request to balancebeam"), "ok");
+    let response = reqwest::Client::new()
+        .post(&format!("http://{}/second", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(requests.load(Ordering::SeqCst), 2, "The POST was sent more than once");
+    log::info!("All done :)");
+}
+
+/// Starts a server that answers each request with "slow" after `delay`, then hangs up. Returns its
+/// address and the most connections it ever had open at once.
+async fn start_slow_server(delay: Duration) -> (String, Arc<AtomicUsize>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let open = Arc::new(AtomicUsize::new(0));
+    let most_open = Arc::new(AtomicUsize::new(0));
+    let most_open_clone = Arc::clone(&most_open);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let now_open = open.fetch_add(1, Ordering::SeqCst) + 1;
+            most_open_clone.fetch_max(now_open, Ordering::SeqCst);
+            let open = Arc::clone(&open);
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                let mut request = Vec::new();
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(len) => request.extend_from_slice(&buf[..len]),
+                    }
+                }
+                tokio::time::delay_for(delay).await;
+                let response = "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nslow";
+                let _ = stream.write_all(response.as_bytes()).await;
+                drop(stream);
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    (address, most_open)
+}
+
+/// Connections opened before the upstreams are changed through the admin API should still count
+/// against max_per_upstream afterwards
+#[tokio::test]
+async fn test_connection_limit_survives_upstream_changes() {
+    init_logging();
+    let (upstream, most_open) = start_slow_server(Duration::from_secs(1)).await;
+    let other_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--pool-max-per-upstream", "1", "--admin-bind", "127.0.0.1:0", "--active-health-check-interval", "3600"],
+    )
+    .await;
+    let admin = format!("http://{}", balancebeam.admin_address.as_ref().unwrap());
+
+    let url = format!("http://{}/first", balancebeam.address);
+    let first = tokio::spawn(async move { reqwest::get(&url).await });
+    tokio::time::delay_for(Duration::from_millis(300)).await;
+    let client = reqwest::Client::new();
+    let added = client
+        .post(&format!("{}/upstreams", admin))
+        .body(format!("\"{}\"", other_upstream.address))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(added.status().as_u16(), 201);
+    let drained = client
+        .post(&format!("{}/upstreams/{}/drain", admin, other_upstream.address))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(drained.status().as_u16(), 200);
+
+    assert_eq!(balancebeam.get("/second").await.expect("Error sending request to balancebeam"), "slow");
+    let first = first.await.unwrap().expect("Error sending request to balancebeam");
+    assert_eq!(first.text().await.unwrap(), "slow");
+    assert_eq!(most_open.load(Ordering::SeqCst), 1, "max_per_upstream was exceeded");
+
+    let num_requests_received = Box::new(other_upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+    log::info!("All done :)");
+}
@@ -123,6 +123,31 @@ async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Ser
     }
 }
 
+/// When every upstream is down, balancebeam should answer straight away with a 503 that says when
+/// to try again, rather than hanging
+#[tokio::test]
+async fn test_all_upstreams_down() {
+    let n_upstreams = 2;
+    let (balancebeam, mut upstreams) = setup_with_params(n_upstreams, Some(4), None).await;
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        let start = Instant::now();
+        let response = client
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 503);
+        assert_eq!(response.headers()["retry-after"], "4");
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+    log::info!("All done :)");
+}
+
 /// Make sure passive health checks work. Send a few requests, then kill one of the upstreams and
 /// make sure requests continue to work
 #[tokio::test]
@@ -0,0 +1,191 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+async fn setup_with_strategy(
+    n_upstreams: usize,
+    weights: Option<&[usize]>,
+    extra_args: &[&str],
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_addresses: Vec<String> = upstreams
+        .iter()
+        .enumerate()
+        .map(|(i, upstream)| match weights {
+            Some(weights) => format!("{}={}", upstream.address(), weights[i]),
+            None => upstream.address(),
+        })
+        .collect();
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    // Keep active health checks out of the way so that they don't show up in the request counts
+    let mut args = vec!["--active-health-check-interval", "3600"];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(&upstream_addresses, &args).await;
+    (balancebeam, upstreams)
+}
+
+async fn stop_all(mut upstreams: Vec<Box<dyn Server>>) -> Vec<usize> {
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
+
+/// Round-robin should hand out requests in strict rotation, so every upstream gets exactly the
+/// same number of requests
+#[tokio::test]
+async fn test_round_robin() {
+    let n_upstreams = 3;
+    let n_requests = 30;
+    let (balancebeam, upstreams) =
+        setup_with_strategy(n_upstreams, None, &["--strategy", "round-robin"]).await;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![n_requests / n_upstreams; n_upstreams]);
+
+    log::info!("All done :)");
+}
+
+/// With weights of 3 and 1, the first upstream should get roughly three quarters of the traffic
+#[tokio::test]
+async fn test_weighted() {
+    let n_requests = 100;
+    let (balancebeam, upstreams) =
+        setup_with_strategy(2, Some(&[3, 1]), &["--strategy", "weighted"]).await;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters.iter().sum::<usize>(), n_requests);
+    assert!(
+        request_counters[0] > 2 * request_counters[1],
+        "Heavier upstream did not get noticeably more requests"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Consistent hashing on a header should send every request with the same header value to the
+/// same upstream
+#[tokio::test]
+async fn test_consistent_hash_on_header() {
+    let n_upstreams = 3;
+    let n_requests = 20;
+    let (balancebeam, upstreams) = setup_with_strategy(
+        n_upstreams,
+        None,
+        &["--strategy", "consistent-hash", "--hash-header", "x-session"],
+    )
+    .await;
+    for i in 0..n_requests {
+        let response_text = reqwest::Client::new()
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .header("x-session", "sticky-session-id")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET /request-{} HTTP/1.1", i)));
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert!(
+        request_counters.contains(&n_requests),
+        "Requests with the same hash key were spread across upstreams"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Requests sent over a single keep-alive connection should still be balanced one by one rather
+/// than all being pinned to whichever upstream the connection started on
+#[tokio::test]
+async fn test_keep_alive_requests_are_balanced() {
+    let n_upstreams = 3;
+    let n_requests = 30;
+    let (balancebeam, upstreams) =
+        setup_with_strategy(n_upstreams, None, &["--strategy", "round-robin"]).await;
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let request_counters = stop_all(upstreams).await;
+    assert_eq!(request_counters, vec![n_requests / n_upstreams; n_upstreams]);
+
+    log::info!("All done :)");
+}
+
+/// A long-lived client connection should fail over to the remaining upstream when the one it was
+/// talking to goes away
+#[tokio::test]
+async fn test_keep_alive_failover() {
+    let (balancebeam, mut upstreams) =
+        setup_with_strategy(2, None, &["--strategy", "round-robin"]).await;
+    let client = reqwest::Client::new();
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Killing one of the upstream servers");
+    upstreams.pop().unwrap().stop().await;
+
+    for i in 0..6 {
+        let path = format!("/failover-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "balancebeam returned unexpected response. Failover may not be working."
+        );
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,238 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::delay_for;
+
+async fn setup() -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    (balancebeam, upstream)
+}
+
+/// Reads a single response with a Content-Length body off of a raw connection, returning the
+/// response head and body as text.
+async fn read_response(conn: &mut TcpStream) -> (String, String) {
+    let mut buffer = Vec::new();
+    let head_len = loop {
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let mut chunk = [0_u8; 512];
+        let bytes_read = conn.read(&mut chunk).await.expect("Error reading response");
+        assert!(bytes_read > 0, "balancebeam hung up before sending a response");
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    };
+    let head = String::from_utf8_lossy(&buffer[..head_len]).to_lowercase();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length: "))
+        .expect("Response has no Content-Length")
+        .trim()
+        .parse()
+        .unwrap();
+    while buffer.len() < head_len + content_length {
+        let mut chunk = [0_u8; 512];
+        let bytes_read = conn.read(&mut chunk).await.expect("Error reading response");
+        assert!(bytes_read > 0, "balancebeam hung up in the middle of a response");
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    }
+    let body = String::from_utf8_lossy(&buffer[head_len..]).to_string();
+    (head, body)
+}
+
+/// Send a request with a chunked body, followed by a second request on the same connection. The
+/// chunked body should arrive at the upstream intact and the connection should stay usable.
+#[tokio::test]
+async fn test_chunked_request() {
+    let (balancebeam, upstream) = setup().await;
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+
+    log::info!("Sending a request with a chunked body");
+    conn.write_all(
+        b"POST /chunked-upload HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        5\r\nHello\r\n\
+        7;note=ext\r\n world!\r\n\
+        0\r\n\
+        \r\n",
+    )
+    .await
+    .unwrap();
+    let (head, body) = read_response(&mut conn).await;
+    assert!(head.starts_with("http/1.1 200"));
+    assert!(body.contains("POST /chunked-upload HTTP/1.1"));
+    assert!(body.ends_with("\n\nHello world!"));
+
+    log::info!("Sending a second request on the same connection");
+    conn.write_all(b"GET /after-chunked HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .unwrap();
+    let (_, body) = read_response(&mut conn).await;
+    assert!(body.contains("GET /after-chunked HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// Chunked responses from the upstream should be passed along in full, without breaking
+/// keep-alive for the requests that follow
+#[tokio::test]
+async fn test_chunked_response() {
+    let (balancebeam, upstream) = setup().await;
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        let path = format!("/chunked-response-{}", i);
+        let response_text = client
+            .post(&format!("http://{}{}", balancebeam.address, path))
+            .body("Hello world!")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("POST {} HTTP/1.1", path)));
+        assert!(response_text.ends_with("\n\nHello world!"));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// Send several requests at once, without waiting for responses (pipelining). Each request should
+/// be answered in turn, however much of the next one was read along with the one before it.
+#[tokio::test]
+async fn test_pipelined_requests() {
+    let (balancebeam, upstream) = setup().await;
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+
+    log::info!("Sending four requests in one go");
+    conn.write_all(
+        b"POST /first HTTP/1.1\r\nHost: balancebeam\r\nContent-Length: 5\r\n\r\nfirst\
+        POST /second HTTP/1.1\r\nHost: balancebeam\r\nTransfer-Encoding: chunked\r\n\r\n\
+        6\r\nsecond\r\n0\r\n\r\n\
+        GET /third HTTP/1.1\r\nHost: balancebeam\r\n\r\n\
+        GET /fourth HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    for (path, body) in [("/first", "first"), ("/second", "second"), ("/third", ""), ("/fourth", "")].iter() {
+        let (head, echoed) = read_response(&mut conn).await;
+        assert!(head.starts_with("http/1.1 200"), "Request for {} failed: {}", path, head);
+        assert!(echoed.contains(&format!(" {} HTTP/1.1", path)), "Wrong response for {}: {}", path, echoed);
+        assert!(echoed.ends_with(&format!("\n\n{}", body)), "Wrong body for {}: {}", path, echoed);
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+    log::info!("All done :)");
+}
+
+/// Bodies bigger than the old 10 MB limit should be streamed through in both directions, and the
+/// connections should stay usable afterwards
+#[tokio::test]
+async fn test_large_bodies_are_streamed() {
+    let (balancebeam, upstream) = setup().await;
+    let client = reqwest::Client::new();
+    let upload: Vec<u8> = (0..12_000_000).map(|i| (i % 251) as u8).collect();
+    for i in 0..2 {
+        let path = format!("/large-upload-{}", i);
+        let response_body = client
+            .post(&format!("http://{}{}", balancebeam.address, path))
+            .body(upload.clone())
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .bytes()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        let head_len = response_body
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .expect("Echoed request has no blank line")
+            + 2;
+        assert!(String::from_utf8_lossy(&response_body[..head_len])
+            .contains(&format!("POST {} HTTP/1.1", path)));
+        assert!(response_body[head_len..] == upload[..]);
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// Starts a server that sends the first part of a response body straight away and the rest after
+/// a pause: `/chunked` as one small chunk of a chunked body, and anything else as the start of a
+/// body that ends when the server hangs up.
+async fn start_trickling_server() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(len) => request.extend_from_slice(&buf[..len]),
+                    }
+                }
+                let (first, rest) = if request.starts_with(b"GET /chunked ") {
+                    ("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n", "4\r\nrest\r\n0\r\n\r\n")
+                } else {
+                    ("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nfirst", "rest")
+                };
+                let _ = stream.write_all(first.as_bytes()).await;
+                delay_for(Duration::from_secs(3)).await;
+                let _ = stream.write_all(rest.as_bytes()).await;
+            });
+        }
+    });
+    address
+}
+
+/// A streamed body should reach the client as it arrives, rather than once a buffer's worth has
+/// built up or the body has ended
+#[tokio::test]
+async fn test_streamed_bodies_are_not_held_back() {
+    init_logging();
+    let upstream = start_trickling_server().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "3600"]).await;
+
+    for path in ["/chunked", "/until-close"].iter() {
+        let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+        conn.write_all(format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        let read_first_part = async {
+            while !String::from_utf8_lossy(&received).contains("first") {
+                let mut chunk = [0_u8; 512];
+                let bytes_read = conn.read(&mut chunk).await.expect("Error reading response");
+                assert!(bytes_read > 0, "balancebeam hung up in the middle of a response");
+                received.extend_from_slice(&chunk[..bytes_read]);
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), read_first_part)
+            .await
+            .unwrap_or_else(|_| panic!("The start of the {} body was held back", path));
+    }
+    log::info!("All done :)");
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+
+/// Returns a path in the temp directory that no other test is using.
+fn config_path(extension: &str) -> std::path::PathBuf {
+    let mut rng = rand::thread_rng();
+    std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.{}",
+        rng.gen_range(0, u64::MAX),
+        extension
+    ))
+}
+
+async fn stop_all(mut upstreams: Vec<Box<dyn Server>>) -> Vec<usize> {
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
+
+/// Upstreams and settings should be read from a TOML config file when none are given on the
+/// command line
+#[tokio::test]
+async fn test_toml_config() {
+    init_logging();
+    let upstreams: Vec<Box<dyn Server>> = vec![
+        Box::new(EchoServer::new().await),
+        Box::new(EchoServer::new().await),
+    ];
+    let path = config_path("toml");
+    std::fs::write(
+        &path,
+        format!(
+            "strategy = \"round-robin\"\n\
+            upstreams = [\"{}\", \"{}\"]\n\
+            \n\
+            [health_check]\n\
+            interval = 3600\n",
+            upstreams[0].address(),
+            upstreams[1].address()
+        ),
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    assert_eq!(stop_all(upstreams).await, vec![5, 5]);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Rewriting a YAML config file and sending SIGHUP should move traffic to the new upstreams,
+/// without breaking keep-alive connections that were already open
+#[tokio::test]
+async fn test_reload_on_sighup() {
+    init_logging();
+    let upstreams: Vec<Box<dyn Server>> = vec![
+        Box::new(EchoServer::new().await),
+        Box::new(EchoServer::new().await),
+    ];
+    let write_config = |path: &std::path::Path, upstream: &str| {
+        std::fs::write(
+            path,
+            format!(
+                "upstreams:\n  - address: \"{}\"\n    weight: 1\nhealth_check:\n  interval: 3600\n",
+                upstream
+            ),
+        )
+        .unwrap();
+    };
+    let path = config_path("yaml");
+    write_config(&path, &upstreams[0].address());
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+
+    // Use a single client so that its keep-alive connection spans the reload
+    let client = reqwest::Client::new();
+    let send_requests = |n: usize| {
+        let client = client.clone();
+        let address = balancebeam.address.clone();
+        async move {
+            for i in 0..n {
+                let path = format!("/request-{}", i);
+                let response_text = client
+                    .get(&format!("http://{}{}", address, path))
+                    .send()
+                    .await
+                    .expect("Error sending request to balancebeam")
+                    .text()
+                    .await
+                    .expect("Balancebeam replied with a malformed response");
+                assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+            }
+        }
+    };
+
+    log::info!("Sending requests to the first upstream");
+    send_requests(3).await;
+    log::info!("Switching to the second upstream");
+    write_config(&path, &upstreams[1].address());
+    balancebeam.reload().await;
+    send_requests(4).await;
+
+    log::info!("Reloading an invalid config should keep the current one");
+    std::fs::write(&path, "upstreams: [\n").unwrap();
+    balancebeam.reload().await;
+    send_requests(2).await;
+
+    assert_eq!(stop_all(upstreams).await, vec![3, 6]);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
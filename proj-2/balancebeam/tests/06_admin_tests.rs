@@ -0,0 +1,271 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+struct AdminClient {
+    address: String,
+    client: reqwest::Client,
+}
+
+impl AdminClient {
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: &str,
+    ) -> (reqwest::StatusCode, serde_json::Value) {
+        let response = self
+            .client
+            .request(method, &format!("http://{}{}", self.address, path))
+            .body(body.to_string())
+            .send()
+            .await
+            .expect("Error sending request to the admin API");
+        let status = response.status();
+        let text = response.text().await.unwrap();
+        let json = serde_json::from_str(&text)
+            .unwrap_or_else(|_| panic!("Admin API replied with invalid JSON: {}", text));
+        (status, json)
+    }
+
+    async fn status(&self) -> serde_json::Value {
+        let (status, json) = self.send(reqwest::Method::GET, "/status", "").await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        json
+    }
+}
+
+async fn setup(n_upstreams: usize) -> (BalanceBeam, AdminClient, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_addresses: Vec<String> = upstreams.iter().map(|u| u.address()).collect();
+    let upstream_addresses: Vec<&str> = upstream_addresses.iter().map(|a| a.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--admin-bind",
+            "127.0.0.1:0",
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    let admin = AdminClient {
+        address: balancebeam.admin_address.clone().unwrap(),
+        client: reqwest::Client::new(),
+    };
+    (balancebeam, admin, upstreams)
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, n: usize) {
+    for i in 0..n {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+async fn stop_all(mut upstreams: Vec<Box<dyn Server>>) -> Vec<usize> {
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
+
+/// The status endpoint should describe every upstream
+#[tokio::test]
+async fn test_status() {
+    let (balancebeam, admin, upstreams) = setup(2).await;
+    send_requests(&balancebeam, 2).await;
+
+    let status = admin.status().await;
+    assert_eq!(status["strategy"], "round-robin");
+    let listed = status["upstreams"].as_array().unwrap();
+    assert_eq!(listed.len(), 2);
+    for (listed, upstream) in listed.iter().zip(upstreams.iter()) {
+        assert_eq!(listed["address"], upstream.address());
+        assert_eq!(listed["weight"], 1);
+        assert_eq!(listed["healthy"], true);
+        assert_eq!(listed["draining"], false);
+        assert_eq!(listed["active_connections"], 0);
+    }
+
+    let (status, _) = admin.send(reqwest::Method::GET, "/nope", "").await;
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+    let (status, _) = admin.send(reqwest::Method::DELETE, "/status", "").await;
+    assert_eq!(status, reqwest::StatusCode::METHOD_NOT_ALLOWED);
+
+    stop_all(upstreams).await;
+    log::info!("All done :)");
+}
+
+/// A drained upstream should get no new requests until it is re-enabled
+#[tokio::test]
+async fn test_drain_and_enable() {
+    let (balancebeam, admin, upstreams) = setup(2).await;
+    let drained = upstreams[0].address();
+
+    let (status, json) = admin
+        .send(
+            reqwest::Method::POST,
+            &format!("/upstreams/{}/drain", drained),
+            "",
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert_eq!(json["upstreams"][0]["draining"], true);
+    send_requests(&balancebeam, 4).await;
+
+    let (status, json) = admin
+        .send(
+            reqwest::Method::POST,
+            &format!("/upstreams/{}/enable", drained),
+            "",
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert_eq!(json["upstreams"][0]["draining"], false);
+    send_requests(&balancebeam, 4).await;
+
+    let (status, _) = admin
+        .send(reqwest::Method::POST, "/upstreams/127.0.0.1:1/drain", "")
+        .await;
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+
+    assert_eq!(stop_all(upstreams).await, vec![2, 6]);
+    log::info!("All done :)");
+}
+
+/// Upstreams added through the admin API should start receiving requests right away, and removed
+/// ones should stop
+#[tokio::test]
+async fn test_add_and_remove_upstreams() {
+    let (balancebeam, admin, mut upstreams) = setup(1).await;
+    let added: Box<dyn Server> = Box::new(EchoServer::new().await);
+
+    let (status, json) = admin
+        .send(
+            reqwest::Method::POST,
+            "/upstreams",
+            &format!("{{\"address\": \"{}\", \"weight\": 2}}", added.address()),
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::CREATED);
+    assert_eq!(json["upstreams"][1]["address"], added.address());
+    assert_eq!(json["upstreams"][1]["weight"], 2);
+    let (status, _) = admin
+        .send(
+            reqwest::Method::POST,
+            "/upstreams",
+            &format!("\"{}\"", added.address()),
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::CONFLICT);
+    send_requests(&balancebeam, 4).await;
+
+    let original = upstreams[0].address();
+    let (status, json) = admin
+        .send(
+            reqwest::Method::DELETE,
+            &format!("/upstreams/{}", original),
+            "",
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::OK);
+    assert_eq!(json["upstreams"].as_array().unwrap().len(), 1);
+    send_requests(&balancebeam, 2).await;
+
+    let (status, _) = admin
+        .send(
+            reqwest::Method::DELETE,
+            &format!("/upstreams/{}", added.address()),
+            "",
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::CONFLICT);
+
+    upstreams.push(added);
+    assert_eq!(stop_all(upstreams).await, vec![2, 4]);
+    log::info!("All done :)");
+}
+
+/// The metrics endpoint should count requests, bytes and health checks for each upstream
+#[tokio::test]
+async fn test_metrics() {
+    let (balancebeam, admin, upstreams) = setup(1).await;
+    let upstream = upstreams[0].address();
+    send_requests(&balancebeam, 3).await;
+    balancebeam
+        .post("/upload", "Hello world!")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let response = admin
+        .client
+        .get(&format!("http://{}/metrics", admin.address))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let metrics = response.text().await.unwrap();
+    log::info!("Metrics:\n{}", metrics);
+    let value = |series: &str| -> f64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{} ", series)))
+            .unwrap_or_else(|| panic!("Missing series {}", series))
+            .parse()
+            .unwrap()
+    };
+    assert_eq!(
+        value(&format!(
+            "balancebeam_upstream_responses_total{{upstream=\"{}\",code=\"200\"}}",
+            upstream
+        )),
+        4.0
+    );
+    assert_eq!(
+        value(&format!(
+            "balancebeam_upstream_latency_seconds_count{{upstream=\"{}\"}}",
+            upstream
+        )),
+        4.0
+    );
+    assert_eq!(
+        value(&format!(
+            "balancebeam_upstream_request_bytes_total{{upstream=\"{}\"}}",
+            upstream
+        )),
+        12.0
+    );
+    assert!(
+        value(&format!(
+            "balancebeam_upstream_response_bytes_total{{upstream=\"{}\"}}",
+            upstream
+        )) > 0.0
+    );
+    assert_eq!(
+        value(&format!(
+            "balancebeam_upstream_healthy{{upstream=\"{}\"}}",
+            upstream
+        )),
+        1.0
+    );
+    assert_eq!(value("balancebeam_rate_limited_total"), 0.0);
+
+    stop_all(upstreams).await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+async fn setup(algorithm: &str, limit: usize, window: u64) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let limit = limit.to_string();
+    let window = window.to_string();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            &limit,
+            "--rate-limit-algorithm",
+            algorithm,
+            "--rate-limit-window",
+            &window,
+        ],
+    )
+    .await;
+    (balancebeam, upstream)
+}
+
+/// Sends `n` requests, each on a new connection, and returns how many were let through.
+async fn send_requests(balancebeam: &BalanceBeam, n: usize) -> usize {
+    // Building a client is slow enough to throw the timing off, so build one that doesn't keep
+    // connections open instead of building one per request
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(0)
+        .build()
+        .unwrap();
+    let mut allowed = 0;
+    for i in 0..n {
+        let response = client
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        match response.status().as_u16() {
+            200 => allowed += 1,
+            429 => {}
+            status => panic!("Unexpected status {}", status),
+        }
+    }
+    allowed
+}
+
+/// The sliding window log shouldn't let a client double its rate by bunching requests up on
+/// either side of a window boundary
+#[tokio::test]
+async fn test_sliding_window_log() {
+    let (balancebeam, upstream) = setup("sliding-window-log", 3, 2).await;
+    assert_eq!(send_requests(&balancebeam, 1).await, 1);
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(send_requests(&balancebeam, 3).await, 2);
+    // The first request has left the window, but the other two are still in it
+    delay_for(Duration::from_millis(700)).await;
+    assert_eq!(send_requests(&balancebeam, 3).await, 1);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+    log::info!("All done :)");
+}
+
+/// The sliding window counter should keep counting the previous window's requests for a while
+/// after the window rolls over
+#[tokio::test]
+async fn test_sliding_window_counter() {
+    let (balancebeam, upstream) = setup("sliding-window-counter", 3, 2).await;
+    assert_eq!(send_requests(&balancebeam, 4).await, 3);
+    // Just past the boundary, nearly all of the previous window still counts
+    delay_for(Duration::from_millis(2200)).await;
+    assert_eq!(send_requests(&balancebeam, 1).await, 0);
+    // Two windows later, the earlier requests no longer count at all
+    delay_for(Duration::from_millis(2000)).await;
+    assert_eq!(send_requests(&balancebeam, 4).await, 3);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 6);
+    log::info!("All done :)");
+}
+
+/// The token bucket should allow a burst up to the limit and then refill gradually
+#[tokio::test]
+async fn test_token_bucket() {
+    let (balancebeam, upstream) = setup("token-bucket", 4, 2).await;
+    assert_eq!(send_requests(&balancebeam, 5).await, 4);
+    // Tokens come back at 2 per second
+    delay_for(Duration::from_millis(1100)).await;
+    assert_eq!(send_requests(&balancebeam, 3).await, 2);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 6);
+    log::info!("All done :)");
+}
+
+/// Requests on a keep-alive connection should each count against the limit, and the 429 should
+/// say when to come back
+#[tokio::test]
+async fn test_keep_alive_requests_are_counted() {
+    let (balancebeam, upstream) = setup("fixed-window", 3, 60).await;
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    let mut rejected = None;
+    for i in 0..5 {
+        let response = client
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+        if response.status().as_u16() == 429 {
+            rejected = Some(response);
+        }
+    }
+    assert_eq!(statuses, vec![200, 200, 200, 429, 429]);
+
+    let rejected = rejected.unwrap();
+    let header = |name: &str| -> u64 {
+        rejected.headers()[name].to_str().unwrap().parse().unwrap()
+    };
+    assert!((1..=60).contains(&header("retry-after")));
+    assert_eq!(header("ratelimit-limit"), 3);
+    assert_eq!(header("ratelimit-remaining"), 0);
+    assert!(header("ratelimit-reset") <= 60);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+    log::info!("All done :)");
+}
+
+/// Rules from the config file should limit each route separately, keyed as configured
+#[tokio::test]
+async fn test_route_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut rng = rand::thread_rng();
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.toml",
+        rng.gen_range(0, u64::MAX)
+    ));
+    std::fs::write(
+        &path,
+        "[[rate_limit.rules]]\n\
+        path_prefix = \"/api\"\n\
+        key = \"header:x-api-key\"\n\
+        limit = 2\n\
+        \n\
+        [[rate_limit.rules]]\n\
+        path_prefix = \"/api/public\"\n\
+        limit = 0\n\
+        \n\
+        [[rate_limit.rules]]\n\
+        path_prefix = \"/shared\"\n\
+        key = \"path-prefix\"\n\
+        limit = 1\n",
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--config", path.to_str().unwrap()],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let send = |path: &str, api_key: Option<&str>| {
+        let mut request = client.get(&format!("http://{}{}", balancebeam.address, path));
+        if let Some(api_key) = api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        async move {
+            request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+    // Each API key gets its own limit
+    for (api_key, expected) in [("a", 200), ("a", 200), ("a", 429), ("b", 200), ("b", 200)].iter() {
+        assert_eq!(send("/api/users", Some(api_key)).await, *expected);
+    }
+    // The more specific rule exempts /api/public, and paths without a rule aren't limited
+    for _ in 0..3 {
+        assert_eq!(send("/api/public/docs", Some("a")).await, 200);
+        assert_eq!(send("/other", None).await, 200);
+    }
+    // Everyone shares the limit for /shared
+    assert_eq!(send("/shared/1", Some("a")).await, 200);
+    assert_eq!(send("/shared/2", Some("b")).await, 429);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 11);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
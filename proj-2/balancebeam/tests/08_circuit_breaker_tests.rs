@@ -0,0 +1,132 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+
+/// Sends `n` requests and returns the status of each.
+async fn send_requests(balancebeam: &BalanceBeam, n: usize) -> Vec<u16> {
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for i in 0..n {
+        let response = client
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    statuses
+}
+
+/// An upstream that keeps answering with 500s should be ejected after a few requests, then let
+/// back in with a trial request once its ejection runs out
+#[tokio::test]
+async fn test_failing_upstream_is_ejected_and_restored() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let failing = ErrorServer::new().await;
+    let failing_address = failing.address.clone();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy.address, &failing.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--passive-failure-threshold",
+            "2",
+            "--ejection-time",
+            "2",
+            // Leave it to the circuit breaker
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    // Round-robin sends every other request to the failing upstream until it has failed twice
+    let statuses = send_requests(&balancebeam, 10).await;
+    assert_eq!(statuses.iter().filter(|status| **status == 500).count(), 2);
+    assert_eq!(Box::new(failing).stop().await, 2);
+
+    log::info!("Bringing the failing upstream back and waiting for its ejection to run out");
+    let restored = EchoServer::new_at_address(failing_address).await;
+    delay_for(Duration::from_millis(2500)).await;
+    let statuses = send_requests(&balancebeam, 6).await;
+    assert!(statuses.iter().all(|status| *status == 200));
+    assert!(
+        Box::new(restored).stop().await > 1,
+        "The upstream never came back into rotation after its trial request"
+    );
+    Box::new(healthy).stop().await;
+    log::info!("All done :)");
+}
+
+/// Clients that give up partway through sending a request body are not the upstream's fault, and
+/// shouldn't get it ejected
+#[tokio::test]
+async fn test_aborted_uploads_do_not_eject_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--passive-failure-threshold",
+            "2",
+            "--ejection-time",
+            "60",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    for i in 0..6 {
+        let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+        client
+            .write_all(
+                format!("POST /upload-{} HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5000000\r\n\r\nstart", i)
+                    .as_bytes(),
+            )
+            .await
+            .unwrap();
+        // Give balancebeam time to start streaming the body upstream before hanging up
+        delay_for(Duration::from_millis(200)).await;
+    }
+
+    let statuses = send_requests(&balancebeam, 2).await;
+    assert_eq!(statuses, vec![200, 200]);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Once every upstream is ejected, clients should be told to come back when the first of them is
+/// let back in, rather than at the next active health check
+#[tokio::test]
+async fn test_retry_after_ejection() {
+    init_logging();
+    let failing = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing.address],
+        &[
+            "--passive-failure-threshold",
+            "2",
+            "--ejection-time",
+            "5",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    assert_eq!(send_requests(&balancebeam, 2).await, vec![500, 500]);
+    let response = reqwest::get(&format!("http://{}/ejected", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    let retry_after: u64 = response.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!((1..=5).contains(&retry_after), "Retry-After was {}", retry_after);
+    Box::new(failing).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Sends `n` requests and returns the status of each.
+async fn send_requests(balancebeam: &BalanceBeam, n: usize) -> Vec<u16> {
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for i in 0..n {
+        let response = client
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    statuses
+}
+
+/// An upstream answering with a status in a configured range should stay in rotation, even if
+/// it isn't a 200
+#[tokio::test]
+async fn test_healthy_status_ranges() {
+    init_logging();
+    let echo = EchoServer::new().await;
+    let error = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo.address, &error.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-status",
+            "200-299,500",
+            "--passive-failure-threshold",
+            "0",
+        ],
+    )
+    .await;
+
+    delay_for(Duration::from_millis(1500)).await;
+    let statuses = send_requests(&balancebeam, 4).await;
+    assert_eq!(statuses.iter().filter(|status| **status == 500).count(), 2);
+
+    Box::new(echo).stop().await;
+    Box::new(error).stop().await;
+    log::info!("All done :)");
+}
+
+/// Probes should carry the configured headers, and the response body should be checked
+#[tokio::test]
+async fn test_headers_and_body_match() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-path",
+            "/healthz",
+            "--active-health-check-header",
+            "X-Probe: yes",
+            "--active-health-check-body",
+            "x-probe: yes",
+            "--active-health-check-body-regex",
+            "^GET /healthz ",
+        ],
+    )
+    .await;
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(send_requests(&balancebeam, 2).await, vec![200, 200]);
+    Box::new(upstream).stop().await;
+
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-body-regex",
+            "^POST ",
+        ],
+    )
+    .await;
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(send_requests(&balancebeam, 2).await, vec![503, 503]);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream should only be marked unhealthy once it has failed `fall` probes in a row
+#[tokio::test]
+async fn test_fall_threshold() {
+    init_logging();
+    let echo = EchoServer::new().await;
+    let error = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo.address, &error.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-fall",
+            "3",
+            "--passive-failure-threshold",
+            "0",
+        ],
+    )
+    .await;
+
+    // One probe has failed so far
+    delay_for(Duration::from_millis(500)).await;
+    let statuses = send_requests(&balancebeam, 4).await;
+    assert_eq!(statuses.iter().filter(|status| **status == 500).count(), 2);
+
+    // Three have failed now
+    delay_for(Duration::from_millis(2500)).await;
+    let statuses = send_requests(&balancebeam, 4).await;
+    assert!(statuses.iter().all(|status| *status == 200));
+
+    Box::new(echo).stop().await;
+    Box::new(error).stop().await;
+    log::info!("All done :)");
+}
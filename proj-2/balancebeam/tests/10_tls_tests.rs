@@ -0,0 +1,120 @@
+mod common;
+
+use common::{init_logging, served_common_name, BalanceBeam, Certificate, EchoServer, Server};
+use rand::Rng;
+
+fn https_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap()
+}
+
+/// Requests, including ones with bodies big enough to be streamed, should be proxied over HTTPS
+#[tokio::test]
+async fn test_https() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certificate = Certificate::new("localhost");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--tls-cert", certificate.cert(), "--tls-key", certificate.key()],
+    )
+    .await;
+
+    let client = https_client();
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("https://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    let body = "x".repeat(2 * 1024 * 1024);
+    let response_text = client
+        .post(&format!("https://{}/upload", balancebeam.address))
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.ends_with(&body));
+
+    // Plain HTTP shouldn't get anywhere
+    assert!(balancebeam.get("/plain").await.is_err());
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+    log::info!("All done :)");
+}
+
+/// The certificate should be picked by the server name the client asks for, and certificates
+/// should be re-read when the config is reloaded
+#[tokio::test]
+async fn test_sni_and_reload() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let default = Certificate::new("default");
+    let api = Certificate::new("api");
+    let wildcard = Certificate::new("wildcard");
+    let mut rng = rand::thread_rng();
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.toml",
+        rng.gen_range(0, u64::MAX)
+    ));
+    std::fs::write(
+        &path,
+        format!(
+            "upstreams = [\"{}\"]\n\
+            \n\
+            [tls]\n\
+            cert = \"{}\"\n\
+            key = \"{}\"\n\
+            \n\
+            [[tls.sni]]\n\
+            server_name = \"api.example.com\"\n\
+            cert = \"{}\"\n\
+            key = \"{}\"\n\
+            \n\
+            [[tls.sni]]\n\
+            server_name = \"*.example.org\"\n\
+            cert = \"{}\"\n\
+            key = \"{}\"\n",
+            upstream.address,
+            default.cert(),
+            default.key(),
+            api.cert(),
+            api.key(),
+            wildcard.cert(),
+            wildcard.key(),
+        ),
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+
+    let address = &balancebeam.address;
+    assert_eq!(served_common_name(address, "api.example.com").await, "api");
+    assert_eq!(served_common_name(address, "API.example.com").await, "api");
+    assert_eq!(served_common_name(address, "www.example.org").await, "wildcard");
+    assert_eq!(served_common_name(address, "a.b.example.org").await, "default");
+    assert_eq!(served_common_name(address, "other.test").await, "default");
+
+    log::info!("Renewing the default certificate");
+    default.write("renewed");
+    balancebeam.reload().await;
+    assert_eq!(served_common_name(address, "other.test").await, "renewed");
+    assert_eq!(served_common_name(address, "api.example.com").await, "api");
+
+    Box::new(upstream).stop().await;
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
@@ -0,0 +1,181 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Certificate, EchoServer, Server};
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use rand::Rng;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+/// Writes a config file that proxies to a single `https://` upstream, verified as "localhost".
+fn write_config(upstream: &str, extra: &str) -> PathBuf {
+    let mut rng = rand::thread_rng();
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.toml",
+        rng.gen_range(0, u64::MAX)
+    ));
+    std::fs::write(
+        &path,
+        format!(
+            "[[upstreams]]\n\
+            address = \"https://{}\"\n\
+            server_name = \"localhost\"\n\
+            \n\
+            {}",
+            upstream, extra
+        ),
+    )
+    .unwrap();
+    path
+}
+
+/// Serves HTTPS on a random port, only to clients that present a certificate signed by
+/// `client_ca`. Every response body is the common name of the client's certificate. The server
+/// runs on its own thread until the test process exits.
+fn start_client_cert_server(certificate: &Certificate, client_ca: &Certificate) -> String {
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_certificate_chain_file(&certificate.cert_path).unwrap();
+    acceptor.set_private_key_file(&certificate.key_path, SslFiletype::PEM).unwrap();
+    acceptor.set_ca_file(&client_ca.cert_path).unwrap();
+    acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    let acceptor = acceptor.build();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match acceptor.accept(stream.unwrap()) {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let common_name = stream
+                .ssl()
+                .peer_certificate()
+                .unwrap()
+                .subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .next()
+                .unwrap()
+                .data()
+                .to_string()
+                .unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => request.extend_from_slice(&buf[..len]),
+                }
+            }
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                common_name.len(),
+                common_name
+            );
+            let _ = stream.shutdown();
+        }
+    });
+    address
+}
+
+/// Requests should be proxied to an https:// upstream whose certificate checks out against the
+/// configured CA bundle and server name, and not to one whose certificate doesn't.
+#[tokio::test]
+async fn test_https_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certificate = Certificate::new("localhost");
+    let https_upstream = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--tls-cert", certificate.cert(), "--tls-key", certificate.key()],
+    )
+    .await;
+
+    let path = write_config(
+        &https_upstream.address,
+        &format!("[upstream_tls]\nca = \"{}\"\n", certificate.cert()),
+    );
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let body = "x".repeat(2 * 1024 * 1024);
+    let response_text = balancebeam
+        .post("/upload", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.ends_with(&body));
+
+    // Without the CA bundle, the self-signed certificate isn't trusted
+    let untrusted = BalanceBeam::new_with_args(&[&format!("https://{}", https_upstream.address)], &[])
+        .await;
+    let response = reqwest::get(&format!("http://{}/untrusted", untrusted.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.status().is_server_error());
+
+    // The certificate is for localhost, not 127.0.0.1
+    let wrong_name = BalanceBeam::new_with_args(
+        &[&format!("https://{}", https_upstream.address)],
+        &["--upstream-ca", certificate.cert()],
+    )
+    .await;
+    let response = reqwest::get(&format!("http://{}/wrong-name", wrong_name.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.status().is_server_error());
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
+
+/// A client certificate should be presented to upstreams that require one
+#[tokio::test]
+async fn test_client_certificate() {
+    init_logging();
+    let server_certificate = Certificate::new("localhost");
+    let client_certificate = Certificate::new("balancebeam-client");
+    let upstream = start_client_cert_server(&server_certificate, &client_certificate);
+
+    let path = write_config(
+        &upstream,
+        &format!(
+            "[upstream_tls]\n\
+            ca = \"{}\"\n\
+            client_cert = \"{}\"\n\
+            client_key = \"{}\"\n",
+            server_certificate.cert(),
+            client_certificate.cert(),
+            client_certificate.key(),
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+    let response_text = balancebeam
+        .get("/whoami")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "balancebeam-client");
+
+    // Without a client certificate, the upstream hangs up during the handshake
+    let without_cert_path = write_config(
+        &upstream,
+        &format!("[upstream_tls]\nca = \"{}\"\n", server_certificate.cert()),
+    );
+    let without_cert =
+        BalanceBeam::new_with_args(&[], &["--config", without_cert_path.to_str().unwrap()]).await;
+    let response = reqwest::get(&format!("http://{}/whoami", without_cert.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.status().is_server_error());
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&without_cert_path).unwrap();
+    log::info!("All done :)");
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Certificate, EchoServer, Server};
+use hyper::{Body, Request};
+
+fn h2c_client() -> hyper::Client<hyper::client::HttpConnector> {
+    hyper::Client::builder().http2_only(true).build_http()
+}
+
+async fn send(client: &hyper::Client<hyper::client::HttpConnector>, request: Request<Body>) -> String {
+    let response = client.request(request).await.expect("Error sending request to balancebeam");
+    assert_eq!(response.version(), http::Version::HTTP_2);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// Returns the ALPN protocol the server picks when the client offers h2 and http/1.1.
+async fn negotiated_protocol(address: &str) -> Option<String> {
+    let address = address.to_string();
+    tokio::task::spawn_blocking(move || {
+        let mut connector = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls()).unwrap();
+        connector.set_verify(openssl::ssl::SslVerifyMode::NONE);
+        connector.set_alpn_protos(b"\x02h2\x08http/1.1").unwrap();
+        let connector = connector.build();
+        let stream = std::net::TcpStream::connect(&address).unwrap();
+        let stream = connector.connect("localhost", stream).unwrap();
+        stream
+            .ssl()
+            .selected_alpn_protocol()
+            .map(|protocol| String::from_utf8(protocol.to_vec()).unwrap())
+    })
+    .await
+    .unwrap()
+}
+
+/// Requests multiplexed over one h2c connection should each be forwarded as an HTTP/1.1 request,
+/// with bodies streamed both ways when they are too big to buffer
+#[tokio::test]
+async fn test_h2c() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let client = h2c_client();
+
+    let requests: Vec<_> = (0..10)
+        .map(|i| {
+            let client = client.clone();
+            let address = balancebeam.address.clone();
+            tokio::spawn(async move {
+                let request = Request::get(format!("http://{}/request-{}", address, i))
+                    .header("cookie", "a=1")
+                    .header("cookie", "b=2")
+                    .body(Body::empty())
+                    .unwrap();
+                (i, send(&client, request).await)
+            })
+        })
+        .collect();
+    for request in requests {
+        let (i, response_text) = request.await.unwrap();
+        assert!(response_text.contains(&format!("GET /request-{} HTTP/1.1", i)));
+        assert!(response_text.contains(&format!("host: {}", balancebeam.address)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+        assert!(response_text.contains("cookie: a=1; b=2"));
+    }
+
+    // A big body with a known length, echoed back with a chunked response
+    let body = "x".repeat(2 * 1024 * 1024);
+    let request = Request::post(format!("http://{}/chunked-response", balancebeam.address))
+        .header("content-length", body.len())
+        .body(Body::from(body.clone()))
+        .unwrap();
+    let response_text = send(&client, request).await;
+    assert!(response_text.contains(&format!("content-length: {}", body.len())));
+    assert!(response_text.ends_with(&body));
+
+    // A big body of unknown length, which goes upstream chunked
+    let chunks = vec![
+        Ok::<_, std::io::Error>(body.clone()),
+        Ok::<_, std::io::Error>("y".repeat(1024)),
+    ];
+    let request = Request::post(format!("http://{}/upload", balancebeam.address))
+        .body(Body::wrap_stream(tokio::stream::iter(chunks)))
+        .unwrap();
+    let response_text = send(&client, request).await;
+    assert!(response_text.contains("transfer-encoding: chunked"));
+    assert!(response_text.ends_with(&format!("{}{}", body, "y".repeat(1024))));
+
+    // HTTP/1.1 clients are still served on the same port
+    let response_text = balancebeam.get("/http1").await.expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /http1 HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 13);
+    log::info!("All done :)");
+}
+
+/// HTTP/2 should be offered during the TLS handshake and served over TLS
+#[tokio::test]
+async fn test_h2_over_tls() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certificate = Certificate::new("localhost");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--tls-cert", certificate.cert(), "--tls-key", certificate.key()],
+    )
+    .await;
+
+    assert_eq!(negotiated_protocol(&balancebeam.address).await.as_deref(), Some("h2"));
+
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let stream = tokio::net::TcpStream::connect(&balancebeam.address).await.unwrap();
+    let stream = tokio_tls::TlsConnector::from(connector)
+        .connect("localhost", stream)
+        .await
+        .unwrap();
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake(stream)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    for i in 0..3 {
+        let request = Request::get(format!("https://localhost/tls-{}", i))
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.expect("Error sending request to balancebeam");
+        assert_eq!(response.version(), http::Version::HTTP_2);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response_text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(response_text.contains(&format!("GET /tls-{} HTTP/1.1", i)));
+        assert!(response_text.contains("host: localhost"));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,225 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const UPGRADE_REQUEST: &[u8] =
+    b"GET /socket HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n";
+
+/// Reads from the stream until `len` bytes past the end of the headers have arrived. Returns the
+/// headers and those bytes.
+async fn read_head_and(stream: &mut TcpStream, len: usize) -> (String, Vec<u8>) {
+    let mut received = Vec::new();
+    loop {
+        if let Some(end) = received.windows(4).position(|window| window == b"\r\n\r\n") {
+            if received.len() >= end + 4 + len {
+                let rest = received.split_off(end + 4);
+                return (String::from_utf8(received).unwrap(), rest);
+            }
+        }
+        let mut buf = [0; 1024];
+        let bytes_read = stream.read(&mut buf).await.unwrap();
+        assert!(bytes_read > 0, "Connection closed early");
+        received.extend_from_slice(&buf[..bytes_read]);
+    }
+}
+
+/// Starts an upstream that switches to an echo protocol when asked to, greeting the client in the
+/// same packet as its 101 response. Other requests get an empty 200 response.
+async fn start_echo_protocol_server() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(len) => request.extend_from_slice(&buf[..len]),
+                    }
+                }
+                // Anything after the headers is already the new protocol
+                let head_len = request.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+                let early = request.split_off(head_len);
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                if !request.contains("upgrade: echo") {
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+                    return;
+                }
+                stream
+                    .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\nwelcome")
+                    .await
+                    .unwrap();
+                if stream.write_all(&early).await.is_err() {
+                    return;
+                }
+                let mut buf = [0; 1024];
+                loop {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(len) => {
+                            if stream.write_all(&buf[..len]).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// After a 101 response, bytes should be relayed both ways until the client hangs up
+#[tokio::test]
+async fn test_upgrade() {
+    init_logging();
+    let upstream = start_echo_protocol_server().await;
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(UPGRADE_REQUEST).await.unwrap();
+    let (head, greeting) = read_head_and(&mut client, 7).await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert!(head.to_lowercase().contains("upgrade: echo"));
+    assert_eq!(greeting, b"welcome");
+
+    for i in 0..3 {
+        let message = format!("message {}", i);
+        client.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0; message.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message.as_bytes());
+    }
+
+    // Hanging up on our side should be passed along, and the upstream hanging up in turn should
+    // close the tunnel
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+        .await
+        .expect("Tunnel was not closed")
+        .unwrap();
+    assert!(rest.is_empty());
+    log::info!("All done :)");
+}
+
+/// A client may start on the new protocol without waiting for the 101, so whatever it sent along
+/// with the upgrade request should reach the upstream
+#[tokio::test]
+async fn test_data_sent_with_upgrade_request() {
+    init_logging();
+    let upstream = start_echo_protocol_server().await;
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut request = UPGRADE_REQUEST.to_vec();
+    request.extend_from_slice(b"first frame");
+    client.write_all(&request).await.unwrap();
+    let (head, rest) = tokio::time::timeout(Duration::from_secs(5), read_head_and(&mut client, 7 + 11))
+        .await
+        .expect("What was sent with the upgrade request never reached the upstream");
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert_eq!(rest, b"welcomefirst frame");
+    log::info!("All done :)");
+}
+
+/// A client that wants the connection closed afterwards should still get a 101 that says it is
+/// upgrading, and the connection should be closed when the tunnel is done
+#[tokio::test]
+async fn test_upgrade_with_connection_close() {
+    init_logging();
+    let upstream = start_echo_protocol_server().await;
+    let balancebeam = BalanceBeam::new(&[&upstream], None, None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /socket HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, close\r\nUpgrade: echo\r\n\r\n")
+        .await
+        .unwrap();
+    let (head, greeting) = read_head_and(&mut client, 7).await;
+    let head = head.to_lowercase();
+    assert!(head.starts_with("http/1.1 101"));
+    assert!(head.contains("connection: upgrade"), "{}", head);
+    assert!(!head.contains("close"), "{}", head);
+    assert_eq!(greeting, b"welcome");
+
+    client.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+        .await
+        .expect("Tunnel was not closed")
+        .unwrap();
+    assert!(rest.is_empty());
+    log::info!("All done :)");
+}
+
+/// A tunnel that nothing goes through should be closed after the idle timeout
+#[tokio::test]
+async fn test_idle_tunnel_is_closed() {
+    init_logging();
+    let upstream = start_echo_protocol_server().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--tunnel-idle-timeout", "1"]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(UPGRADE_REQUEST).await.unwrap();
+    let (head, _) = read_head_and(&mut client, 7).await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    // Traffic keeps the tunnel open past the timeout
+    for _ in 0..3 {
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+        .await
+        .expect("Idle tunnel was not closed")
+        .unwrap();
+    assert!(rest.is_empty());
+    log::info!("All done :)");
+}
+
+/// If the upstream answers an upgrade request normally, the connection should carry on as HTTP
+#[tokio::test]
+async fn test_upgrade_refused() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        client.write_all(UPGRADE_REQUEST).await.unwrap();
+        let (head, mut body) = read_head_and(&mut client, 0).await;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        let content_length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let already_read = body.len();
+        body.resize(content_length, 0);
+        client.read_exact(&mut body[already_read..]).await.unwrap();
+        assert!(String::from_utf8(body).unwrap().contains("upgrade: echo"));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Starts a server that greets every connection with its name on a line of its own, then echoes
+/// back whatever it is sent. It speaks no HTTP at all.
+async fn start_greeting_server(name: &'static str) -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                if stream.write_all(format!("{}\n", name).as_bytes()).await.is_err() {
+                    return;
+                }
+                let mut buf = [0; 1024];
+                loop {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(len) => {
+                            if stream.write_all(&buf[..len]).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Reads the greeting line a greeting server sends.
+async fn read_greeting(stream: &mut TcpStream) -> String {
+    let mut greeting = Vec::new();
+    while !greeting.ends_with(b"\n") {
+        let mut byte = [0; 1];
+        let bytes_read = stream.read(&mut byte).await.unwrap();
+        assert!(bytes_read > 0, "Connection closed before the greeting");
+        greeting.push(byte[0]);
+    }
+    String::from_utf8(greeting).unwrap().trim_end().to_string()
+}
+
+/// Connections should be balanced across upstreams that don't speak HTTP, and health checks should
+/// only check that they accept connections
+#[tokio::test]
+async fn test_tcp_mode() {
+    init_logging();
+    let first = start_greeting_server("first").await;
+    let second = start_greeting_server("second").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first, &second],
+        &["--mode", "tcp", "--strategy", "round-robin", "--active-health-check-interval", "1"],
+    )
+    .await;
+    // Give health checks a chance to run; an HTTP health check would fail both upstreams
+    tokio::time::delay_for(Duration::from_millis(2500)).await;
+
+    let mut greetings = Vec::new();
+    for _ in 0..4 {
+        let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+        greetings.push(read_greeting(&mut client).await);
+        let data = [0_u8, 1, 2, 255, b'\r', b'\n'];
+        client.write_all(&data).await.unwrap();
+        let mut echoed = [0; 6];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, data);
+    }
+    greetings.sort();
+    assert_eq!(greetings, vec!["first", "first", "second", "second"]);
+    log::info!("All done :)");
+}
+
+/// HTTP requests should be relayed as they are, without the headers balancebeam adds in http mode
+#[tokio::test]
+async fn test_http_is_relayed_untouched() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--mode", "tcp"]).await;
+
+    let response_text = balancebeam
+        .get("/raw")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /raw HTTP/1.1"));
+    assert!(!response_text.contains("x-forwarded-for"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
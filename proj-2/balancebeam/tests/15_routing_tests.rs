@@ -0,0 +1,179 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Returns a path in the temp directory that no other test is using.
+fn config_path() -> std::path::PathBuf {
+    let mut rng = rand::thread_rng();
+    std::env::temp_dir().join(format!("balancebeam-test-{}.toml", rng.gen_range(0, u64::MAX)))
+}
+
+/// Sends a request with extra headers and returns its status and body.
+async fn send(balancebeam: &BalanceBeam, method: reqwest::Method, path: &str,
+        headers: &[(&str, &str)]) -> (u16, String) {
+    let client = reqwest::Client::new();
+    let mut request = client.request(method, &format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request.send().await.expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Requests should go to the pool of the first route they match, and to the top-level upstreams
+/// if they match none
+#[tokio::test]
+async fn test_routes() {
+    init_logging();
+    let default = EchoServer::new().await;
+    let api = EchoServer::new().await;
+    let images = EchoServer::new().await;
+    let canary = EchoServer::new().await;
+    let path = config_path();
+    std::fs::write(
+        &path,
+        format!(
+            "upstreams = [\"{}\"]\n\
+            \n\
+            [[upstream_pools]]\n\
+            name = \"api\"\n\
+            upstreams = [\"{}\"]\n\
+            \n\
+            [[upstream_pools]]\n\
+            name = \"images\"\n\
+            upstreams = [\"{}\"]\n\
+            strategy = \"round-robin\"\n\
+            \n\
+            [[upstream_pools]]\n\
+            name = \"canary\"\n\
+            upstreams = [\"{}\"]\n\
+            \n\
+            [[routes]]\n\
+            headers = {{ x-canary = \"1\" }}\n\
+            pool = \"canary\"\n\
+            \n\
+            [[routes]]\n\
+            host = \"*.api.example.com\"\n\
+            pool = \"api\"\n\
+            \n\
+            [[routes]]\n\
+            path_prefix = \"/images\"\n\
+            method = \"GET\"\n\
+            pool = \"images\"\n\
+            \n\
+            [[routes]]\n\
+            path_regex = \"\\\\.(png|jpg)$\"\n\
+            pool = \"images\"\n",
+            default.address, api.address, images.address, canary.address
+        ),
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+
+    let get = reqwest::Method::GET;
+    // To the api pool by host, with or without a port
+    send(&balancebeam, get.clone(), "/users", &[("host", "eu.api.example.com")]).await;
+    send(&balancebeam, get.clone(), "/users", &[("host", "us.api.example.com:8080")]).await;
+    // To the images pool by path prefix and method, or by path regex
+    send(&balancebeam, get.clone(), "/images/cat", &[]).await;
+    send(&balancebeam, get.clone(), "/avatars/dog.png", &[]).await;
+    // The canary route comes first, so it wins over the host
+    send(&balancebeam, get.clone(), "/users", &[("host", "eu.api.example.com"), ("x-canary", "1")]).await;
+    // Everything else goes to the top-level upstream
+    let (status, response_text) = send(&balancebeam, reqwest::Method::POST, "/images/cat", &[]).await;
+    assert_eq!(status, 200);
+    assert!(response_text.contains("POST /images/cat HTTP/1.1"));
+    send(&balancebeam, get.clone(), "/imagesx", &[]).await;
+    send(&balancebeam, get.clone(), "/users", &[("host", "api.example.com")]).await;
+    send(&balancebeam, get.clone(), "/users", &[("x-canary", "0")]).await;
+
+    assert_eq!(Box::new(default).stop().await, 4);
+    assert_eq!(Box::new(api).stop().await, 2);
+    assert_eq!(Box::new(images).stop().await, 2);
+    assert_eq!(Box::new(canary).stop().await, 1);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Requests no route matches should get a 404 when there are no top-level upstreams
+#[tokio::test]
+async fn test_no_route() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = config_path();
+    std::fs::write(
+        &path,
+        format!(
+            "[[upstream_pools]]\n\
+            name = \"api\"\n\
+            upstreams = [\"{}\"]\n\
+            \n\
+            [[routes]]\n\
+            path_prefix = \"/api\"\n\
+            pool = \"api\"\n",
+            upstream.address
+        ),
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+
+    let (status, _) = send(&balancebeam, reqwest::Method::GET, "/api/users", &[]).await;
+    assert_eq!(status, 200);
+    let (status, _) = send(&balancebeam, reqwest::Method::GET, "/other", &[]).await;
+    assert_eq!(status, 404);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Each pool should be health checked with its own settings
+#[tokio::test]
+async fn test_pool_health_checks() {
+    init_logging();
+    let default = ErrorServer::new().await;
+    let errors = ErrorServer::new().await;
+    let path = config_path();
+    std::fs::write(
+        &path,
+        format!(
+            "upstreams = [\"{}\"]\n\
+            \n\
+            [health_check]\n\
+            interval = 1\n\
+            \n\
+            [circuit_breaker]\n\
+            failure_threshold = 0\n\
+            \n\
+            [[upstream_pools]]\n\
+            name = \"errors\"\n\
+            upstreams = [\"{}\"]\n\
+            \n\
+            [upstream_pools.health_check]\n\
+            status = \"500\"\n\
+            \n\
+            [[routes]]\n\
+            path_prefix = \"/errors\"\n\
+            pool = \"errors\"\n",
+            default.address, errors.address
+        ),
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+
+    // The top-level upstream fails its health check, while a 500 passes the errors pool's
+    delay_for(Duration::from_millis(1500)).await;
+    let (status, _) = send(&balancebeam, reqwest::Method::GET, "/", &[]).await;
+    assert_eq!(status, 503);
+    let (status, _) = send(&balancebeam, reqwest::Method::GET, "/errors", &[]).await;
+    assert_eq!(status, 500);
+
+    Box::new(default).stop().await;
+    Box::new(errors).stop().await;
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
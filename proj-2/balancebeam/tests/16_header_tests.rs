@@ -0,0 +1,184 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Returns a path in the temp directory that no other test is using.
+fn config_path() -> std::path::PathBuf {
+    let mut rng = rand::thread_rng();
+    std::env::temp_dir().join(format!("balancebeam-test-{}.toml", rng.gen_range(0, u64::MAX)))
+}
+
+/// Sends a raw request, which asks for the connection to be closed, and returns the whole
+/// response.
+async fn send_raw(balancebeam: &BalanceBeam, request: &str) -> String {
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+/// Hop-by-hop headers should be dropped, and the forwarding headers, Via and X-Request-Id added
+#[tokio::test]
+async fn test_forwarding_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--forwarded-headers", "both", "--via", "--request-id"],
+    )
+    .await;
+
+    let response = send_raw(
+        &balancebeam,
+        "GET /headers HTTP/1.1\r\n\
+        Host: example.com:8080\r\n\
+        Connection: close, x-secret\r\n\
+        Keep-Alive: timeout=5\r\n\
+        TE: trailers\r\n\
+        X-Secret: hunter2\r\n\
+        X-Forwarded-For: 10.0.0.1\r\n\
+        Forwarded: for=10.0.0.1\r\n\
+        \r\n",
+    )
+    .await;
+    let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+    let head = head.to_lowercase();
+    assert!(head.starts_with("http/1.1 200"));
+    assert!(head.contains("connection: close"));
+    assert!(head.contains("via: 1.1 balancebeam"));
+    let request_id = head
+        .lines()
+        .find_map(|line| line.strip_prefix("x-request-id: "))
+        .expect("No X-Request-Id on the response");
+    assert_eq!(request_id.len(), 36);
+
+    assert!(body.contains("x-forwarded-for: 10.0.0.1, 127.0.0.1"));
+    assert!(body.contains("x-forwarded-proto: http"));
+    assert!(body.contains("x-forwarded-host: example.com:8080"));
+    assert!(body.contains("forwarded: for=10.0.0.1, for=127.0.0.1;host=\"example.com:8080\";proto=http"));
+    assert!(body.contains("via: 1.1 balancebeam"));
+    assert!(body.contains(&format!("x-request-id: {}", request_id)));
+    for header in &["connection", "keep-alive", "te", "x-secret"] {
+        assert!(!body.contains(&format!("\n{}: ", header)), "{} was forwarded", header);
+    }
+
+    // A request that already has an ID keeps it
+    let response = send_raw(
+        &balancebeam,
+        "GET /headers HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\nX-Request-Id: abc\r\n\r\n",
+    )
+    .await;
+    assert_eq!(response.matches("x-request-id: abc").count(), 2);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    log::info!("All done :)");
+}
+
+/// Request and response headers should be added, set, removed and renamed as configured
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = config_path();
+    std::fs::write(
+        &path,
+        format!(
+            "upstreams = [\"{}\"]\n\
+            \n\
+            [headers]\n\
+            forwarded = \"none\"\n\
+            \n\
+            [[headers.request]]\n\
+            action = \"set\"\n\
+            name = \"x-env\"\n\
+            value = \"prod\"\n\
+            \n\
+            [[headers.request]]\n\
+            action = \"add\"\n\
+            name = \"x-tag\"\n\
+            value = \"balanced\"\n\
+            \n\
+            [[headers.request]]\n\
+            action = \"remove\"\n\
+            name = \"x-internal\"\n\
+            \n\
+            [[headers.request]]\n\
+            action = \"rename\"\n\
+            name = \"x-old\"\n\
+            to = \"x-new\"\n\
+            \n\
+            [[headers.response]]\n\
+            action = \"remove\"\n\
+            name = \"date\"\n\
+            \n\
+            [[headers.response]]\n\
+            action = \"set\"\n\
+            name = \"x-served-by\"\n\
+            value = \"balancebeam\"\n",
+            upstream.address
+        ),
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--config", path.to_str().unwrap()]).await;
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/rules", balancebeam.address))
+        .header("x-env", "dev")
+        .header("x-tag", "original")
+        .header("x-internal", "secret")
+        .header("x-old", "value")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(!response.headers().contains_key("date"));
+    assert_eq!(response.headers()["x-served-by"], "balancebeam");
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("x-env: prod"));
+    assert!(!response_text.contains("x-env: dev"));
+    assert!(response_text.contains("x-tag: original"));
+    assert!(response_text.contains("x-tag: balanced"));
+    assert!(!response_text.contains("x-internal"));
+    assert!(!response_text.contains("x-old"));
+    assert!(response_text.contains("x-new: value"));
+    assert!(!response_text.contains("x-forwarded-for"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Naming Content-Length in the Connection header must not get it dropped, which would let the
+/// body through to the upstream as a second request
+#[tokio::test]
+async fn test_connection_cannot_strip_framing_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let response = send_raw(
+        &balancebeam,
+        &format!(
+            "POST / HTTP/1.1\r\nHost: example.com\r\nConnection: close, content-length, host\r\n\
+            Content-Length: {}\r\n\r\n{}",
+            smuggled.len(),
+            smuggled
+        ),
+    )
+    .await;
+    let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap());
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert!(body.contains(&format!("content-length: {}\n", smuggled.len())));
+    assert!(body.contains("host: example.com\n"));
+    assert!(body.ends_with(smuggled));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
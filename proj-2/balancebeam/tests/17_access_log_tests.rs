@@ -0,0 +1,145 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Returns a path in the temp directory that no other test is using.
+fn log_path() -> String {
+    let mut rng = rand::thread_rng();
+    let path = std::env::temp_dir().join(format!("balancebeam-test-{}.log", rng.gen_range(0, u64::MAX)));
+    path.to_str().unwrap().to_string()
+}
+
+/// Reads the access log once balancebeam has had a moment to write the last record, which happens
+/// after the response is sent.
+async fn read_log(path: &str) -> Vec<String> {
+    delay_for(Duration::from_millis(100)).await;
+    std::fs::read_to_string(path)
+        .expect("Access log was not written")
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+/// Every request should get a JSON record with who asked for what, where it went and how it went
+#[tokio::test]
+async fn test_json_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = log_path();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--access-log", &path, "--access-log-format", "json", "--request-id", "--max-requests-per-minute", "2"],
+    )
+    .await;
+
+    for _ in 0..3 {
+        reqwest::Client::new()
+            .post(&format!("http://{}/logged?x=1", balancebeam.address))
+            .header("user-agent", "access-log-test")
+            .body("hello")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let records: Vec<serde_json::Value> = read_log(&path)
+        .await
+        .iter()
+        .map(|line| serde_json::from_str(line).expect("Access log record is not JSON"))
+        .collect();
+    assert_eq!(records.len(), 3);
+    for record in &records[..2] {
+        assert_eq!(record["client_ip"], "127.0.0.1");
+        assert_eq!(record["upstream"], upstream.address.as_str());
+        assert_eq!(record["method"], "POST");
+        assert_eq!(record["uri"], "/logged?x=1");
+        assert_eq!(record["protocol"], "HTTP/1.1");
+        assert_eq!(record["status"], 200);
+        assert!(record["bytes"].as_u64().unwrap() > 0);
+        assert!(record["upstream_latency_ms"].as_f64().unwrap() <= record["total_latency_ms"].as_f64().unwrap());
+        assert_eq!(record["request_id"].as_str().unwrap().len(), 36);
+        assert_eq!(record["user_agent"], "access-log-test");
+    }
+    assert_ne!(records[0]["request_id"], records[1]["request_id"]);
+    // The rate limiter answered the last one itself
+    assert_eq!(records[2]["status"], 429);
+    assert!(records[2]["upstream"].is_null());
+    assert!(records[2]["upstream_latency_ms"].is_null());
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Records should be in Combined Log Format by default
+#[tokio::test]
+async fn test_combined_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = log_path();
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--access-log", &path]).await;
+
+    reqwest::Client::new()
+        .get(&format!("http://{}/combined", balancebeam.address))
+        .header("referer", "http://example.com/")
+        .header("user-agent", "say \"hi\"")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let records = read_log(&path).await;
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert!(record.starts_with("127.0.0.1 - - ["));
+    assert!(record.contains(" +0000] \"GET /combined HTTP/1.1\" 200 "));
+    assert!(record.ends_with(" \"http://example.com/\" \"say \\\"hi\\\"\""));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    std::fs::remove_file(&path).unwrap();
+    log::info!("All done :)");
+}
+
+/// A log that grows past its maximum size should be moved aside, keeping only so many old logs
+#[tokio::test]
+async fn test_access_log_rotation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = log_path();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log", &path,
+            "--access-log-format", "common",
+            "--access-log-max-size", "100",
+            "--access-log-max-files", "2",
+        ],
+    )
+    .await;
+
+    // Each record is a little over 60 bytes, so every record after the first starts a new file
+    for i in 0..4 {
+        reqwest::get(&format!("http://{}/rotate/{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let current = read_log(&path).await;
+    assert_eq!(current.len(), 1);
+    assert!(current[0].contains("/rotate/3 "));
+    let newest = read_log(&format!("{}.1", path)).await;
+    assert!(newest[0].contains("/rotate/2 "));
+    let oldest = read_log(&format!("{}.2", path)).await;
+    assert!(oldest[0].contains("/rotate/1 "));
+    assert!(!std::path::Path::new(&format!("{}.3", path)).exists());
+
+    Box::new(upstream).stop().await;
+    for file in &[path.clone(), format!("{}.1", path), format!("{}.2", path)] {
+        std::fs::remove_file(file).unwrap();
+    }
+    log::info!("All done :)");
+}
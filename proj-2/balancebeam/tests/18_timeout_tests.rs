@@ -0,0 +1,177 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, Certificate, EchoServer, Server};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Starts a server that accepts connections and reads requests, but never responds.
+async fn start_silent_server() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(len) = stream.read(&mut buf).await {
+                    if len == 0 {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Sends part of a request and returns everything balancebeam sends back before hanging up,
+/// along with how long that took.
+async fn send_partial(balancebeam: &BalanceBeam, partial_request: &str) -> (String, Duration) {
+    let started = Instant::now();
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(partial_request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    (String::from_utf8(response).unwrap(), started.elapsed())
+}
+
+/// A client that doesn't finish sending its headers or body in time should get a 408
+#[tokio::test]
+async fn test_client_timeouts() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--client-header-timeout", "1", "--client-body-timeout", "1"],
+    )
+    .await;
+
+    let (response, elapsed) = send_partial(&balancebeam, "GET / HTTP/1.1\r\nHost: example.com\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 408"), "Unexpected response: {}", response);
+    assert!(elapsed < Duration::from_secs(5));
+
+    let (response, elapsed) = send_partial(
+        &balancebeam,
+        "POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 10\r\n\r\nabc",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 408"), "Unexpected response: {}", response);
+    assert!(elapsed < Duration::from_secs(5));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+    log::info!("All done :)");
+}
+
+/// A connection that is kept alive should be closed, without a response, once it sits idle for
+/// the keep-alive timeout
+#[tokio::test]
+async fn test_keep_alive_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--keep-alive-timeout", "1"]).await;
+
+    let (response, elapsed) =
+        send_partial(&balancebeam, "GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response: {}", response);
+    assert!(response.contains("GET /first HTTP/1.1"));
+    assert!(!response.contains("408"));
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(5));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Connects, sends `sent`, and returns how long balancebeam takes to hang up, failing if it
+/// doesn't within five seconds.
+async fn time_until_closed(balancebeam: &BalanceBeam, sent: &[u8]) -> Duration {
+    let started = Instant::now();
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(sent).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+        .await
+        .expect("balancebeam kept the connection open")
+        .unwrap();
+    started.elapsed()
+}
+
+/// A client that connects and sends nothing, or sends nothing but the HTTP/2 preface, should be
+/// hung up on once the header timeout is up, whether or not it was meant to start a TLS handshake
+#[tokio::test]
+async fn test_silent_clients_are_closed() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--client-header-timeout", "1"]).await;
+
+    let elapsed = time_until_closed(&balancebeam, b"").await;
+    assert!(elapsed >= Duration::from_secs(1));
+    let elapsed = time_until_closed(&balancebeam, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await;
+    assert!(elapsed >= Duration::from_secs(1));
+
+    let certificate = Certificate::new("localhost");
+    let tls_balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--client-header-timeout", "1",
+            "--tls-cert", certificate.cert(),
+            "--tls-key", certificate.key(),
+        ],
+    )
+    .await;
+    let elapsed = time_until_closed(&tls_balancebeam, b"").await;
+    assert!(elapsed >= Duration::from_secs(1));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+    log::info!("All done :)");
+}
+
+/// An HTTP/2 client that stops sending its request body should get a 408
+#[tokio::test]
+async fn test_http2_body_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--client-body-timeout", "1"]).await;
+    let client = hyper::Client::builder().http2_only(true).build_http::<hyper::Body>();
+
+    let started = Instant::now();
+    let (mut sender, body) = hyper::Body::channel();
+    sender.send_data("abc".into()).await.unwrap();
+    let request = hyper::Request::post(format!("http://{}/", balancebeam.address))
+        .body(body)
+        .unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(5), client.request(request))
+        .await
+        .expect("balancebeam kept waiting for the request body")
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 408);
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    drop(sender);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+    log::info!("All done :)");
+}
+
+/// An upstream that doesn't respond in time should get the client a 504
+#[tokio::test]
+async fn test_upstream_read_timeout() {
+    init_logging();
+    let upstream = start_silent_server().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--upstream-read-timeout", "1"]).await;
+
+    let started = Instant::now();
+    let response = reqwest::get(&format!("http://{}/slow", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    log::info!("All done :)");
+}
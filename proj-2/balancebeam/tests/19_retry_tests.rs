@@ -0,0 +1,120 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+
+/// Starts a server that reads each request and hangs up without responding.
+async fn start_hang_up_server() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+            });
+        }
+    });
+    address
+}
+
+/// Starts balancebeam in front of a server that hangs up and an echo server, taking turns, with
+/// nothing else taking the broken server out of rotation.
+async fn setup(extra_args: &[&str]) -> (BalanceBeam, EchoServer, String) {
+    init_logging();
+    let broken = start_hang_up_server().await;
+    let upstream = EchoServer::new().await;
+    let mut args = vec![
+        "--admin-bind",
+        "127.0.0.1:0",
+        "--strategy",
+        "round-robin",
+        "--active-health-check-interval",
+        "3600",
+        "--passive-failure-threshold",
+        "0",
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(&[&broken, &upstream.address], &args).await;
+    let admin_address = balancebeam.admin_address.clone().unwrap();
+    (balancebeam, upstream, admin_address)
+}
+
+/// Sends requests and returns how many of them failed with a 502.
+async fn count_bad_gateways(balancebeam: &BalanceBeam, method: reqwest::Method, n: usize) -> usize {
+    let client = reqwest::Client::new();
+    let mut bad_gateways = 0;
+    for i in 0..n {
+        let response = client
+            .request(method.clone(), &format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        match response.status().as_u16() {
+            200 => {}
+            502 => bad_gateways += 1,
+            status => panic!("Unexpected status {}", status),
+        }
+    }
+    bad_gateways
+}
+
+/// Reads a counter from the admin API's metrics.
+async fn metric(admin_address: &str, name: &str) -> usize {
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .unwrap();
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{} ", name)))
+        .unwrap_or_else(|| panic!("Missing series {}", name))
+        .parse()
+        .unwrap()
+}
+
+/// Idempotent requests that fail should be retried on another upstream, but other requests
+/// shouldn't be
+#[tokio::test]
+async fn test_retries() {
+    let (balancebeam, upstream, admin_address) = setup(&[]).await;
+
+    assert_eq!(count_bad_gateways(&balancebeam, reqwest::Method::GET, 10).await, 0);
+    assert!(metric(&admin_address, "balancebeam_retries_total").await > 0);
+    assert!(count_bad_gateways(&balancebeam, reqwest::Method::POST, 10).await > 0);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert!(num_requests_received >= 10);
+    log::info!("All done :)");
+}
+
+/// With retries turned off, a failed request should fail straight away
+#[tokio::test]
+async fn test_no_retries() {
+    let (balancebeam, upstream, admin_address) = setup(&["--max-retries", "0"]).await;
+
+    assert!(count_bad_gateways(&balancebeam, reqwest::Method::GET, 10).await > 0);
+    assert_eq!(metric(&admin_address, "balancebeam_retries_total").await, 0);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Once the retry budget is spent, failed requests should not be retried
+#[tokio::test]
+async fn test_retry_budget() {
+    // Allows 10 retries in any 10 seconds, and none for the requests made
+    let (balancebeam, upstream, admin_address) =
+        setup(&["--retry-budget", "0", "--retry-budget-min", "1"]).await;
+
+    assert!(count_bad_gateways(&balancebeam, reqwest::Method::GET, 40).await > 0);
+    assert_eq!(metric(&admin_address, "balancebeam_retries_total").await, 10);
+    assert!(metric(&admin_address, "balancebeam_retries_denied_total").await > 0);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::delay_for;
+
+/// Starts a server that answers every request with "slow" after `delay`.
+async fn start_slow_server(delay: Duration) -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(len) = stream.read(&mut buf).await {
+                    if len == 0 {
+                        return;
+                    }
+                    delay_for(delay).await;
+                    let response = "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow";
+                    if stream.write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Reads one response, framed by Content-Length, off a connection.
+async fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    loop {
+        let mut buf = [0; 1024];
+        let len = stream.read(&mut buf).await.unwrap();
+        assert!(len > 0, "Connection closed before the response was complete");
+        response.extend_from_slice(&buf[..len]);
+        let text = String::from_utf8_lossy(&response).to_string();
+        if let Some(head_len) = text.find("\r\n\r\n") {
+            let content_length: usize = text[..head_len]
+                .lines()
+                .find_map(|line| line.to_lowercase().strip_prefix("content-length: ").map(String::from))
+                .map_or(0, |length| length.parse().unwrap());
+            if response.len() >= head_len + 4 + content_length {
+                return text;
+            }
+        }
+    }
+}
+
+/// On SIGTERM, requests in flight should finish (with the connection closed after them), new
+/// connections should be turned away, and balancebeam should exit once nothing is left
+#[tokio::test]
+async fn test_in_flight_requests_finish() {
+    init_logging();
+    let upstream = start_slow_server(Duration::from_secs(1)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], &[]).await;
+
+    let url = format!("http://{}/in-flight", balancebeam.address);
+    let in_flight = tokio::spawn(async move { reqwest::get(&url).await });
+    delay_for(Duration::from_millis(300)).await;
+    balancebeam.terminate();
+    delay_for(Duration::from_millis(300)).await;
+    assert!(TcpStream::connect(&balancebeam.address).await.is_err());
+
+    let response = in_flight.await.unwrap().expect("In-flight request failed");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["connection"], "close");
+    assert_eq!(response.text().await.unwrap(), "slow");
+    let status = tokio::time::timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam did not exit");
+    assert!(status.success());
+    log::info!("All done :)");
+}
+
+/// Idle keep-alive connections should be closed straight away on SIGTERM
+#[tokio::test]
+async fn test_idle_connections_close() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(b"GET /idle HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+    assert!(read_response(&mut client).await.starts_with("HTTP/1.1 200"));
+
+    let started = Instant::now();
+    balancebeam.terminate();
+    let mut buf = [0; 1024];
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    let status = tokio::time::timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam did not exit");
+    assert!(status.success());
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Connections that haven't sent anything yet, or are speaking HTTP/2 with nothing in flight,
+/// should be closed straight away on SIGTERM too
+#[tokio::test]
+async fn test_silent_and_idle_http2_connections_close() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let silent = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut preface_only = TcpStream::connect(&balancebeam.address).await.unwrap();
+    preface_only.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await.unwrap();
+    let h2c_client = hyper::Client::builder().http2_only(true).build_http::<hyper::Body>();
+    let uri: hyper::Uri = format!("http://{}/idle", balancebeam.address).parse().unwrap();
+    let response = h2c_client.get(uri).await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    delay_for(Duration::from_millis(300)).await;
+
+    let started = Instant::now();
+    balancebeam.terminate();
+    for client in &mut [silent, preface_only] {
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .expect("Connection was left open")
+            .unwrap();
+    }
+    let status = tokio::time::timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam did not exit");
+    assert!(status.success());
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Requests that are still going at the drain deadline should be cut off
+#[tokio::test]
+async fn test_drain_deadline() {
+    init_logging();
+    let upstream = start_slow_server(Duration::from_secs(60)).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], &["--drain-timeout", "1"]).await;
+
+    let url = format!("http://{}/stuck", balancebeam.address);
+    let stuck = tokio::spawn(async move { reqwest::get(&url).await });
+    delay_for(Duration::from_millis(300)).await;
+    let started = Instant::now();
+    balancebeam.terminate();
+    tokio::time::timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam did not exit");
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert!(stuck.await.unwrap().is_err());
+    log::info!("All done :)");
+}
@@ -0,0 +1,214 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Answers requests the way an upstream that wants its responses cached would, depending on the
+/// path:
+///
+/// * `/fresh` may be cached for a minute
+/// * `/revalidate` may be cached, but has to be revalidated every time, and answers 304 when the
+///   client already has it
+/// * `/private` is only for the client that asked
+/// * `/vary` depends on the Accept-Language header, and echoes it
+/// * `/big/...` may be cached for a minute, and is 2000 bytes long
+fn respond(req: Request<Body>) -> Response<Body> {
+    let response = Response::builder();
+    let path = req.uri().path().to_string();
+    match path.as_str() {
+        "/fresh" => response
+            .header("Cache-Control", "max-age=60")
+            .body(Body::from("fresh"))
+            .unwrap(),
+        "/revalidate" if req.headers().get("If-None-Match").is_some_and(|etag| etag == "\"v1\"") => response
+            .status(304)
+            .header("ETag", "\"v1\"")
+            .body(Body::empty())
+            .unwrap(),
+        "/revalidate" => response
+            .header("Cache-Control", "no-cache")
+            .header("ETag", "\"v1\"")
+            .body(Body::from("revalidate"))
+            .unwrap(),
+        "/private" => response
+            .header("Cache-Control", "private, max-age=60")
+            .body(Body::from("private"))
+            .unwrap(),
+        "/vary" => {
+            let language = req.headers().get("Accept-Language").map_or("none", |value| value.to_str().unwrap());
+            response
+                .header("Cache-Control", "max-age=60")
+                .header("Vary", "Accept-Language")
+                .body(Body::from(language.to_string()))
+                .unwrap()
+        }
+        _ => response
+            .header("Cache-Control", "max-age=60")
+            .body(Body::from(vec![b'x'; 2000]))
+            .unwrap(),
+    }
+}
+
+/// Starts a cacheable server, returning its address and the number of requests it has received.
+fn start_cacheable_server() -> (String, Arc<AtomicUsize>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests_clone = Arc::clone(&requests);
+    let service = make_service_fn(move |_| {
+        let requests = Arc::clone(&requests_clone);
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                async move { Ok::<_, hyper::Error>(respond(req)) }
+            }))
+        }
+    });
+    let server = hyper::Server::from_tcp(listener).unwrap().serve(service);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("Error in cacheable server: {}", e);
+        }
+    });
+    (address, requests)
+}
+
+/// Sends a request, returning the status, X-Cache header and body of the response.
+async fn request(balancebeam: &BalanceBeam, method: reqwest::Method, path: &str,
+        headers: &[(&str, &str)]) -> (reqwest::StatusCode, String, String) {
+    let mut request = reqwest::Client::new().request(method, &format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request.send().await.expect("Error sending request to balancebeam");
+    let status = response.status();
+    let cache_status = response
+        .headers()
+        .get("x-cache")
+        .map_or(String::new(), |value| value.to_str().unwrap().to_string());
+    (status, cache_status, response.text().await.unwrap())
+}
+
+async fn get(balancebeam: &BalanceBeam, path: &str) -> (String, String) {
+    let (_, cache_status, body) = request(balancebeam, reqwest::Method::GET, path, &[]).await;
+    (cache_status, body)
+}
+
+/// Fresh responses should be answered from the cache, until a request that may change them
+#[tokio::test]
+async fn test_cache_hits() {
+    init_logging();
+    let (upstream, requests) = start_cacheable_server();
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], &["--cache-size", "100000"]).await;
+
+    assert_eq!(get(&balancebeam, "/fresh").await, ("MISS".to_string(), "fresh".to_string()));
+    assert_eq!(get(&balancebeam, "/fresh").await, ("HIT".to_string(), "fresh".to_string()));
+    let (status, cache_status, body) = request(&balancebeam, reqwest::Method::HEAD, "/fresh", &[]).await;
+    assert_eq!((status.as_u16(), cache_status.as_str(), body.as_str()), (200, "HIT", ""));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // A POST may change what is at its URL, so the stored response shouldn't be used again
+    let (_, cache_status, _) = request(&balancebeam, reqwest::Method::POST, "/fresh", &[]).await;
+    assert_eq!(cache_status, "BYPASS");
+    assert_eq!(get(&balancebeam, "/fresh").await.0, "MISS");
+    let (_, cache_status, _) =
+        request(&balancebeam, reqwest::Method::GET, "/fresh", &[("Cache-Control", "no-store")]).await;
+    assert_eq!(cache_status, "BYPASS");
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+    // Private responses are never stored
+    assert_eq!(get(&balancebeam, "/private").await.0, "MISS");
+    assert_eq!(get(&balancebeam, "/private").await.0, "MISS");
+    assert_eq!(requests.load(Ordering::SeqCst), 6);
+    log::info!("All done :)");
+}
+
+/// Stale responses should be revalidated with the upstream rather than fetched again, and clients
+/// that already have a response should be told so
+#[tokio::test]
+async fn test_revalidation() {
+    init_logging();
+    let (upstream, requests) = start_cacheable_server();
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], &["--cache-size", "100000"]).await;
+
+    assert_eq!(get(&balancebeam, "/revalidate").await, ("MISS".to_string(), "revalidate".to_string()));
+    assert_eq!(get(&balancebeam, "/revalidate").await, ("REVALIDATED".to_string(), "revalidate".to_string()));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    let (status, cache_status, body) =
+        request(&balancebeam, reqwest::Method::GET, "/revalidate", &[("If-None-Match", "\"v1\"")]).await;
+    assert_eq!((status.as_u16(), cache_status.as_str(), body.as_str()), (304, "REVALIDATED", ""));
+    log::info!("All done :)");
+}
+
+/// A response should only answer requests with the same values of the headers it varies on
+#[tokio::test]
+async fn test_vary() {
+    init_logging();
+    let (upstream, requests) = start_cacheable_server();
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], &["--cache-size", "100000"]).await;
+
+    for (language, expected) in [("en", "MISS"), ("fr", "MISS"), ("en", "HIT"), ("fr", "HIT")].iter() {
+        let (_, cache_status, body) =
+            request(&balancebeam, reqwest::Method::GET, "/vary", &[("Accept-Language", language)]).await;
+        assert_eq!((cache_status.as_str(), body.as_str()), (*expected, *language));
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    log::info!("All done :)");
+}
+
+/// Once the cache is full, the least recently used responses should be evicted, and the admin
+/// API should count what the cache did
+#[tokio::test]
+async fn test_eviction() {
+    init_logging();
+    let (upstream, requests) = start_cacheable_server();
+    // Room for two of the 2000 byte responses (and their headers)
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--cache-size", "5000", "--admin-bind", "127.0.0.1:0"],
+    )
+    .await;
+
+    assert_eq!(get(&balancebeam, "/big/1").await.0, "MISS");
+    assert_eq!(get(&balancebeam, "/big/2").await.0, "MISS");
+    assert_eq!(get(&balancebeam, "/big/1").await.0, "HIT");
+    // /big/2 is the least recently used, so it makes way for /big/3
+    assert_eq!(get(&balancebeam, "/big/3").await.0, "MISS");
+    assert_eq!(get(&balancebeam, "/big/1").await.0, "HIT");
+    assert_eq!(get(&balancebeam, "/big/2").await.0, "MISS");
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+    let admin_address = balancebeam.admin_address.as_ref().unwrap();
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("balancebeam_cache_requests_total{result=\"hit\"} 2"), "{}", metrics);
+    assert!(metrics.contains("balancebeam_cache_requests_total{result=\"miss\"} 4"), "{}", metrics);
+    assert!(metrics.contains("balancebeam_cache_evictions_total 2"), "{}", metrics);
+    assert!(metrics.contains("balancebeam_cache_entries 2"), "{}", metrics);
+    log::info!("All done :)");
+}
+
+/// Without --cache-size, every request should go to the upstream
+#[tokio::test]
+async fn test_cache_off() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    for _ in 0..2 {
+        let (cache_status, _) = get(&balancebeam, "/").await;
+        assert_eq!(cache_status, "");
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    log::info!("All done :)");
+}
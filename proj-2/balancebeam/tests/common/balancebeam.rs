@@ -1,13 +1,40 @@
-use rand::Rng;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
 use tokio::time::delay_for;
 
+const LISTENING: &str = "Listening for requests on ";
+const SERVING_ADMIN: &str = "Serving the admin API on ";
+
 pub struct BalanceBeam {
     #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// Where the admin API is served, if it was asked for with --admin-bind
+    #[allow(dead_code)]
+    pub admin_address: Option<String>,
+}
+
+/// Prints each line of output from the child, passing the lines that say where it is listening on
+/// to `listening`.
+fn forward_output<R>(output: R, listening: mpsc::UnboundedSender<String>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(output).lines();
+        while let Some(line) = reader
+            .next_line()
+            .await
+            .expect("I/O error reading from child output")
+        {
+            println!("Balancebeam output: {}", line);
+            if line.contains(LISTENING) || line.contains(SERVING_ADMIN) {
+                let _ = listening.send(line);
+            }
+        }
+    });
 }
 
 impl BalanceBeam {
@@ -19,69 +46,108 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
-        let mut cmd = Command::new(BalanceBeam::target_bin_path());
-        cmd.arg("--bind").arg(&address);
-        for upstream in upstreams {
-            cmd.arg("--upstream").arg(upstream);
-        }
+        let mut args = Vec::new();
         if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
         }
         if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing `extra_args` through on the command
+    /// line as-is. balancebeam listens on a port picked by the OS, so that it can't collide with
+    /// anything else. The addresses it (and its admin API, given --admin-bind) ended up on are read
+    /// back from its output.
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        cmd.arg("--bind").arg("127.0.0.1:0");
+        for upstream in upstreams {
+            cmd.arg("--upstream").arg(upstream);
         }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
         // suppressed if the test passes and displayed if it fails.
+        let (listening_tx, mut listening_rx) = mpsc::unbounded_channel();
         let stdout = child
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
-        tokio::spawn(async move {
-            let mut stdout_reader = BufReader::new(stdout).lines();
-            while let Some(line) = stdout_reader
-                .next_line()
-                .await
-                .expect("I/O error reading from child stdout")
-            {
-                println!("Balancebeam output: {}", line);
-            }
-        });
+        forward_output(stdout, listening_tx.clone());
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
-        tokio::spawn(async move {
-            let mut stderr_reader = BufReader::new(stderr).lines();
-            while let Some(line) = stderr_reader
-                .next_line()
+        forward_output(stderr, listening_tx);
+
+        // balancebeam logs the addresses it has bound to before it starts serving
+        let wants_admin = extra_args.contains(&"--admin-bind");
+        let mut address = None;
+        let mut admin_address = None;
+        while address.is_none() || (wants_admin && admin_address.is_none()) {
+            let line = tokio::time::timeout(Duration::from_secs(10), listening_rx.recv())
                 .await
-                .expect("I/O error reading from child stderr")
-            {
-                println!("Balancebeam output: {}", line);
+                .expect("Timed out waiting for balancebeam to start listening")
+                .expect("balancebeam exited without starting to listen");
+            if let Some((_, bound)) = line.split_once(LISTENING) {
+                address = Some(bound.trim().to_string());
+            } else if let Some((_, bound)) = line.split_once(SERVING_ADMIN) {
+                admin_address = Some(bound.trim().to_string());
             }
-        });
+        }
 
-        // Hack: wait for executable to start running
+        // Hack: wait for executable to finish starting up
         delay_for(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam { child, address: address.unwrap(), admin_address }
+    }
+
+    /// Sends SIGHUP to balancebeam, asking it to reload its config file.
+    #[allow(dead_code)]
+    pub async fn reload(&self) {
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.child.id() as i32),
+            nix::sys::signal::Signal::SIGHUP,
+        )
+        .expect("Could not send SIGHUP to balancebeam");
+        // Give balancebeam a moment to reload
+        delay_for(Duration::from_millis(500)).await;
+    }
+
+    /// Sends SIGTERM to balancebeam, asking it to shut down once the requests in flight finish.
+    #[allow(dead_code)]
+    pub fn terminate(&self) {
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(self.child.id() as i32),
+            nix::sys::signal::Signal::SIGTERM,
+        )
+        .expect("Could not send SIGTERM to balancebeam");
+    }
+
+    /// Waits for balancebeam to exit, returning its exit status.
+    #[allow(dead_code)]
+    pub async fn wait(self) -> std::process::ExitStatus {
+        self.child.await.expect("Could not wait for balancebeam to exit")
     }
 
     #[allow(dead_code)]
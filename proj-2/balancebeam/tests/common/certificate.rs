@@ -0,0 +1,109 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509};
+use rand::Rng;
+use std::path::PathBuf;
+
+/// A self-signed certificate for a name (as both its common name and its subject alternative name)
+/// and its key, written to files in the temp directory that are deleted when this is dropped.
+pub struct Certificate {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl Certificate {
+    #[allow(dead_code)]
+    pub fn new(common_name: &str) -> Certificate {
+        let mut rng = rand::thread_rng();
+        let base = std::env::temp_dir().join(format!(
+            "balancebeam-test-{}",
+            rng.gen_range(0, u64::MAX)
+        ));
+        let certificate = Certificate {
+            cert_path: base.with_extension("crt"),
+            key_path: base.with_extension("key"),
+        };
+        certificate.write(common_name);
+        certificate
+    }
+
+    /// Replaces the certificate with a new one for a different name, e.g. to test reloading.
+    #[allow(dead_code)]
+    pub fn write(&self, common_name: &str) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name).unwrap();
+        let name = name.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let alt_name = SubjectAlternativeName::new()
+            .dns(common_name)
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(alt_name).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        std::fs::write(&self.cert_path, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&self.key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+
+    #[allow(dead_code)]
+    pub fn cert(&self) -> &str {
+        self.cert_path.to_str().unwrap()
+    }
+
+    #[allow(dead_code)]
+    pub fn key(&self) -> &str {
+        self.key_path.to_str().unwrap()
+    }
+}
+
+impl Drop for Certificate {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.cert_path);
+        let _ = std::fs::remove_file(&self.key_path);
+    }
+}
+
+/// Performs a TLS handshake with `address`, asking for `server_name`, and returns the common name
+/// of the certificate it presents.
+#[allow(dead_code)]
+pub async fn served_common_name(address: &str, server_name: &str) -> String {
+    let address = address.to_string();
+    let server_name = server_name.to_string();
+    tokio::task::spawn_blocking(move || {
+        let mut connector = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls()).unwrap();
+        connector.set_verify(openssl::ssl::SslVerifyMode::NONE);
+        let connector = connector.build();
+        let stream = std::net::TcpStream::connect(&address).unwrap();
+        let mut config = connector.configure().unwrap();
+        config.set_verify_hostname(false);
+        let stream = config.connect(&server_name, stream).unwrap();
+        let cert = stream.ssl().peer_certificate().unwrap();
+        let common_name = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .unwrap()
+            .data()
+            .to_string();
+        common_name.unwrap()
+    })
+    .await
+    .unwrap()
+}
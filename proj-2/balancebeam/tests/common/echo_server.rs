@@ -2,7 +2,6 @@ use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
@@ -27,8 +26,19 @@ async fn echo(
         );
     }
     req_text += "\n";
+    let chunked_response = req.uri().path().starts_with("/chunked-response");
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
+    if chunked_response {
+        // Send the echo back in two pieces of unknown total length, which makes hyper use chunked
+        // transfer encoding
+        let second_half = req_as_bytes.split_off(req_as_bytes.len() / 2);
+        let chunks = vec![
+            Ok::<_, std::io::Error>(req_as_bytes),
+            Ok::<_, std::io::Error>(second_half),
+        ];
+        return Ok(Response::new(Body::wrap_stream(tokio::stream::iter(chunks))));
+    }
     Ok(Response::new(Body::from(req_as_bytes)))
 }
 
@@ -41,12 +51,14 @@ pub struct EchoServer {
 
 impl EchoServer {
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024, 65535))).await
+        // Let the OS pick a free port, rather than guessing one that may already be taken
+        EchoServer::new_at_address("127.0.0.1:0".to_string()).await
     }
 
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
-        let bind_addr = bind_addr_string.parse().unwrap();
+        let listener = std::net::TcpListener::bind(&bind_addr_string)
+            .unwrap_or_else(|err| panic!("Could not bind to {}: {}", bind_addr_string, err));
+        let address = listener.local_addr().unwrap().to_string();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
@@ -65,7 +77,8 @@ impl EchoServer {
                     }))
                 }
             });
-            let server = hyper::Server::bind(&bind_addr)
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
                 .serve(service)
                 .with_graceful_shutdown(async {
                     shutdown_rx.await.ok();
@@ -80,7 +93,7 @@ impl EchoServer {
             shutdown_signal_sender: shutdown_tx,
             server_task,
             state: server_state,
-            address: bind_addr_string,
+            address,
         }
     }
 }
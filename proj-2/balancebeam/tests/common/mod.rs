@@ -1,4 +1,5 @@
 mod balancebeam;
+mod certificate;
 mod echo_server;
 mod error_server;
 mod server;
@@ -6,7 +7,10 @@ mod server;
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use certificate::{served_common_name, Certificate};
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
 pub use server::Server;
 